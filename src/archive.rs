@@ -0,0 +1,15 @@
+use std::io::{Cursor, Write};
+
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::error::AppError;
+
+/// Packs named files into an in-memory zip archive.
+pub fn create_zip_archive(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, AppError> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, bytes) in files {
+        writer.start_file(name.as_str(), SimpleFileOptions::default())?;
+        writer.write_all(bytes)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
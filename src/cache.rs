@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::ops::{FetchedImage, Lut, ProcessedImage, Validators};
+
+/// Processed output stored in the disk cache, along with the operations that
+/// produced it so cached responses carry the same headers as fresh ones.
+pub struct CachedImage {
+    pub image: ProcessedImage,
+    pub operations: Vec<String>,
+    /// The source's `Last-Modified` header, if it had one.
+    pub last_modified: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntryMeta {
+    mime_type: String,
+    width: u32,
+    height: u32,
+    quality: Option<u8>,
+    quality_clamped: bool,
+    operations: Vec<String>,
+    last_modified: Option<String>,
+    /// Unix time in milliseconds when the entry was written.
+    created_at: u64,
+}
+
+/// Size and recency of an entry, tracked in memory for eviction.
+struct DiskEntry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+#[derive(Default)]
+struct DiskIndex {
+    entries: HashMap<String, DiskEntry>,
+    total_bytes: u64,
+}
+
+impl DiskIndex {
+    fn insert(&mut self, key: String, size: u64, last_used: SystemTime) {
+        if let Some(old) = self.entries.insert(key, DiskEntry { size, last_used }) {
+            self.total_bytes -= old.size;
+        }
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.total_bytes -= old.size;
+        }
+    }
+
+    /// Removes least recently used entries until the total fits in
+    /// `max_bytes`, returning the evicted keys.
+    fn evict_to(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_bytes > max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(oldest) = oldest else { break };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+/// Disk-backed cache of processed images.
+///
+/// Each entry is a single file holding a JSON metadata line followed by the
+/// encoded image. Entries are written to a temporary file and renamed into
+/// place, so concurrent writers never interleave and readers never observe a
+/// partially written entry.
+///
+/// Entries older than the TTL are treated as misses and removed, and once
+/// the cache grows past its size cap the least recently used entries are
+/// evicted. A cap or TTL of zero disables that limit.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: Mutex<DiskIndex>,
+    tmp_counter: AtomicU64,
+}
+
+impl DiskCache {
+    pub async fn new(dir: impl Into<PathBuf>, max_bytes: u64, ttl: Duration) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).await?;
+
+        // Rebuild the index from what an earlier run left behind, using the
+        // modification time as a stand-in for the last access.
+        let mut index = DiskIndex::default();
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.ends_with(".tmp") {
+                let _ = fs::remove_file(&path).await;
+            } else if let Some(key) = name.strip_suffix(".bin") {
+                let metadata = entry.metadata().await?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                index.insert(key.to_string(), metadata.len(), modified);
+            }
+        }
+
+        let cache = DiskCache {
+            dir,
+            max_bytes,
+            ttl,
+            index: Mutex::new(index),
+            tmp_counter: AtomicU64::new(0),
+        };
+        cache.evict().await;
+        Ok(cache)
+    }
+
+    /// Derives a cache key from the request inputs.
+    pub fn key(parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedImage> {
+        let data = fs::read(self.entry_path(key)).await.ok()?;
+        let newline = data.iter().position(|&b| b == b'\n')?;
+        let meta: CacheEntryMeta = serde_json::from_slice(&data[..newline]).ok()?;
+
+        let age = Duration::from_millis(unix_millis().saturating_sub(meta.created_at));
+        if !self.ttl.is_zero() && age >= self.ttl {
+            self.index.lock().unwrap().remove(key);
+            let _ = fs::remove_file(self.entry_path(key)).await;
+            return None;
+        }
+        self.index
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.len() as u64, SystemTime::now());
+
+        Some(CachedImage {
+            image: ProcessedImage {
+                bytes: data[newline + 1..].to_vec(),
+                mime_type: meta.mime_type,
+                width: meta.width,
+                height: meta.height,
+                quality: meta.quality,
+                quality_clamped: meta.quality_clamped,
+            },
+            operations: meta.operations,
+            last_modified: meta.last_modified,
+        })
+    }
+
+    pub async fn put(
+        &self,
+        key: &str,
+        image: &ProcessedImage,
+        operations: &[String],
+        last_modified: Option<&str>,
+    ) -> io::Result<()> {
+        let meta = CacheEntryMeta {
+            mime_type: image.mime_type.clone(),
+            width: image.width,
+            height: image.height,
+            quality: image.quality,
+            quality_clamped: image.quality_clamped,
+            operations: operations.to_vec(),
+            last_modified: last_modified.map(str::to_string),
+            created_at: unix_millis(),
+        };
+        let mut data = serde_json::to_vec(&meta)?;
+        data.push(b'\n');
+        data.extend_from_slice(&image.bytes);
+
+        // An entry that could never fit would only evict everything else.
+        if self.max_bytes > 0 && data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+
+        let tmp_path = self.dir.join(format!(
+            "{}.{}.{}.tmp",
+            key,
+            std::process::id(),
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, &data).await?;
+        if let Err(err) = fs::rename(&tmp_path, self.entry_path(key)).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(err);
+        }
+
+        self.index
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.len() as u64, SystemTime::now());
+        self.evict().await;
+        Ok(())
+    }
+
+    async fn evict(&self) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        let evicted = self.index.lock().unwrap().evict_to(self.max_bytes);
+        for key in evicted {
+            let _ = fs::remove_file(self.entry_path(&key)).await;
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", key))
+    }
+}
+
+/// A source image held by the [`SourceCache`].
+#[derive(Clone)]
+pub struct CachedSource {
+    pub bytes: Bytes,
+    pub validators: Validators,
+    fetched_at: Instant,
+}
+
+impl CachedSource {
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        self.fetched_at.elapsed() < max_age
+    }
+}
+
+/// In-memory cache of fetched source images, keyed by URL.
+///
+/// Entries younger than the max age are used as-is; older entries are
+/// revalidated against the origin with the validators it sent.
+pub struct SourceCache {
+    entries: Mutex<HashMap<String, CachedSource>>,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+impl SourceCache {
+    pub fn new(max_entries: usize, max_age: Duration) -> Self {
+        SourceCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            max_age,
+        }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    pub fn get(&self, url: &str) -> Option<CachedSource> {
+        self.entries.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn insert(&self, url: &str, fetched: &FetchedImage) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(url) && entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.fetched_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            url.to_string(),
+            CachedSource {
+                bytes: fetched.bytes.clone(),
+                validators: fetched.validators.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Marks an entry as freshly validated.
+    pub fn refresh(&self, url: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(url) {
+            entry.fetched_at = Instant::now();
+        }
+    }
+}
+
+/// In-memory cache of parsed color lookup tables, keyed by URL.
+///
+/// LUTs rarely change once published, so entries are kept until they are
+/// evicted to make room, oldest first.
+pub struct LutCache {
+    entries: Mutex<HashMap<String, (Arc<Lut>, Instant)>>,
+    max_entries: usize,
+}
+
+impl LutCache {
+    pub fn new(max_entries: usize) -> Self {
+        LutCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<Arc<Lut>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(url).map(|(lut, _)| Arc::clone(lut))
+    }
+
+    pub fn insert(&self, url: &str, lut: Arc<Lut>) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(url) && entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (_, inserted_at))| *inserted_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(url.to_string(), (lut, Instant::now()));
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("{}-{}-{}", name, std::process::id(), unix_millis()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn image(len: usize) -> ProcessedImage {
+        ProcessedImage {
+            bytes: vec![0; len],
+            mime_type: "image/png".to_string(),
+            width: 1,
+            height: 1,
+            quality: None,
+            quality_clamped: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn disk_cache_evicts_least_recently_used_entries() {
+        let dir = temp_dir("disk-cache-lru");
+        let cache = DiskCache::new(&dir, 2500, Duration::ZERO).await.unwrap();
+        cache.put("a", &image(1000), &[], None).await.unwrap();
+        cache.put("b", &image(1000), &[], None).await.unwrap();
+        // Touching "a" makes "b" the least recently used entry
+        assert!(cache.get("a").await.is_some());
+        cache.put("c", &image(1000), &[], None).await.unwrap();
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        assert!(!dir.join("b.bin").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_skips_entries_larger_than_the_cap() {
+        let dir = temp_dir("disk-cache-large");
+        let cache = DiskCache::new(&dir, 500, Duration::ZERO).await.unwrap();
+        cache.put("a", &image(1000), &[], None).await.unwrap();
+        assert!(cache.get("a").await.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_expires_entries_after_the_ttl() {
+        let dir = temp_dir("disk-cache-ttl");
+        let cache = DiskCache::new(&dir, 0, Duration::from_secs(1))
+            .await
+            .unwrap();
+        cache.put("a", &image(10), &[], None).await.unwrap();
+        assert!(cache.get("a").await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get("a").await.is_none());
+        assert!(!dir.join("a.bin").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn disk_cache_indexes_entries_from_a_previous_run() {
+        let dir = temp_dir("disk-cache-reopen");
+        let cache = DiskCache::new(&dir, 0, Duration::ZERO).await.unwrap();
+        cache.put("a", &image(1000), &[], None).await.unwrap();
+        cache.put("b", &image(1000), &[], None).await.unwrap();
+        drop(cache);
+
+        let cache = DiskCache::new(&dir, 1500, Duration::ZERO).await.unwrap();
+        let remaining = ["a", "b"]
+            .iter()
+            .filter(|key| dir.join(format!("{}.bin", key)).exists())
+            .count();
+        assert_eq!(remaining, 1);
+        drop(cache);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
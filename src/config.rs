@@ -0,0 +1,306 @@
+use std::{env, path::PathBuf, str::FromStr};
+
+use image::ImageFormat;
+use reqwest::Url;
+
+use crate::ops::{JpegBackend, OutputFormat};
+
+const DEFAULT_MAX_MULTIPART_FIELDS: usize = 32;
+const DEFAULT_DISK_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024; // 1GB
+const DEFAULT_DISK_CACHE_TTL_SECS: u64 = 7 * 24 * 60 * 60; // 1 week
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024; // 10MB
+const DEFAULT_MAX_JSON_BODY_BYTES: usize = 64 * 1024; // 64KB
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_SOURCE_DIMENSION: u32 = 16384;
+const DEFAULT_MAX_SOURCE_PIXELS: u64 = 100_000_000;
+const DEFAULT_SOURCE_CACHE_MAX_AGE_SECS: u64 = 60;
+const DEFAULT_REQUEST_LOG_SAMPLE_EVERY: u64 = 100;
+const DEFAULT_LUT_CACHE_ENTRIES: usize = 16;
+const DEFAULT_JOB_WORKERS: usize = 2;
+const DEFAULT_JOB_QUEUE_SIZE: usize = 64;
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum number of multipart fields processed per upload.
+    pub max_multipart_fields: usize,
+    /// Largest request body accepted by `/upload` and `/jobs`, in bytes.
+    pub max_upload_bytes: usize,
+    /// Largest JSON request body accepted by `POST /url`, in bytes.
+    pub max_json_body_bytes: usize,
+    /// Seconds a request may take from arrival to response, including reading
+    /// its body and fetching images; unlimited when 0.
+    pub request_timeout_secs: u64,
+    /// User-Agent sent with outbound image fetches.
+    pub user_agent: String,
+    /// Whether the client's `Referer` header is forwarded to image origins.
+    pub forward_referer: bool,
+    /// Directory for the persistent processed-image cache; disabled when unset.
+    pub disk_cache_dir: Option<PathBuf>,
+    /// Size cap for the disk cache in bytes, enforced by LRU eviction; unbounded when 0.
+    pub disk_cache_max_bytes: u64,
+    /// Seconds a disk cache entry is served before it is discarded; never when 0.
+    pub disk_cache_ttl_secs: u64,
+    /// Number of source images kept in memory; the source cache is disabled when 0.
+    pub source_cache_entries: usize,
+    /// Seconds a cached source image is used before it is revalidated.
+    pub source_cache_max_age_secs: u64,
+    /// Number of parsed `lut_url` LUTs kept in memory; disabled when 0.
+    pub lut_cache_entries: usize,
+    /// Whether per-stage durations are exposed in a `Server-Timing` header.
+    pub server_timing: bool,
+    /// Largest width or height of a source image accepted for decoding.
+    pub max_source_dimension: u32,
+    /// Largest pixel count of a source image accepted for decoding.
+    pub max_source_pixels: u64,
+    /// Whether `/url` may read images from the local files directory.
+    pub allow_local_files: bool,
+    /// Whether requests may ask for `debug_stamp`, which is meant for QA builds.
+    pub allow_debug_stamp: bool,
+    /// Directory that local file paths are resolved against.
+    pub local_files_dir: PathBuf,
+    /// Hosts images may be fetched from, with `*.` wildcards for subdomains;
+    /// any host is allowed when empty.
+    pub allowed_source_hosts: Vec<String>,
+    /// Hosts job callbacks may be posted to, with `*.` wildcards for
+    /// subdomains; any public host is allowed when empty. Listed hosts may
+    /// resolve to private addresses.
+    pub allowed_callback_hosts: Vec<String>,
+    /// Source formats that may be decoded; any supported format is accepted
+    /// when unset.
+    pub allowed_input_formats: Option<Vec<ImageFormat>>,
+    /// Whether encoded images are streamed to the client instead of buffered;
+    /// outputs written to the disk cache are always buffered.
+    pub stream_responses: bool,
+    /// Output format for `/url` requests without `output_format`; inferred
+    /// from the source image when unset.
+    pub url_default_format: Option<OutputFormat>,
+    /// Image served by `/url` requests with `on_error=fallback` and no
+    /// `fallback_url` when their source cannot be fetched.
+    pub fallback_image_url: Option<String>,
+    /// Output format for `/upload` requests without `output_format`; inferred
+    /// from the source image when unset.
+    pub upload_default_format: Option<OutputFormat>,
+    /// JPEG encoder used for requests without `jpeg_encoder`.
+    pub jpeg_encoder: JpegBackend,
+    /// Base URL clients reach the service at, used in generated `/srcset`
+    /// URLs; they are relative when unset.
+    pub public_base_url: Option<Url>,
+    /// Whether each request is logged with its status, response size and latency.
+    pub request_log: bool,
+    /// File that sampled requests are appended to while request logging is on.
+    pub request_log_sample_file: Option<PathBuf>,
+    /// Every n-th request is written to the sample file.
+    pub request_log_sample_every: u64,
+    /// Endpoint of the S3-compatible service that `store=s3` uploads to.
+    pub s3_endpoint: Option<String>,
+    /// Bucket that stored images are written to.
+    pub s3_bucket: Option<String>,
+    /// Region used when signing storage requests.
+    pub s3_region: String,
+    /// Access key ID used when signing storage requests.
+    pub s3_access_key_id: Option<String>,
+    /// Secret access key used when signing storage requests.
+    pub s3_secret_access_key: Option<String>,
+    /// Base URL returned for stored objects; defaults to the object's
+    /// endpoint URL.
+    pub s3_public_url: Option<String>,
+    /// Number of workers processing `/jobs` submissions in the background.
+    pub job_workers: usize,
+    /// Jobs that may wait for a worker before submissions are rejected.
+    pub job_queue_size: usize,
+    /// Outbound image fetches allowed at once; unlimited when 0.
+    pub max_concurrent_fetches: usize,
+    /// Requests per second each client IP may make to the processing routes;
+    /// rate limiting is disabled when 0.
+    pub rate_limit_per_second: f64,
+    /// Requests a client may make in a burst before being limited; defaults
+    /// to one second's worth.
+    pub rate_limit_burst: Option<u32>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            max_multipart_fields: env_or("MAX_MULTIPART_FIELDS", DEFAULT_MAX_MULTIPART_FIELDS),
+            max_upload_bytes: env_or("MAX_UPLOAD_BYTES", DEFAULT_MAX_UPLOAD_BYTES),
+            max_json_body_bytes: env_or("MAX_JSON_BODY_BYTES", DEFAULT_MAX_JSON_BODY_BYTES),
+            request_timeout_secs: env_or("REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS),
+            user_agent: env_or("FETCH_USER_AGENT", DEFAULT_USER_AGENT.to_string()),
+            forward_referer: env_flag("FORWARD_REFERER", false),
+            disk_cache_dir: env_opt("DISK_CACHE_DIR"),
+            disk_cache_max_bytes: env_or("DISK_CACHE_MAX_BYTES", DEFAULT_DISK_CACHE_MAX_BYTES),
+            disk_cache_ttl_secs: env_or("DISK_CACHE_TTL_SECS", DEFAULT_DISK_CACHE_TTL_SECS),
+            source_cache_entries: env_or("SOURCE_CACHE_ENTRIES", 0),
+            source_cache_max_age_secs: env_or(
+                "SOURCE_CACHE_MAX_AGE",
+                DEFAULT_SOURCE_CACHE_MAX_AGE_SECS,
+            ),
+            lut_cache_entries: env_or("LUT_CACHE_ENTRIES", DEFAULT_LUT_CACHE_ENTRIES),
+            server_timing: env_flag("SERVER_TIMING", false),
+            max_source_dimension: env_or("MAX_SOURCE_DIMENSION", DEFAULT_MAX_SOURCE_DIMENSION),
+            max_source_pixels: env_or("MAX_SOURCE_PIXELS", DEFAULT_MAX_SOURCE_PIXELS),
+            allow_local_files: env_flag("ALLOW_LOCAL_FILES", false),
+            allow_debug_stamp: env_flag("ALLOW_DEBUG_STAMP", false),
+            local_files_dir: env_or("LOCAL_FILES_DIR", PathBuf::from(".")),
+            allowed_source_hosts: env_list("ALLOWED_SOURCE_HOSTS"),
+            allowed_callback_hosts: env_list("ALLOWED_CALLBACK_HOSTS"),
+            allowed_input_formats: env_formats("ALLOWED_INPUT_FORMATS"),
+            stream_responses: env_flag("STREAM_RESPONSES", false),
+            url_default_format: env_opt("URL_DEFAULT_FORMAT"),
+            fallback_image_url: env_opt("FALLBACK_IMAGE_URL"),
+            upload_default_format: env_opt("UPLOAD_DEFAULT_FORMAT"),
+            jpeg_encoder: env_or("JPEG_ENCODER", JpegBackend::default()),
+            public_base_url: env_opt("PUBLIC_BASE_URL"),
+            request_log: env_flag("REQUEST_LOG", false),
+            request_log_sample_file: env_opt("REQUEST_LOG_SAMPLE_FILE"),
+            request_log_sample_every: env_or(
+                "REQUEST_LOG_SAMPLE_EVERY",
+                DEFAULT_REQUEST_LOG_SAMPLE_EVERY,
+            ),
+            s3_endpoint: env_opt("S3_ENDPOINT"),
+            s3_bucket: env_opt("S3_BUCKET"),
+            s3_region: env_or("S3_REGION", DEFAULT_S3_REGION.to_string()),
+            s3_access_key_id: env_opt("S3_ACCESS_KEY_ID"),
+            s3_secret_access_key: env_opt("S3_SECRET_ACCESS_KEY"),
+            s3_public_url: env_opt("S3_PUBLIC_URL"),
+            job_workers: env_or("JOB_WORKERS", DEFAULT_JOB_WORKERS),
+            job_queue_size: env_or("JOB_QUEUE_SIZE", DEFAULT_JOB_QUEUE_SIZE),
+            max_concurrent_fetches: env_or("MAX_CONCURRENT_FETCHES", 0),
+            rate_limit_per_second: env_or("RATE_LIMIT_PER_SECOND", 0.0),
+            rate_limit_burst: env_opt("RATE_LIMIT_BURST"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_multipart_fields: DEFAULT_MAX_MULTIPART_FIELDS,
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            max_json_body_bytes: DEFAULT_MAX_JSON_BODY_BYTES,
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            forward_referer: false,
+            disk_cache_dir: None,
+            disk_cache_max_bytes: DEFAULT_DISK_CACHE_MAX_BYTES,
+            disk_cache_ttl_secs: DEFAULT_DISK_CACHE_TTL_SECS,
+            source_cache_entries: 0,
+            source_cache_max_age_secs: DEFAULT_SOURCE_CACHE_MAX_AGE_SECS,
+            lut_cache_entries: DEFAULT_LUT_CACHE_ENTRIES,
+            server_timing: false,
+            max_source_dimension: DEFAULT_MAX_SOURCE_DIMENSION,
+            max_source_pixels: DEFAULT_MAX_SOURCE_PIXELS,
+            allow_local_files: false,
+            allow_debug_stamp: false,
+            local_files_dir: PathBuf::from("."),
+            allowed_source_hosts: Vec::new(),
+            allowed_callback_hosts: Vec::new(),
+            allowed_input_formats: None,
+            stream_responses: false,
+            url_default_format: None,
+            fallback_image_url: None,
+            upload_default_format: None,
+            jpeg_encoder: JpegBackend::default(),
+            public_base_url: None,
+            request_log: false,
+            request_log_sample_file: None,
+            request_log_sample_every: DEFAULT_REQUEST_LOG_SAMPLE_EVERY,
+            s3_endpoint: None,
+            s3_bucket: None,
+            s3_region: DEFAULT_S3_REGION.to_string(),
+            s3_access_key_id: None,
+            s3_secret_access_key: None,
+            s3_public_url: None,
+            job_workers: DEFAULT_JOB_WORKERS,
+            job_queue_size: DEFAULT_JOB_QUEUE_SIZE,
+            max_concurrent_fetches: 0,
+            rate_limit_per_second: 0.0,
+            rate_limit_burst: None,
+        }
+    }
+}
+
+/// Reads and parses `key`, falling back to `default` when it is unset or
+/// empty. A value that doesn't parse stops startup rather than silently
+/// running with the default.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env_opt(key).unwrap_or(default)
+}
+
+/// Reads and parses `key`, or `None` when it is unset or empty. Panics on a
+/// value that doesn't parse.
+fn env_opt<T: FromStr>(key: &str) -> Option<T> {
+    let value = env::var(key).ok()?;
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => panic!("invalid value for {}: {:?}", key, value),
+    }
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a list of image formats by extension, or `None` when it is unset or
+/// empty. Panics on an unknown name, like [`env_opt`].
+fn env_formats(key: &str) -> Option<Vec<ImageFormat>> {
+    let names = env_list(key);
+    if names.is_empty() {
+        return None;
+    }
+    Some(
+        names
+            .iter()
+            .map(|name| {
+                ImageFormat::from_extension(name.to_lowercase())
+                    .unwrap_or_else(|| panic!("invalid value for {}: {:?}", key, name))
+            })
+            .collect(),
+    )
+}
+
+fn env_flag(key: &str, default: bool) -> bool {
+    match env::var(key) {
+        Ok(value) => matches!(
+            value.trim().to_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_and_empty_variables_use_the_default() {
+        assert_eq!(env_or("CONFIG_TEST_UNSET", 7u32), 7);
+        // SAFETY: the variable is only used by this test
+        unsafe { env::set_var("CONFIG_TEST_EMPTY", " ") };
+        assert_eq!(env_or("CONFIG_TEST_EMPTY", 7u32), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value for CONFIG_TEST_INVALID")]
+    fn unparsable_variables_stop_startup() {
+        // SAFETY: the variable is only used by this test
+        unsafe { env::set_var("CONFIG_TEST_INVALID", "fast") };
+        env_or("CONFIG_TEST_INVALID", 0.0f64);
+    }
+}
@@ -1,10 +1,11 @@
 use axum::{
     Json,
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
-use image::ImageError;
+use image::{ImageError, ImageFormat};
 use serde_json::json;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum AppError {
@@ -13,14 +14,91 @@ pub enum AppError {
     MultipartError(axum::extract::multipart::MultipartError),
     IoError(std::io::Error),
     ReqwestError(reqwest::Error),
+    ArchiveError(zip::result::ZipError),
+    ExifError(exif::Error),
     MissingImageFile,
+    EmptyImageFile,
+    InvalidImageSource(&'static str),
+    LocalFileAccessDenied(&'static str),
+    DebugStampDisabled,
+    SourceHostNotAllowed(String),
+    LocalFileNotFound(String),
+    UnrecognizedImageFormat,
+    UnsupportedInputFormat(String),
+    InputFormatNotAllowed(String),
+    DuplicateImageFile,
+    TooManyMultipartFields(usize),
+    InvalidFormField {
+        field: &'static str,
+        value: String,
+    },
     UnsupportedFilter(String),
     InvalidFilterParameters(String),
     UnsupportedOutputFormat(String),
+    InvalidEncoderOption(String),
+    ParameterNotSupported {
+        parameter: &'static str,
+        route: &'static str,
+    },
+    SourceImageTooLarge {
+        width: u32,
+        height: u32,
+    },
+    InvalidImageDimensions {
+        width: u32,
+        height: u32,
+    },
     InvalidCropDimensions(&'static str),
+    InvalidRegion(String),
+    InvalidColor(String),
+    InvalidLut(String),
+    InvalidGravity(String),
     InvalidResizeDimensions(&'static str),
+    InvalidResizeMode(String),
+    InvalidUpscaleMode(String),
+    InvalidRotateMode(String),
+    InvalidRoundMode(String),
+    InvalidResizeFilter(String),
+    InvalidPipeline(String),
+    InvalidSrcset(String),
+    InvalidOgCard(String),
+    InvalidTiles(String),
+    InvalidPage(String),
+    UpscaleNotAllowed {
+        width: u32,
+        height: u32,
+        source_width: u32,
+        source_height: u32,
+    },
+    InvalidStorageTarget(String),
+    StorageNotConfigured,
+    StorageError(String),
+    TransparencyNotSupported(&'static str),
+    MissingCallbackUrl,
+    InvalidCallbackUrl(String),
+    CallbackHostNotAllowed(String),
+    JobQueueFull,
+    OptimizationError(String),
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// A handler panicked; the details are logged, not sent to the client.
+    Panicked,
 }
 
+/// Raised by the HTTP client's redirect policy when a redirect leads to a
+/// host outside the source allowlist.
+#[derive(Debug)]
+pub struct RedirectNotAllowed(pub String);
+
+impl fmt::Display for RedirectNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redirect to {} is not allowed", self.0)
+    }
+}
+
+impl std::error::Error for RedirectNotAllowed {}
+
 impl From<ImageError> for AppError {
     fn from(err: ImageError) -> Self {
         AppError::ImageProcessingError(err)
@@ -41,13 +119,31 @@ impl From<std::io::Error> for AppError {
 
 impl From<reqwest::Error> for AppError {
     fn from(err: reqwest::Error) -> Self {
+        let redirect_denied = std::error::Error::source(&err)
+            .and_then(|source| source.downcast_ref::<RedirectNotAllowed>());
+        if let Some(RedirectNotAllowed(host)) = redirect_denied {
+            return AppError::SourceHostNotAllowed(host.clone());
+        }
         AppError::ReqwestError(err)
     }
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+impl From<zip::result::ZipError> for AppError {
+    fn from(err: zip::result::ZipError) -> Self {
+        AppError::ArchiveError(err)
+    }
+}
+
+impl From<exif::Error> for AppError {
+    fn from(err: exif::Error) -> Self {
+        AppError::ExifError(err)
+    }
+}
+
+impl AppError {
+    /// HTTP status and client-facing message for the error.
+    fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
             AppError::ImageFetchError(msg) => (
                 StatusCode::BAD_REQUEST,
                 format!("failed to fetch image: {}", msg),
@@ -56,9 +152,10 @@ impl IntoResponse for AppError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("image processing failed: {}", err),
             ),
+            // Reports bodies over the route's size limit as 413
             AppError::MultipartError(err) => (
-                StatusCode::BAD_REQUEST,
-                format!("invalid multipart data: {}", err),
+                err.status(),
+                format!("invalid multipart data: {}", err.body_text()),
             ),
             AppError::IoError(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -68,10 +165,67 @@ impl IntoResponse for AppError {
                 StatusCode::BAD_GATEWAY,
                 format!("external request failed: {}", err),
             ),
+            AppError::ArchiveError(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to create archive: {}", err),
+            ),
+            AppError::OptimizationError(msg) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to optimize image: {}", msg),
+            ),
+            AppError::ExifError(err) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("failed to read exif metadata: {}", err),
+            ),
+            AppError::InvalidImageSource(msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
+            AppError::LocalFileAccessDenied(msg) => (StatusCode::FORBIDDEN, msg.to_string()),
+            AppError::DebugStampDisabled => {
+                (StatusCode::FORBIDDEN, "debug_stamp is disabled".to_string())
+            }
+            AppError::SourceHostNotAllowed(host) => (
+                StatusCode::FORBIDDEN,
+                format!("fetching images from {} is not allowed", host),
+            ),
+            AppError::LocalFileNotFound(path) => (
+                StatusCode::NOT_FOUND,
+                format!("local file not found: {}", path),
+            ),
             AppError::MissingImageFile => (
                 StatusCode::BAD_REQUEST,
                 "no image file found in upload.".to_string(),
             ),
+            AppError::UnrecognizedImageFormat => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "could not recognize the image format.".to_string(),
+            ),
+            AppError::InputFormatNotAllowed(format) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("image format not allowed: {}", format),
+            ),
+            AppError::UnsupportedInputFormat(format) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!(
+                    "unsupported image format: {}; supported formats are {}",
+                    format,
+                    supported_input_formats().join(", ")
+                ),
+            ),
+            AppError::EmptyImageFile => (
+                StatusCode::BAD_REQUEST,
+                "uploaded image file is empty.".to_string(),
+            ),
+            AppError::DuplicateImageFile => (
+                StatusCode::BAD_REQUEST,
+                "background jobs accept only one image file.".to_string(),
+            ),
+            AppError::TooManyMultipartFields(max) => (
+                StatusCode::BAD_REQUEST,
+                format!("too many multipart fields (maximum is {})", max),
+            ),
+            AppError::InvalidFormField { field, value } => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid value for {}: {}", field, value),
+            ),
             AppError::UnsupportedFilter(filter) => (
                 StatusCode::BAD_REQUEST,
                 format!("unsupported filter type: {}", filter),
@@ -84,14 +238,179 @@ impl IntoResponse for AppError {
                 StatusCode::BAD_REQUEST,
                 format!("unsupported output format: {}", format),
             ),
+            AppError::SourceImageTooLarge { width, height } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "source image is too large to process ({}x{})",
+                    width, height
+                ),
+            ),
+            AppError::InvalidImageDimensions { width, height } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("source image has no pixels ({}x{})", width, height),
+            ),
+            AppError::InvalidEncoderOption(msg) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid encoder option: {}", msg),
+            ),
+            AppError::ParameterNotSupported { parameter, route } => (
+                StatusCode::BAD_REQUEST,
+                format!("{} is not supported by {}", parameter, route),
+            ),
             AppError::InvalidCropDimensions(msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
+            AppError::InvalidColor(color) => {
+                (StatusCode::BAD_REQUEST, format!("invalid color: {}", color))
+            }
+            AppError::InvalidLut(msg) => (StatusCode::BAD_REQUEST, format!("invalid lut: {}", msg)),
+            AppError::InvalidRegion(msg) => {
+                (StatusCode::BAD_REQUEST, format!("invalid region: {}", msg))
+            }
+            AppError::InvalidGravity(gravity) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid gravity: {}", gravity),
+            ),
             AppError::InvalidResizeDimensions(msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
-        };
+            AppError::InvalidResizeMode(mode) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid resize mode: {}", mode),
+            ),
+            AppError::InvalidUpscaleMode(mode) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid upscale mode: {}", mode),
+            ),
+            AppError::InvalidRotateMode(mode) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid rotate mode: {}", mode),
+            ),
+            AppError::InvalidResizeFilter(filter) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid resize filter: {}", filter),
+            ),
+            AppError::InvalidPipeline(msg) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid pipeline: {}", msg),
+            ),
+            AppError::InvalidSrcset(msg) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid srcset request: {}", msg),
+            ),
+            AppError::InvalidOgCard(msg) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid og-card request: {}", msg),
+            ),
+            AppError::InvalidTiles(msg) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid tiles request: {}", msg),
+            ),
+            AppError::InvalidPage(msg) => {
+                (StatusCode::BAD_REQUEST, format!("invalid page: {}", msg))
+            }
+            AppError::InvalidRoundMode(mode) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid round mode: {}", mode),
+            ),
+            AppError::UpscaleNotAllowed {
+                width,
+                height,
+                source_width,
+                source_height,
+            } => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "upscaling is not allowed: requested {}x{} but the source is {}x{}",
+                    width, height, source_width, source_height
+                ),
+            ),
+            AppError::InvalidStorageTarget(target) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid storage target: {}", target),
+            ),
+            AppError::StorageNotConfigured => (
+                StatusCode::BAD_REQUEST,
+                "storage is not configured".to_string(),
+            ),
+            AppError::StorageError(msg) => (
+                StatusCode::BAD_GATEWAY,
+                format!("failed to store image: {}", msg),
+            ),
+            AppError::TransparencyNotSupported(format) => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "{} cannot represent the image's transparency; set flatten or background_url, or use an output format with transparency such as png or webp",
+                    format
+                ),
+            ),
+            AppError::MissingCallbackUrl => (
+                StatusCode::BAD_REQUEST,
+                "missing callback_url field".to_string(),
+            ),
+            AppError::InvalidCallbackUrl(url) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid callback url: {}", url),
+            ),
+            AppError::CallbackHostNotAllowed(host) => (
+                StatusCode::FORBIDDEN,
+                format!("delivering callbacks to {} is not allowed", host),
+            ),
+            AppError::JobQueueFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "job queue is full, try again later".to_string(),
+            ),
+            AppError::RateLimited { retry_after_secs } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("too many requests, retry in {}s", retry_after_secs),
+            ),
+            AppError::Panicked => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal server error".to_string(),
+            ),
+        }
+    }
+}
 
+/// Seconds clients are asked to wait before resubmitting to a full job queue.
+const JOB_QUEUE_RETRY_AFTER_SECS: u64 = 5;
+
+impl AppError {
+    /// Seconds a client should wait before retrying, for errors caused by load
+    /// rather than by the request.
+    fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            AppError::RateLimited { retry_after_secs } => Some(*retry_after_secs),
+            AppError::JobQueueFull => Some(JOB_QUEUE_RETRY_AFTER_SECS),
+            _ => None,
+        }
+    }
+}
+
+/// Names of the formats this build can decode.
+fn supported_input_formats() -> Vec<&'static str> {
+    ImageFormat::all()
+        // The avif feature only brings in the encoder
+        .filter(|format| format.reading_enabled() && *format != ImageFormat::Avif)
+        .filter_map(|format| format.extensions_str().first().copied())
+        .collect()
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.status_and_message().1)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = self.status_and_message();
         let body = Json(json!({
             "error": error_message,
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let Some(retry_after_secs) = self.retry_after_secs() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+        }
+        response
     }
 }
@@ -0,0 +1,304 @@
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{
+    Client, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{error::AppError, ops::host_matches};
+
+/// A queued unit of background work.
+pub struct Job<T> {
+    pub id: String,
+    pub payload: T,
+}
+
+/// A job accepted by [`JobQueue::submit`].
+pub struct QueuedJob {
+    pub id: String,
+    /// Jobs waiting for a worker, including this one, when it was queued.
+    pub position: usize,
+}
+
+/// In-process queue of background jobs drained by a fixed pool of workers.
+///
+/// The queue is bounded, so submissions are rejected instead of piling up
+/// when the workers fall behind. Queued jobs are lost on shutdown.
+pub struct JobQueue<T> {
+    sender: mpsc::Sender<Job<T>>,
+    submitted: AtomicU64,
+}
+
+impl<T: Send + 'static> JobQueue<T> {
+    /// Spawns `workers` tasks that run `handler` for each job in turn.
+    pub fn start<F, Fut>(workers: usize, capacity: usize, handler: F) -> Self
+    where
+        F: Fn(Job<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        for _ in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+            tokio::spawn(async move {
+                loop {
+                    // The lock is released before the job runs, so other
+                    // workers can pick up the next one meanwhile
+                    let Some(job) = receiver.lock().await.recv().await else {
+                        break;
+                    };
+                    handler(job).await;
+                }
+            });
+        }
+        JobQueue {
+            sender,
+            submitted: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues a job, or rejects it when the queue is full.
+    pub fn submit(&self, payload: T) -> Result<QueuedJob, AppError> {
+        let id = self.next_id();
+        self.sender
+            .try_send(Job {
+                id: id.clone(),
+                payload,
+            })
+            .map_err(|_| AppError::JobQueueFull)?;
+        // Approximate, as workers may take jobs off the queue meanwhile
+        let position = self.sender.max_capacity() - self.sender.capacity();
+        Ok(QueuedJob { id, position })
+    }
+
+    /// Ids only need to be unique and hard to guess from one another, so
+    /// they are hashed from the submission time and a counter.
+    fn next_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(nanos.to_le_bytes());
+        hasher.update(self.submitted.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        format!("{:x}", hasher.finalize())[..16].to_string()
+    }
+}
+
+/// Parses a callback URL, which must use http or https.
+///
+/// Callbacks are posted from inside the service's network, so hosts that
+/// are loopback, private or link-local addresses are refused unless they are
+/// on `allowed_hosts`. When `allowed_hosts` is not empty, other hosts are
+/// refused too. Names are checked again when they are resolved, by the
+/// client from [`build_callback_client`].
+pub fn parse_callback_url(url: &str, allowed_hosts: &[String]) -> Result<Url, AppError> {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+        _ => return Err(AppError::InvalidCallbackUrl(url.to_string())),
+    };
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| AppError::InvalidCallbackUrl(url.to_string()))?;
+    if is_listed(host, allowed_hosts) {
+        return Ok(parsed);
+    }
+
+    let internal = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => !is_public_ip(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_lowercase();
+            host == "localhost" || host.ends_with(".localhost")
+        }
+    };
+    if internal || !allowed_hosts.is_empty() {
+        return Err(AppError::CallbackHostNotAllowed(host.to_string()));
+    }
+    Ok(parsed)
+}
+
+/// Builds the client job callbacks are posted with. It does not follow
+/// redirects, and names that are not on `allowed_hosts` may only resolve to
+/// public addresses.
+pub fn build_callback_client(
+    user_agent: &str,
+    allowed_hosts: &[String],
+) -> Result<Client, AppError> {
+    Ok(Client::builder()
+        .user_agent(user_agent)
+        .redirect(redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver {
+            allowed_hosts: allowed_hosts.to_vec(),
+        }))
+        .build()?)
+}
+
+/// Resolver that drops non-public addresses, so a public name cannot be
+/// pointed at an internal service after its callback was accepted.
+struct PublicResolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let listed = is_listed(&host, &self.allowed_hosts);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| listed || is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_listed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|pattern| host_matches(host, pattern))
+}
+
+/// Whether `ip` is routable on the public internet.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{StatusCode, header},
+        response::IntoResponse,
+    };
+
+    #[test]
+    fn callback_urls_must_be_http() {
+        assert!(parse_callback_url("https://hooks.example.com/done", &[]).is_ok());
+        assert!(matches!(
+            parse_callback_url("ftp://hooks.example.com/done", &[]),
+            Err(AppError::InvalidCallbackUrl(_))
+        ));
+        assert!(matches!(
+            parse_callback_url("not a url", &[]),
+            Err(AppError::InvalidCallbackUrl(_))
+        ));
+    }
+
+    #[test]
+    fn callback_urls_reject_internal_hosts() {
+        for url in [
+            "http://127.0.0.1:8080/done",
+            "http://localhost/done",
+            "http://api.localhost/done",
+            "http://10.0.0.5/done",
+            "http://192.168.1.1/done",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/done",
+            "http://[fd00::1]/done",
+            "http://[::ffff:127.0.0.1]/done",
+            "http://0.0.0.0/done",
+        ] {
+            assert!(
+                matches!(
+                    parse_callback_url(url, &[]),
+                    Err(AppError::CallbackHostNotAllowed(_))
+                ),
+                "{} was accepted",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn callback_allowlist_restricts_and_exempts_hosts() {
+        let allowed = vec!["127.0.0.1".to_string(), "*.example.com".to_string()];
+        assert!(parse_callback_url("http://127.0.0.1:8080/done", &allowed).is_ok());
+        assert!(parse_callback_url("https://hooks.example.com/done", &allowed).is_ok());
+        assert!(matches!(
+            parse_callback_url("https://example.org/done", &allowed),
+            Err(AppError::CallbackHostNotAllowed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn callback_resolver_drops_internal_addresses() {
+        let resolver = PublicResolver {
+            allowed_hosts: Vec::new(),
+        };
+        assert!(
+            resolver
+                .resolve("localhost".parse().unwrap())
+                .await
+                .is_err()
+        );
+
+        let resolver = PublicResolver {
+            allowed_hosts: vec!["localhost".to_string()],
+        };
+        let addrs = resolver
+            .resolve("localhost".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(addrs.into_iter().all(|addr| addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn full_queue_rejects_jobs_with_retry_after() {
+        // Workers never finish, and on the test's single thread they haven't
+        // taken a job off the queue before it fills
+        let queue = JobQueue::start(1, 2, |_job: Job<()>| std::future::pending());
+
+        assert_eq!(queue.submit(()).unwrap().position, 1);
+        assert_eq!(queue.submit(()).unwrap().position, 2);
+        let Err(error) = queue.submit(()) else {
+            panic!("a third job fit into the queue");
+        };
+        assert!(matches!(error, AppError::JobQueueFull));
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+}
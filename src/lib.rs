@@ -1,2 +1,11 @@
+pub mod archive;
+pub mod cache;
+pub mod config;
 pub mod error;
+pub mod jobs;
 pub mod ops;
+pub mod pipeline;
+pub mod rate_limit;
+pub mod request_log;
+pub mod storage;
+pub mod stream;
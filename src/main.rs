@@ -1,275 +1,5247 @@
-use std::path::Path;
+use std::{
+    any::Any,
+    fmt::Display,
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    Router,
-    extract::{DefaultBodyLimit, Multipart, Query},
-    http::{HeaderMap, HeaderValue, StatusCode},
-    response::IntoResponse,
+    Json, Router,
+    extract::{DefaultBodyLimit, Multipart, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
 use bytes::Bytes;
-use image::{DynamicImage, GenericImageView, imageops::FilterType};
-use serde::Deserialize;
-use tokio::net::TcpListener;
-use tracing::debug;
+use image::{DynamicImage, GenericImageView, Rgba, imageops::FilterType};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    sync::{Semaphore, SemaphorePermit},
+};
+use tower_http::{catch_panic::CatchPanicLayer, timeout::TimeoutLayer};
+use tracing::{debug, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use rust_image_service::{
+    archive,
+    cache::{CachedImage, DiskCache, LutCache, SourceCache},
+    config::Config,
     error::AppError,
-    ops::{self, ProcessedImage, apply_filter_str},
+    jobs::{self, Job, JobQueue},
+    ops::{
+        self, ConditionalFetch, EncodeOptions, FetchedImage, Gravity, HashAlgorithm, JpegBackend,
+        Lut, OutputFormat, ProcessedImage, Quality, QualityPreset, ResizeFilter, ResizeMode,
+        RotateMode, RoundMode, UpscaleMode, apply_filter_str,
+    },
+    pipeline::Pipeline,
+    rate_limit, request_log,
+    storage::{S3Storage, StorageTarget},
+    stream,
 };
 
+/// Parameters for processing a remote image, accepted either as a query string
+/// (`GET /url`) or as a JSON body (`POST /url`).
 #[derive(Deserialize, Debug)]
 struct ImageUrlParams {
-    url: String,
+    url: Option<String>,
+    /// Path relative to the local files directory, when local files are enabled.
+    file: Option<String>,
+    /// Image served instead when `url` cannot be fetched and `on_error` is
+    /// `fallback`; overrides `FALLBACK_IMAGE_URL`.
+    fallback_url: Option<String>,
+    on_error: Option<OnError>,
+    #[serde(flatten)]
+    transform: TransformParams,
+}
+
+/// Where a `/url` request reads its source image from.
+enum ImageSource<'a> {
+    Url(&'a str),
+    LocalFile(&'a str),
+}
+
+/// What a `/url` request does when its source image cannot be fetched.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum OnError {
+    /// Responds with the error.
+    #[default]
+    Error,
+    /// Processes the fallback image instead.
+    Fallback,
+}
+
+impl ImageUrlParams {
+    /// Image to process instead when the source cannot be fetched, if the
+    /// request asks for one.
+    fn fallback_url<'a>(&'a self, config: &'a Config) -> Option<&'a str> {
+        if self.on_error.unwrap_or_default() != OnError::Fallback {
+            return None;
+        }
+        self.fallback_url
+            .as_deref()
+            .or(config.fallback_image_url.as_deref())
+    }
+
+    fn source(&self) -> Result<ImageSource<'_>, AppError> {
+        match (&self.url, &self.file) {
+            (Some(url), None) => Ok(ImageSource::Url(url)),
+            (None, Some(file)) => Ok(ImageSource::LocalFile(file)),
+            _ => Err(AppError::InvalidImageSource(
+                "exactly one of url or file must be provided",
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct TransformParams {
+    /// Page of a multi-page TIFF to process, counted from 1.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    page: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     w: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     h: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     crop_x: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     crop_y: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     crop_w: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
     crop_h: Option<u32>,
+    crop_gravity: Option<Gravity>,
+    /// Shrinks a crop window reaching past the image edges to fit, instead
+    /// of rejecting it.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    crop_clamp: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    crop_xf: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    crop_yf: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    crop_wf: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    crop_hf: Option<f64>,
+    /// Scales to this width, deriving the height from the aspect ratio.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    fit_width: Option<u32>,
+    /// Scales to this height, deriving the width from the aspect ratio.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    fit_height: Option<u32>,
+    mode: Option<ResizeMode>,
+    /// Crops `mode=cover` resizes toward the busiest region instead of the center.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    smart: Option<bool>,
+    upscale: Option<UpscaleMode>,
+    /// Interpolation used when the output is larger than the source.
+    upscale_filter: Option<ResizeFilter>,
+    /// Interpolation used when the output is smaller than the source.
+    downscale_filter: Option<ResizeFilter>,
+    /// Rounds resized dimensions to a multiple of this value.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    round_to: Option<u32>,
+    round: Option<RoundMode>,
+    /// Treats the source as having premultiplied alpha and undoes it first.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    unpremultiply: Option<bool>,
+    /// Premultiplies the output's color channels by its alpha.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    premultiply: Option<bool>,
+    /// Writes the applied operations onto the output, when `ALLOW_DEBUG_STAMP`
+    /// is set.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    debug_stamp: Option<bool>,
+    /// `x,y,w,h:sigma` region of the source image to blur.
+    blur_region: Option<String>,
+    /// Semicolon-separated `x,y,w,h` regions of the source image to fill.
+    redact: Option<String>,
+    /// Hex color used to fill redacted regions; black by default.
+    redact_color: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    post_sharpen: Option<bool>,
     filter: Option<String>,
+    /// `exif` writes right-angle rotations ending the filter chain as an EXIF
+    /// orientation tag on JPEG output instead of rotating the pixels.
+    rotate_mode: Option<RotateMode>,
+    /// Ordered operations that replace the crop, resize, filter and output
+    /// format parameters, e.g. `crop(0,0,100,100);resize(50);format(webp:80)`.
+    pipeline: Option<String>,
+    /// URL of a `.cube` 3D LUT to color grade the image with.
+    lut_url: Option<String>,
+    background_url: Option<String>,
+    background_gravity: Option<Gravity>,
+    /// Hex color to composite transparent areas onto.
+    flatten: Option<String>,
+    /// Rejects transparent images for formats without alpha instead of
+    /// flattening them onto white.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    strict: Option<bool>,
     output_format: Option<String>,
-    quality: Option<u8>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    quality: Option<Quality>,
+    png_compression: Option<String>,
+    /// Encoder effort from 0 to 10, or `fast`/`slow`.
+    effort: Option<String>,
+    png_filter: Option<String>,
+    /// Losslessly recompresses PNG output, when built with the `oxipng` feature.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    optimize: Option<bool>,
+    /// Overrides `JPEG_ENCODER` for this request.
+    jpeg_encoder: Option<JpegBackend>,
+    /// Alpha channel quality for lossy WebP, when built with the `libwebp`
+    /// feature.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    webp_alpha_quality: Option<u8>,
+    /// Uploads the output to the given storage and returns its URL instead;
+    /// left out of cache keys since it does not change the output.
+    #[serde(default, deserialize_with = "deserialize_from_str", skip_serializing)]
+    store: Option<StorageTarget>,
+    /// `1` or `true` asks browsers to download the image rather than display
+    /// it; left out of cache keys since it does not change the output.
+    #[serde(skip_serializing)]
+    dl: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Default)]
-struct ImageFormDataParams {
-    w: Option<u32>,
-    h: Option<u32>,
-    crop_x: Option<u32>,
-    crop_y: Option<u32>,
-    crop_w: Option<u32>,
-    crop_h: Option<u32>,
-    filter: Option<String>,
+impl TransformParams {
+    /// Builds the encoder settings, using `default_format` when no output
+    /// format was requested.
+    fn encode_options(
+        &self,
+        config: &Config,
+        default_format: OutputFormat,
+    ) -> Result<EncodeOptions, AppError> {
+        let pipeline = self.pipeline()?;
+        let format = match (
+            pipeline.as_ref().and_then(Pipeline::output_format),
+            self.output_format.as_deref(),
+        ) {
+            (Some(format), _) => format,
+            (None, Some(_)) if self.jpeg_or_png() => OutputFormat::Jpeg,
+            (None, Some(format)) => format.parse()?,
+            (None, None) => default_format,
+        };
+        let mut options = self.encode_options_for(config, format)?;
+        options.png_if_transparent = pipeline
+            .as_ref()
+            .and_then(Pipeline::output_format)
+            .is_none()
+            && self.jpeg_or_png();
+        if let Some(quality) = pipeline.as_ref().and_then(Pipeline::quality) {
+            options.quality = Some(quality);
+        }
+        Ok(options)
+    }
+
+    /// Rejects parameters the configuration keeps out of production.
+    fn check_allowed(&self, config: &Config) -> Result<(), AppError> {
+        if self.debug_stamp == Some(true) && !config.allow_debug_stamp {
+            return Err(AppError::DebugStampDisabled);
+        }
+        Ok(())
+    }
+
+    /// Whether `output_format=jpeg_or_png` asks for JPEG, or PNG when the
+    /// result has meaningful transparency.
+    fn jpeg_or_png(&self) -> bool {
+        self.output_format
+            .as_deref()
+            .is_some_and(|format| format.trim().eq_ignore_ascii_case("jpeg_or_png"))
+    }
+
+    /// Whether the response should be sent as an attachment.
+    fn download(&self) -> bool {
+        self.dl
+            .as_deref()
+            .is_some_and(|dl| matches!(dl.trim(), "1" | "true"))
+    }
+
+    /// Parses the pipeline, rejecting it alongside the crop, resize and
+    /// filter parameters it replaces, which would otherwise be dropped.
+    fn pipeline(&self) -> Result<Option<Pipeline>, AppError> {
+        let Some(pipeline) = &self.pipeline else {
+            return Ok(None);
+        };
+        let replaced = [
+            ("w", self.w.is_some()),
+            ("h", self.h.is_some()),
+            ("fit_width", self.fit_width.is_some()),
+            ("fit_height", self.fit_height.is_some()),
+            ("crop_x", self.crop_x.is_some()),
+            ("crop_y", self.crop_y.is_some()),
+            ("crop_w", self.crop_w.is_some()),
+            ("crop_h", self.crop_h.is_some()),
+            ("crop_gravity", self.crop_gravity.is_some()),
+            ("crop_clamp", self.crop_clamp.is_some()),
+            ("crop_xf", self.crop_xf.is_some()),
+            ("crop_yf", self.crop_yf.is_some()),
+            ("crop_wf", self.crop_wf.is_some()),
+            ("crop_hf", self.crop_hf.is_some()),
+            ("mode", self.mode.is_some()),
+            ("smart", self.smart.is_some()),
+            ("upscale", self.upscale.is_some()),
+            ("upscale_filter", self.upscale_filter.is_some()),
+            ("downscale_filter", self.downscale_filter.is_some()),
+            ("round_to", self.round_to.is_some()),
+            ("round", self.round.is_some()),
+            ("post_sharpen", self.post_sharpen.is_some()),
+            ("filter", self.filter.is_some()),
+            ("rotate_mode", self.rotate_mode.is_some()),
+        ];
+        if let Some((name, _)) = replaced.iter().find(|(_, given)| *given) {
+            return Err(AppError::InvalidPipeline(format!(
+                "{} cannot be combined with a pipeline",
+                name
+            )));
+        }
+        pipeline.parse().map(Some)
+    }
+
+    /// With `rotate_mode=exif`, splits the right-angle rotations ending the
+    /// filter chain off, returning the remaining parameters and the EXIF
+    /// orientation standing in for the rotations. Applies only to JPEG output
+    /// with nothing after the filters that depends on which way the pixels
+    /// face; everything else rotates the pixels.
+    fn split_exif_rotation(
+        &self,
+        encode_options: &EncodeOptions,
+    ) -> Result<Option<(TransformParams, u16)>, AppError> {
+        if self.rotate_mode != Some(RotateMode::Exif)
+            || encode_options.format != OutputFormat::Jpeg
+            || encode_options.png_if_transparent
+            || self.pipeline.is_some()
+            || self.background_url.is_some()
+            || self.debug_stamp == Some(true)
+        {
+            return Ok(None);
+        }
+        let Some(filter) = &self.filter else {
+            return Ok(None);
+        };
+        let filters = ops::parse_filter_chain(filter)?;
+        let kept = filters
+            .iter()
+            .rposition(|filter| filter.right_angle().is_none())
+            .map_or(0, |last| last + 1);
+        if kept == filters.len() {
+            return Ok(None);
+        }
+        let degrees: u32 = filters[kept..].iter().filter_map(|f| f.right_angle()).sum();
+        let filter = filters[..kept]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let params = TransformParams {
+            filter: Some(filter),
+            rotate_mode: None,
+            ..self.clone()
+        };
+        Ok(Some((params, ops::exif_orientation(degrees))))
+    }
+
+    /// Builds the encoder settings for a specific output format.
+    fn encode_options_for(
+        &self,
+        config: &Config,
+        format: OutputFormat,
+    ) -> Result<EncodeOptions, AppError> {
+        if !format.supports_alpha()
+            && !self.jpeg_or_png()
+            && let Some(filter) = &self.filter
+            && let Some(filter) = ops::parse_filter_chain(filter)?
+                .into_iter()
+                .find(|filter| filter.needs_alpha())
+        {
+            return Err(AppError::InvalidFilterParameters(format!(
+                "{} requires an output format with transparency, such as png or webp",
+                filter
+            )));
+        }
+
+        let mut options = EncodeOptions::new(format);
+        options.quality = self.quality.and_then(|quality| quality.for_format(format));
+        options.auto_quality = self.quality == Some(Quality::Preset(QualityPreset::Auto));
+        if let Some(effort) = &self.effort {
+            // Without libwebp, WebP output is lossless with no speed setting,
+            // so effort would silently do nothing
+            if format == OutputFormat::WebP && !cfg!(feature = "libwebp") {
+                return Err(AppError::InvalidEncoderOption(
+                    "effort for webp output requires the service to be built with the libwebp feature"
+                        .to_string(),
+                ));
+            }
+            options.effort = ops::parse_effort(effort)?;
+        }
+        if let Some(compression) = &self.png_compression {
+            options.png_compression = ops::parse_png_compression(compression)?;
+        }
+        if let Some(filter) = &self.png_filter {
+            options.png_filter = ops::parse_png_filter(filter)?;
+        }
+        if self.optimize == Some(true) {
+            if !cfg!(feature = "oxipng") {
+                return Err(AppError::InvalidEncoderOption(
+                    "optimize requires the service to be built with the oxipng feature".to_string(),
+                ));
+            }
+            options.optimize = true;
+        }
+        if let Some(alpha_quality) = self.webp_alpha_quality {
+            if !cfg!(feature = "libwebp") {
+                return Err(AppError::InvalidEncoderOption(
+                    "webp_alpha_quality requires the service to be built with the libwebp feature"
+                        .to_string(),
+                ));
+            }
+            if alpha_quality > 100 {
+                return Err(AppError::InvalidEncoderOption(format!(
+                    "webp_alpha_quality must be between 0 and 100, got {}",
+                    alpha_quality
+                )));
+            }
+            options.webp_alpha_quality = Some(alpha_quality);
+        }
+        options.jpeg_backend = self.jpeg_encoder.unwrap_or(config.jpeg_encoder);
+        if options.jpeg_backend == JpegBackend::Mozjpeg && !cfg!(feature = "mozjpeg") {
+            return Err(AppError::InvalidEncoderOption(
+                "the mozjpeg encoder requires the service to be built with the mozjpeg feature"
+                    .to_string(),
+            ));
+        }
+        Ok(options)
+    }
+
+    /// Whether the request asks for anything beyond a format conversion.
+    /// First given parameter, other than the output format, that only affects
+    /// how the output is encoded.
+    fn encoding_param(&self) -> Option<&'static str> {
+        let pipeline_format = self
+            .pipeline
+            .as_deref()
+            .and_then(|pipeline| pipeline.parse::<Pipeline>().ok())
+            .is_some_and(|pipeline| pipeline.output_format().is_some());
+        [
+            ("quality", self.quality.is_some()),
+            ("png_compression", self.png_compression.is_some()),
+            ("png_filter", self.png_filter.is_some()),
+            ("effort", self.effort.is_some()),
+            ("optimize", self.optimize.is_some()),
+            ("jpeg_encoder", self.jpeg_encoder.is_some()),
+            ("webp_alpha_quality", self.webp_alpha_quality.is_some()),
+            ("pipeline format", pipeline_format),
+        ]
+        .into_iter()
+        .find_map(|(name, given)| given.then_some(name))
+    }
+
+    /// First given parameter that changes how the output is delivered.
+    fn delivery_param(&self) -> Option<&'static str> {
+        [("store", self.store.is_some()), ("dl", self.dl.is_some())]
+            .into_iter()
+            .find_map(|(name, given)| given.then_some(name))
+    }
+
+    fn has_transformations(&self) -> bool {
+        self.page.is_some()
+            || self.w.is_some()
+            || self.h.is_some()
+            || self.fit_width.is_some()
+            || self.fit_height.is_some()
+            || self.crop_w.is_some()
+            || self.crop_h.is_some()
+            || self.crop_xf.is_some()
+            || self.crop_yf.is_some()
+            || self.crop_wf.is_some()
+            || self.crop_hf.is_some()
+            || self.unpremultiply == Some(true)
+            || self.premultiply == Some(true)
+            || self.debug_stamp == Some(true)
+            || self.blur_region.is_some()
+            || self.redact.is_some()
+            || self.quality.is_some()
+            || self.background_url.is_some()
+            || self.lut_url.is_some()
+            || self.pipeline.is_some()
+            || self.flatten.is_some()
+            || self.png_compression.is_some()
+            || self.effort.is_some()
+            || self.png_filter.is_some()
+            || self.optimize == Some(true)
+            || self.jpeg_encoder.is_some()
+            || self.webp_alpha_quality.is_some()
+            || self
+                .filter
+                .as_deref()
+                .is_some_and(|filter| !filter.trim().is_empty())
+    }
+}
+
+/// Flattened query parameters always reach serde as strings, so numeric fields
+/// accept either a native value (JSON) or its string representation (query).
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StrOrValue<T> {
+        Str(String),
+        Value(T),
+    }
+
+    match Option::<StrOrValue<T>>::deserialize(deserializer)? {
+        Some(StrOrValue::Str(s)) => s.parse().map(Some).map_err(de::Error::custom),
+        Some(StrOrValue::Value(value)) => Ok(Some(value)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SourceUrlParams {
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TileParams {
+    /// Edge length of the square tiles, in pixels.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    tile_size: Option<u32>,
+    #[serde(flatten)]
+    image: ImageUrlParams,
+}
+
+#[derive(Deserialize, Debug)]
+struct OgCardParams {
+    /// URL of the background image.
+    url: String,
+    title: String,
+    subtitle: Option<String>,
+    /// `png` (the default) or `webp`.
     output_format: Option<String>,
-    quality: Option<u8>,
 }
 
-const MAX_UPLOAD_SIZE: usize = 10 * 1024 * 1024; // 10MB
+#[derive(Deserialize, Debug)]
+struct SrcsetParams {
+    url: String,
+    /// Comma-separated widths, e.g. `320,640,1280`.
+    widths: String,
+}
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "rust_image_service=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+/// Durations of the processing stages of a single request.
+#[derive(Debug, Default)]
+struct StageTimings {
+    stages: Vec<(&'static str, Duration)>,
+}
 
-    let app = Router::new()
-        .route("/url", get(process_image_from_url))
-        .route("/upload", post(process_image_from_upload))
-        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE));
+impl StageTimings {
+    fn record(&mut self, stage: &'static str, started: Instant) {
+        let elapsed = started.elapsed();
+        debug!("{} took {:.2?}", stage, elapsed);
+        self.stages.push((stage, elapsed));
+    }
 
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    /// Formats the stages as a `Server-Timing` header value.
+    fn server_timing(&self) -> String {
+        self.stages
+            .iter()
+            .map(|(stage, elapsed)| format!("{};dur={:.3}", stage, elapsed.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
-async fn process_image_from_url(
-    Query(params): Query<ImageUrlParams>,
-) -> Result<impl IntoResponse, AppError> {
-    tracing::debug!("Processing image from URL: {:?}", params);
+#[derive(Deserialize, Debug)]
+struct PerceptualHashParams {
+    url: String,
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+}
 
-    let image_bytes = ops::fetch_image_bytes_from_url(&params.url).await?;
-    let mut img = image::load_from_memory(&image_bytes)?;
-
-    img = apply_transformations(
-        img,
-        params.w,
-        params.h,
-        params.crop_x,
-        params.crop_y,
-        params.crop_w,
-        params.crop_h,
-        params.filter,
-    )?;
+#[derive(Deserialize, Debug)]
+struct ExifParams {
+    url: String,
+    /// Leaves out GPS tags, which can reveal where a photo was taken.
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    omit_gps: Option<bool>,
+}
 
-    let output_format_str = params
-        .output_format
-        .clone()
-        .unwrap_or_else(|| infer_format_from_url_or_default(&params.url, "png"));
+#[derive(Deserialize, Debug)]
+struct FilterParams {
+    filter: String,
+}
 
-    let processed_image = ops::encode_image_to_bytes(img, &output_format_str, params.quality)?;
+/// Fields of a multipart upload to `/upload` or `/jobs`.
+struct UploadForm {
+    /// Every `image` field, in the order they were sent.
+    images: Vec<UploadedImage>,
+    params: TransformParams,
+    /// Where `/jobs` posts the result; ignored by `/upload`.
+    callback_url: Option<String>,
+}
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Content-Type",
-        HeaderValue::from_str(&processed_image.mime_type).unwrap(),
-    );
+struct UploadedImage {
+    bytes: Bytes,
+    filename: Option<String>,
+}
 
-    Ok((StatusCode::OK, headers, processed_image.bytes))
+/// An upload processed in the background, whose result is posted to the
+/// callback URL.
+struct ImageJob {
+    image: UploadedImage,
+    params: TransformParams,
+    encode_options: EncodeOptions,
+    callback_url: reqwest::Url,
+    /// Referer forwarded to image origins, when forwarding is enabled.
+    referer: Option<String>,
 }
 
-async fn process_image_from_upload(
-    mut multipart: Multipart,
-) -> Result<impl IntoResponse, AppError> {
-    debug!("Processing image from upload");
+const SNIFF_SIZE: usize = 4 * 1024; // 4KB
+/// Most tiles a single `/tiles` request may produce.
+const MAX_TILES: u64 = 4096;
+/// PNG files in a favicon set and their square sizes.
+const FAVICON_PNGS: &[(&str, u32)] = &[
+    ("favicon-16x16.png", 16),
+    ("favicon-32x32.png", 32),
+    ("apple-touch-icon.png", 180),
+    ("android-chrome-192x192.png", 192),
+    ("android-chrome-512x512.png", 512),
+];
+/// Resolutions packed into `favicon.ico`.
+const FAVICON_ICO_SIZES: &[u32] = &[16, 32, 48];
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'; sandbox";
+const REDACT_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+/// Background that transparent images are flattened onto for formats
+/// without alpha.
+const FLATTEN_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const POST_SHARPEN_SIGMA: f32 = 0.6;
+const POST_SHARPEN_THRESHOLD: i32 = 2;
 
-    let mut image_bytes: Option<Bytes> = None;
-    let image_filename: Option<String> = None;
-    let mut form_params = ImageFormDataParams::default();
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    http_client: reqwest::Client,
+    /// Posts job results; refuses to connect to internal addresses.
+    callback_client: reqwest::Client,
+    disk_cache: Option<Arc<DiskCache>>,
+    source_cache: Option<Arc<SourceCache>>,
+    lut_cache: Option<Arc<LutCache>>,
+    /// Bounds outbound fetches when `MAX_CONCURRENT_FETCHES` is set.
+    fetch_limit: Option<Arc<Semaphore>>,
+    storage: Option<Arc<S3Storage>>,
+    /// Set on the router's state; workers hold a copy without it.
+    jobs: Option<Arc<JobQueue<ImageJob>>>,
+}
 
-    while let Some(field) = multipart.next_field().await? {
-        let name = if let Some(name) = field.name() {
-            name.to_string()
-        } else {
-            continue;
+impl AppState {
+    /// Referer to forward to image origins, if forwarding is enabled.
+    fn outbound_referer<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        if !self.config.forward_referer {
+            return None;
+        }
+        headers
+            .get(header::REFERER)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Waits for a free slot when outbound fetches are limited. The slot is
+    /// held until the returned permit is dropped.
+    async fn fetch_permit(&self) -> Option<SemaphorePermit<'_>> {
+        let limit = self.fetch_limit.as_ref()?;
+        Some(
+            limit
+                .acquire()
+                .await
+                .expect("fetch semaphore is never closed"),
+        )
+    }
+
+    /// Fetches a remote image, going through the source cache when enabled.
+    async fn fetch_image(&self, url: &str, referer: Option<&str>) -> Result<Bytes, AppError> {
+        Ok(self.fetch_source_image(url, referer).await?.bytes)
+    }
+
+    /// Like [`AppState::fetch_image`], but also returns the origin's cache
+    /// validators.
+    async fn fetch_source_image(
+        &self,
+        url: &str,
+        referer: Option<&str>,
+    ) -> Result<FetchedImage, AppError> {
+        ops::check_source_host(url, &self.config.allowed_source_hosts)?;
+        let Some(cache) = &self.source_cache else {
+            let _permit = self.fetch_permit().await;
+            return ops::fetch_image_bytes_from_url(&self.http_client, url, referer).await;
         };
 
-        match name.as_str() {
-            "image" => {
-                if image_bytes.is_none() {
-                    image_bytes = Some(field.bytes().await?);
-                }
+        let cached = cache.get(url);
+        if let Some(cached) = &cached
+            && cached.is_fresh(cache.max_age())
+        {
+            return Ok(FetchedImage {
+                bytes: cached.bytes.clone(),
+                validators: cached.validators.clone(),
+            });
+        }
+
+        let validators = cached.as_ref().map(|cached| &cached.validators);
+        let _permit = self.fetch_permit().await;
+        match ops::fetch_image_conditional(&self.http_client, url, referer, validators).await? {
+            ConditionalFetch::Modified(fetched) => {
+                cache.insert(url, &fetched);
+                Ok(fetched)
             }
-            "w" => form_params.w = field.text().await?.parse().ok(),
-            "h" => form_params.h = field.text().await?.parse().ok(),
-            "crop_x" => form_params.crop_x = field.text().await?.parse().ok(),
-            "crop_y" => form_params.crop_y = field.text().await?.parse().ok(),
-            "crop_w" => form_params.crop_w = field.text().await?.parse().ok(),
-            "crop_h" => form_params.crop_h = field.text().await?.parse().ok(),
-            "filter" => form_params.filter = Some(field.text().await?),
-            "output_format" => form_params.output_format = Some(field.text().await?),
-            "quality" => form_params.quality = field.text().await?.parse().ok(),
-            _ => {
-                // ignore
+            ConditionalFetch::NotModified => {
+                debug!("origin reports {} unchanged, reusing cached copy", url);
+                cache.refresh(url);
+                // Only sent in response to validators, so an entry exists
+                Ok(cached
+                    .map(|cached| FetchedImage {
+                        bytes: cached.bytes,
+                        validators: cached.validators,
+                    })
+                    .unwrap_or_default())
             }
         }
     }
 
-    let image_bytes = image_bytes.ok_or_else(|| AppError::MissingImageFile)?;
-    let mut img = image::load_from_memory(&image_bytes)?;
+    /// Fetches the background image and LUT referenced by the parameters.
+    async fn fetch_assets(
+        &self,
+        params: &TransformParams,
+        referer: Option<&str>,
+        timings: &mut StageTimings,
+    ) -> Result<RequestAssets, AppError> {
+        let mut assets = RequestAssets::default();
+        if params.background_url.is_none() && params.lut_url.is_none() {
+            return Ok(assets);
+        }
+        let started = Instant::now();
+        if let Some(url) = &params.background_url {
+            assets.background = Some(self.fetch_image(url, referer).await?);
+        }
+        if let Some(url) = &params.lut_url {
+            assets.lut = Some(self.fetch_lut(url, referer).await?);
+        }
+        timings.record("fetch", started);
+        Ok(assets)
+    }
 
-    debug!("Form params from upload: {:?}", form_params);
+    /// Fetches and parses a LUT, going through the LUT cache when enabled.
+    async fn fetch_lut(&self, url: &str, referer: Option<&str>) -> Result<Arc<Lut>, AppError> {
+        // Checked before the cache, which may hold a LUT from a host that is
+        // no longer allowed
+        ops::check_source_host(url, &self.config.allowed_source_hosts)?;
+        if let Some(lut) = self.lut_cache.as_ref().and_then(|cache| cache.get(url)) {
+            return Ok(lut);
+        }
+        let bytes = self.fetch_image(url, referer).await?;
+        let text = std::str::from_utf8(&bytes)
+            .map_err(|_| AppError::InvalidLut("file is not text".to_string()))?;
+        let lut = Arc::new(Lut::parse_cube(text)?);
+        if let Some(cache) = &self.lut_cache {
+            cache.insert(url, Arc::clone(&lut));
+        }
+        Ok(lut)
+    }
 
-    img = apply_transformations(
-        img,
-        form_params.w,
-        form_params.h,
-        form_params.crop_x,
-        form_params.crop_y,
-        form_params.crop_w,
-        form_params.crop_h,
-        form_params.filter,
-    )?;
+    async fn read_local_image(&self, path: &str) -> Result<Bytes, AppError> {
+        self.check_local_files_allowed()?;
+        ops::read_local_image(&self.config.local_files_dir, path).await
+    }
 
-    let output_format_str = form_params
-        .output_format
-        .unwrap_or_else(|| infer_format_from_filename_or_default(image_filename.as_deref(), "png"));
+    fn check_local_files_allowed(&self) -> Result<(), AppError> {
+        if !self.config.allow_local_files {
+            return Err(AppError::LocalFileAccessDenied(
+                "local file access is disabled",
+            ));
+        }
+        Ok(())
+    }
 
-    let processed_image = ops::encode_image_to_bytes(img, &output_format_str, form_params.quality)?;
+    /// Applies the configured access rules that fetching would otherwise
+    /// enforce, so that cached outputs are not served to requests the current
+    /// configuration rejects.
+    fn check_request_allowed(
+        &self,
+        source: Option<&ImageSource<'_>>,
+        params: &TransformParams,
+    ) -> Result<(), AppError> {
+        match source {
+            Some(ImageSource::Url(url)) => {
+                ops::check_source_host(url, &self.config.allowed_source_hosts)?
+            }
+            Some(ImageSource::LocalFile(_)) => self.check_local_files_allowed()?,
+            None => {}
+        }
+        params.check_allowed(&self.config)?;
+        for url in [&params.background_url, &params.lut_url]
+            .into_iter()
+            .flatten()
+        {
+            ops::check_source_host(url, &self.config.allowed_source_hosts)?;
+        }
+        Ok(())
+    }
 
-    send_image_response(processed_image)
-}
+    /// Cache key for a processing request, or `None` when caching is disabled.
+    /// Settings that decide which sources are accepted are part of the key,
+    /// so tightening them does not leave earlier outputs servable.
+    fn cache_key(&self, source: &[&[u8]], params: &TransformParams) -> Option<String> {
+        self.disk_cache.as_ref()?;
+        let params = serde_json::to_vec(params).expect("transform params serialize to JSON");
+        let config = &self.config;
+        let policy = format!(
+            "{};{};{};{:?}",
+            config.max_source_dimension,
+            config.max_source_pixels,
+            config.local_files_dir.display(),
+            config.allowed_input_formats
+        );
+        let mut parts = source.to_vec();
+        parts.push(&params);
+        parts.push(policy.as_bytes());
+        Some(DiskCache::key(&parts))
+    }
 
-fn send_image_response(processed_image: ProcessedImage) -> Result<impl IntoResponse, AppError> {
-    let mut headers = HeaderMap::new();
-    match HeaderValue::from_str(&processed_image.mime_type) {
-        Ok(val) => {
-            headers.insert("Content-Type", val);
+    fn with_server_timing(&self, mut response: Response, timings: &StageTimings) -> Response {
+        if self.config.server_timing
+            && let Ok(value) = HeaderValue::from_str(&timings.server_timing())
+        {
+            response.headers_mut().insert("Server-Timing", value);
         }
-        Err(_) => {
-            return Err(AppError::ImageProcessingError(
-                image::ImageError::Unsupported(
-                    image::error::UnsupportedError::from_format_and_kind(
-                        image::error::ImageFormatHint::Unknown,
-                        image::error::UnsupportedErrorKind::GenericFeature(
-                            "invalid MIME type generated".to_string(),
-                        ),
-                    ),
-                ),
-            ));
+        response
+    }
+
+    async fn cache_get(&self, key: Option<&str>) -> Option<CachedImage> {
+        let (cache, key) = (self.disk_cache.as_ref()?, key?);
+        let cached = cache.get(key).await;
+        if cached.is_some() {
+            debug!("disk cache hit for {}", key);
         }
+        cached
     }
-    Ok((StatusCode::OK, headers, processed_image.bytes))
-}
 
-fn apply_transformations(
-    mut img: DynamicImage,
-    w: Option<u32>,
-    h: Option<u32>,
-    crop_x: Option<u32>,
-    crop_y: Option<u32>,
-    crop_w: Option<u32>,
-    crop_h: Option<u32>,
-    filter_str: Option<String>,
-) -> Result<DynamicImage, AppError> {
-    // Crop if all crop parameters are present
-    if let (Some(cx), Some(cy), Some(cw), Some(ch)) = (crop_x, crop_y, crop_w, crop_h) {
-        if cw > 0 && ch > 0 {
-            img = ops::crop_image(img, cx, cy, cw, ch)?;
-        } else {
-            return Err(AppError::InvalidCropDimensions(
-                "crop width and height must be greater than 0.",
-            ));
+    async fn cache_put(
+        &self,
+        key: &str,
+        image: &ProcessedImage,
+        operations: &[String],
+        last_modified: Option<&str>,
+    ) {
+        if let Some(cache) = &self.disk_cache
+            && let Err(err) = cache.put(key, image, operations, last_modified).await
+        {
+            warn!("failed to write disk cache entry {}: {}", key, err);
         }
     }
 
-    // Resize if width or height is present
-    let (current_w, current_h) = img.dimensions();
-    let target_w = w.unwrap_or(current_w);
-    let target_h = h.unwrap_or(current_h);
+    /// Processes an image and builds the response, streaming the encoded
+    /// output when enabled and it does not need to be cached. `cache` holds
+    /// the cache key and the source's `Last-Modified` to store alongside.
+    async fn process_and_respond(
+        &self,
+        image_bytes: &[u8],
+        assets: &RequestAssets,
+        params: &TransformParams,
+        encode_options: EncodeOptions,
+        cache: Option<(&str, Option<&str>)>,
+        mut timings: StageTimings,
+    ) -> Result<Response, AppError> {
+        // Stored and optimized outputs, and those tagged with an EXIF
+        // orientation, are handled whole, so they are never streamed
+        if self.config.stream_responses
+            && cache.is_none()
+            && params.store.is_none()
+            && !encode_options.optimize
+            && params.split_exif_rotation(&encode_options)?.is_none()
+        {
+            let response = stream_image(
+                &self.config,
+                image_bytes,
+                assets,
+                params,
+                encode_options,
+                &mut timings,
+            )?;
+            return Ok(self.with_server_timing(response, &timings));
+        }
 
-    if w.is_some() || h.is_some() {
-        if target_w > 0 && target_h > 0 {
-            // If one dimension is not specified for resize, maintain aspect ratio
-            let (final_w, final_h) = if w.is_none() && h.is_some() {
-                // height specified, width auto
-                let aspect_ratio = current_w as f32 / current_h as f32;
-                ((target_h as f32 * aspect_ratio) as u32, target_h)
-            } else if w.is_some() && h.is_none() {
-                // width specified, height auto
-                let aspect_ratio = current_h as f32 / current_w as f32;
-                (target_w, (target_w as f32 * aspect_ratio) as u32)
-            } else {
-                // both specified or neither (no resize if neither)
-                (target_w, target_h)
-            };
+        let (processed_image, operations) = process_image(
+            &self.config,
+            image_bytes,
+            assets,
+            params,
+            &encode_options,
+            &mut timings,
+        )?;
+        if let Some((key, last_modified)) = cache {
+            self.cache_put(key, &processed_image, &operations, last_modified)
+                .await;
+        }
 
-            if final_w > 0 && final_h > 0 {
-                img = ops::resize_image(img, final_w, final_h, FilterType::Triangle);
-            } else if w.is_some() || h.is_some() {
-                // only error if a resize was intended
-                return Err(AppError::InvalidResizeDimensions(
-                    "resize width and height must result in dimensions greater than 0",
-                ));
+        let response = self
+            .deliver(processed_image, &operations, params, &mut timings)
+            .await?;
+        Ok(self.with_server_timing(response, &timings))
+    }
+
+    /// Fails early when the request asks for a storage that is not set up.
+    fn check_storage(&self, params: &TransformParams) -> Result<(), AppError> {
+        match params.store {
+            Some(StorageTarget::S3) if self.storage.is_none() => {
+                Err(AppError::StorageNotConfigured)
             }
-        } else if w.is_some() || h.is_some() {
-            // only error if a resize was intended
-            return Err(AppError::InvalidResizeDimensions(
-                "target resize width and height must be greater than 0",
-            ));
+            _ => Ok(()),
         }
     }
 
-    // Apply filter if present
-    if let Some(f_str) = filter_str {
-        if !f_str.trim().is_empty() {
-            img = apply_filter_str(img, &f_str)?;
+    /// Sends the processed image, or uploads it and responds with its location
+    /// when the request asks for it to be stored.
+    async fn deliver(
+        &self,
+        processed_image: ProcessedImage,
+        operations: &[String],
+        params: &TransformParams,
+        timings: &mut StageTimings,
+    ) -> Result<Response, AppError> {
+        let Some(StorageTarget::S3) = params.store else {
+            return send_image_response(processed_image, operations, params.download());
+        };
+
+        let mut response = Json(self.store_image(&processed_image, timings).await?).into_response();
+        if let Ok(value) = HeaderValue::from_str(&operations.join(";")) {
+            response.headers_mut().insert("X-Image-Operations", value);
         }
+        Ok(response)
     }
 
-    Ok(img)
-}
+    /// Uploads the processed image to storage and describes where it went.
+    async fn store_image(
+        &self,
+        processed_image: &ProcessedImage,
+        timings: &mut StageTimings,
+    ) -> Result<serde_json::Value, AppError> {
+        let storage = self
+            .storage
+            .as_ref()
+            .ok_or(AppError::StorageNotConfigured)?;
 
-fn infer_format_from_url_or_default(url: &str, default: &str) -> String {
-    Path::new(url)
-        .extension()
-        .and_then(|os_str| os_str.to_str())
-        .map(|s| s.to_lowercase())
-        .unwrap_or_else(|| default.to_string())
-}
+        let started = Instant::now();
+        let stored = storage.put_image(processed_image).await?;
+        timings.record("store", started);
 
-fn infer_format_from_filename_or_default(filename: Option<&str>, default: &str) -> String {
-    filename
-        .and_then(|f_name| {
-            Path::new(f_name)
-                .extension()
-                .and_then(|os_str| os_str.to_str())
-                .map(|s| s.to_lowercase())
-        })
-        .unwrap_or_else(|| default.to_string())
+        Ok(json!({
+            "url": stored.url,
+            "key": stored.key,
+            "mime_type": processed_image.mime_type,
+            "width": processed_image.width,
+            "height": processed_image.height,
+            "bytes": processed_image.bytes.len(),
+        }))
+    }
+
+    /// Uploads each encoded variant, then a manifest listing them all, so
+    /// clients have one document describing the set.
+    async fn store_variants(
+        &self,
+        variants: &[(OutputFormat, ProcessedImage)],
+        timings: &mut StageTimings,
+    ) -> Result<serde_json::Value, AppError> {
+        let storage = self
+            .storage
+            .as_ref()
+            .ok_or(AppError::StorageNotConfigured)?;
+
+        let mut stored = Vec::with_capacity(variants.len());
+        for (format, processed_image) in variants {
+            let mut variant = self.store_image(processed_image, timings).await?;
+            variant["format"] = json!(format.name());
+            stored.push(variant);
+        }
+        let manifest = json!({ "variants": stored });
+
+        let started = Instant::now();
+        let body = serde_json::to_vec(&manifest).expect("manifest serializes to JSON");
+        let location = storage.put_object(body, "application/json", "json").await?;
+        timings.record("store", started);
+
+        Ok(json!({
+            "manifest": { "url": location.url, "key": location.key },
+            "variants": manifest["variants"],
+        }))
+    }
+
+    /// Processes a background job and posts the outcome to its callback URL.
+    async fn run_job(&self, job: Job<ImageJob>) {
+        let Job { id, payload } = job;
+        let callback = || {
+            self.callback_client
+                .post(payload.callback_url.clone())
+                .header("X-Job-Id", &id)
+        };
+
+        let request = match self.process_job(callback(), &payload).await {
+            Ok(request) => request.header("X-Job-Status", "done"),
+            Err(err) => {
+                warn!("job {} failed: {}", id, err);
+                callback()
+                    .header("X-Job-Status", "failed")
+                    .json(&json!({ "job_id": id, "error": err.to_string() }))
+            }
+        };
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("delivered job {} to {}", id, payload.callback_url);
+            }
+            Ok(response) => warn!(
+                "callback for job {} responded with {}",
+                id,
+                response.status()
+            ),
+            Err(err) => warn!("failed to deliver job {}: {}", id, err),
+        }
+    }
+
+    /// Processes a job's upload and attaches the result to the callback
+    /// request: the image itself, or its location when it was stored.
+    async fn process_job(
+        &self,
+        request: reqwest::RequestBuilder,
+        job: &ImageJob,
+    ) -> Result<reqwest::RequestBuilder, AppError> {
+        let mut timings = StageTimings::default();
+        let assets = self
+            .fetch_assets(&job.params, job.referer.as_deref(), &mut timings)
+            .await?;
+        let (processed_image, operations) = process_image(
+            &self.config,
+            &job.image.bytes,
+            &assets,
+            &job.params,
+            &job.encode_options,
+            &mut timings,
+        )?;
+
+        if let Some(StorageTarget::S3) = job.params.store {
+            let stored = self.store_image(&processed_image, &mut timings).await?;
+            return Ok(request.json(&stored));
+        }
+        let headers = image_headers(
+            &processed_image.mime_type,
+            processed_image.quality,
+            processed_image.quality_clamped,
+            (processed_image.width, processed_image.height),
+            &operations,
+        )?;
+        Ok(request.headers(headers).body(processed_image.bytes))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "rust_image_service=debug,tower_http=debug".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let app = router(build_state(Config::from_env()).await);
+
+    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    debug!("listening on {}", listener.local_addr().unwrap());
+    // Connect info carries the client address that rate limiting is keyed by
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Sets up the clients and caches described by `config`.
+async fn build_state(config: Config) -> AppState {
+    let http_client =
+        ops::build_http_client(&config.user_agent, &config.allowed_source_hosts).unwrap();
+    let callback_client =
+        jobs::build_callback_client(&config.user_agent, &config.allowed_callback_hosts).unwrap();
+    let disk_cache = match &config.disk_cache_dir {
+        Some(dir) => Some(Arc::new(
+            DiskCache::new(
+                dir,
+                config.disk_cache_max_bytes,
+                Duration::from_secs(config.disk_cache_ttl_secs),
+            )
+            .await
+            .unwrap(),
+        )),
+        None => None,
+    };
+    let source_cache = (config.source_cache_entries > 0).then(|| {
+        Arc::new(SourceCache::new(
+            config.source_cache_entries,
+            Duration::from_secs(config.source_cache_max_age_secs),
+        ))
+    });
+    let lut_cache =
+        (config.lut_cache_entries > 0).then(|| Arc::new(LutCache::new(config.lut_cache_entries)));
+    let fetch_limit = (config.max_concurrent_fetches > 0)
+        .then(|| Arc::new(Semaphore::new(config.max_concurrent_fetches)));
+    let storage = S3Storage::from_config(&config, http_client.clone()).map(Arc::new);
+    let mut state = AppState {
+        config: Arc::new(config),
+        http_client,
+        callback_client,
+        disk_cache,
+        source_cache,
+        lut_cache,
+        fetch_limit,
+        storage,
+        jobs: None,
+    };
+    let worker_state = state.clone();
+    state.jobs = Some(Arc::new(JobQueue::start(
+        state.config.job_workers,
+        state.config.job_queue_size,
+        move |job| {
+            let state = worker_state.clone();
+            async move { state.run_job(job).await }
+        },
+    )));
+    state
+}
+
+/// Routes and middleware of the service.
+fn router(state: AppState) -> Router {
+    // Uploads carry whole images, while JSON bodies only hold parameters
+    let upload_routes = Router::new()
+        .route("/upload", post(process_image_from_upload))
+        .route("/jobs", post(submit_job))
+        .layer(DefaultBodyLimit::max(state.config.max_upload_bytes));
+    let url_routes = Router::new()
+        .route(
+            "/url",
+            get(process_image_from_url).post(process_image_from_url_json),
+        )
+        .route("/pixels", get(process_image_to_pixels))
+        .route("/tiles", get(process_image_to_tiles))
+        .route("/og-card", post(generate_og_card))
+        .layer(DefaultBodyLimit::max(state.config.max_json_body_bytes));
+    let processing_routes = upload_routes.merge(url_routes);
+    let app = Router::new()
+        .merge(rate_limit::rate_limited(processing_routes, &state.config))
+        .route("/sniff", get(sniff_image_format))
+        .route("/favicon-set", get(generate_favicon_set))
+        .route("/phash", get(compute_perceptual_hash))
+        .route("/normalize-filter", get(normalize_filter))
+        .route("/exif", get(read_exif))
+        .route("/histogram", get(compute_histogram))
+        .route("/has-alpha", get(detect_alpha))
+        .route("/srcset", get(generate_srcset));
+    // Lets tests check that a panicking handler gets a clean response, and
+    // that a slow one is cut off
+    #[cfg(test)]
+    let app = app
+        .route("/panic", get(tests::deliberate_panic))
+        .route("/slow", get(tests::deliberately_slow));
+    // Bounds slow clients and origins as well as processing, so a request
+    // cannot hold a connection open indefinitely
+    let app = if state.config.request_timeout_secs > 0 {
+        app.layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            Duration::from_secs(state.config.request_timeout_secs),
+        ))
+    } else {
+        app
+    };
+    let mut app = app
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::map_response(set_security_headers));
+    if let Some(sampler) = request_log::request_sampler(&state.config) {
+        app = app.layer(middleware::from_fn_with_state(
+            sampler,
+            request_log::sample_request,
+        ));
+    }
+    if let Some(layer) = request_log::request_log_layer(&state.config) {
+        app = app.layer(layer);
+    }
+    app.with_state(state)
+}
+
+/// Answers a request whose handler panicked with a generic 500, so a bug in
+/// one operation doesn't reset the client's connection.
+fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let message = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic payload");
+    error!("request handler panicked: {}", message);
+    AppError::Panicked.into_response()
+}
+
+/// Keeps browsers from interpreting responses as anything other than their
+/// declared type, since their content is influenced by the client.
+async fn set_security_headers(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    response
+}
+
+/// Passes the source's `Last-Modified` on, letting downstream caches
+/// revalidate against the origin's copy. Stored outputs respond with their
+/// location instead of the image, so they go without.
+fn with_last_modified(
+    mut response: Response,
+    params: &TransformParams,
+    last_modified: Option<&str>,
+) -> Response {
+    if params.store.is_none()
+        && let Some(value) = last_modified.and_then(|value| HeaderValue::from_str(value).ok())
+    {
+        response.headers_mut().insert(header::LAST_MODIFIED, value);
+    }
+    response
+}
+
+async fn process_image_from_url(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ImageUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::debug!("Processing image from URL: {:?}", params);
+
+    process_remote_image(&state, &headers, params).await
+}
+
+async fn process_image_from_url_json(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(params): Json<ImageUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    tracing::debug!("Processing image from URL (JSON body): {:?}", params);
+
+    process_remote_image(&state, &headers, params).await
+}
+
+async fn process_remote_image(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: ImageUrlParams,
+) -> Result<Response, AppError> {
+    let source = params.source()?;
+    state.check_storage(&params.transform)?;
+    state.check_request_allowed(Some(&source), &params.transform)?;
+    let (source_kind, source_location): (&[u8], &str) = match source {
+        ImageSource::Url(url) => (b"url", url),
+        ImageSource::LocalFile(file) => (b"file", file),
+    };
+
+    let cache_key = state.cache_key(
+        &[source_kind, source_location.as_bytes()],
+        &params.transform,
+    );
+    if let Some(cached) = state.cache_get(cache_key.as_deref()).await {
+        let mut timings = StageTimings::default();
+        let response = state
+            .deliver(
+                cached.image,
+                &cached.operations,
+                &params.transform,
+                &mut timings,
+            )
+            .await?;
+        return Ok(with_last_modified(
+            response,
+            &params.transform,
+            cached.last_modified.as_deref(),
+        ));
+    }
+
+    let mut timings = StageTimings::default();
+    let referer = state.outbound_referer(headers);
+
+    let started = Instant::now();
+    let mut fallback_location = None;
+    let (image_bytes, last_modified) = match source {
+        ImageSource::Url(url) => {
+            let fetched = match state.fetch_source_image(url, referer).await {
+                Err(err @ (AppError::ImageFetchError(_) | AppError::ReqwestError(_))) => {
+                    let Some(fallback_url) = params.fallback_url(&state.config) else {
+                        return Err(err);
+                    };
+                    warn!("serving fallback {} for {}: {}", fallback_url, url, err);
+                    fallback_location = Some(fallback_url);
+                    state.fetch_source_image(fallback_url, referer).await?
+                }
+                result => result?,
+            };
+            (fetched.bytes, fetched.validators.last_modified)
+        }
+        ImageSource::LocalFile(file) => (state.read_local_image(file).await?, None),
+    };
+    timings.record("fetch", started);
+    // A fallback stands in for the source only until it can be fetched again,
+    // so it is neither cached nor given the fallback's validators
+    let (cache_key, last_modified) = if fallback_location.is_some() {
+        (None, None)
+    } else {
+        (cache_key, last_modified)
+    };
+    let assets = state
+        .fetch_assets(&params.transform, referer, &mut timings)
+        .await?;
+
+    let encode_options = params.transform.encode_options(
+        &state.config,
+        default_output_format(
+            state.config.url_default_format,
+            &image_bytes,
+            Some(fallback_location.unwrap_or(source_location)),
+        ),
+    )?;
+
+    let mut response = state
+        .process_and_respond(
+            &image_bytes,
+            &assets,
+            &params.transform,
+            encode_options,
+            cache_key
+                .as_deref()
+                .map(|key| (key, last_modified.as_deref())),
+            timings,
+        )
+        .await?;
+    response = with_last_modified(response, &params.transform, last_modified.as_deref());
+    if fallback_location.is_some() {
+        response
+            .headers_mut()
+            .insert("X-Image-Fallback", HeaderValue::from_static("true"));
+    }
+    Ok(response)
+}
+
+/// Decodes and transforms an image like `/url`, but responds with the raw
+/// 8-bit RGB or RGBA pixels, row by row, for clients that want the array
+/// rather than an encoded file. `output_format=npy` wraps them in a NumPy
+/// array file.
+async fn process_image_to_pixels(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ImageUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Reading raw pixels from URL: {:?}", params);
+    // Pixels are sent as they are, so there is nothing for these to apply to
+    if let Some(parameter) = params
+        .transform
+        .encoding_param()
+        .or(params.transform.delivery_param())
+    {
+        return Err(AppError::ParameterNotSupported {
+            parameter,
+            route: "/pixels",
+        });
+    }
+
+    let mut timings = StageTimings::default();
+    let referer = state.outbound_referer(&headers);
+    let started = Instant::now();
+    let image_bytes = match params.source()? {
+        ImageSource::Url(url) => state.fetch_image(url, referer).await?,
+        ImageSource::LocalFile(file) => state.read_local_image(file).await?,
+    };
+    timings.record("fetch", started);
+    let assets = state
+        .fetch_assets(&params.transform, referer, &mut timings)
+        .await?;
+    let (img, operations) = decode_and_transform(
+        &state.config,
+        &image_bytes,
+        &assets,
+        &params.transform,
+        &mut timings,
+    )?;
+
+    let (width, height) = img.dimensions();
+    let channels = if img.color().has_alpha() { 4 } else { 3 };
+    let body = match params.transform.output_format.as_deref() {
+        None => ops::raw_pixels(img).0,
+        Some(format) => match format.trim().to_lowercase().as_str() {
+            "raw" => ops::raw_pixels(img).0,
+            "npy" => ops::encode_npy(img),
+            _ => return Err(AppError::UnsupportedOutputFormat(format.to_string())),
+        },
+    };
+    let mut response = body.into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response_headers.insert("X-Image-Width", HeaderValue::from(width));
+    response_headers.insert("X-Image-Height", HeaderValue::from(height));
+    response_headers.insert("X-Image-Channels", HeaderValue::from(channels));
+    response_headers.insert("X-Image-Bit-Depth", HeaderValue::from_static("8"));
+    if let Ok(value) = HeaderValue::from_str(&operations.join(";")) {
+        response_headers.insert("X-Image-Operations", value);
+    }
+    Ok(response)
+}
+
+/// Decodes and transforms an image like `/url`, then slices it into a grid
+/// of tiles for deep-zoom viewers. Responds with a zip of the tiles, named
+/// `{column}_{row}.{ext}`, and a `manifest.json` describing the grid.
+async fn process_image_to_tiles(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<TileParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Tiling image from URL: {:?}", params);
+
+    let tile_size = match params.tile_size {
+        Some(0) => {
+            return Err(AppError::InvalidTiles(
+                "tile_size must be greater than 0".to_string(),
+            ));
+        }
+        Some(tile_size) => tile_size,
+        None => return Err(AppError::InvalidTiles("tile_size is required".to_string())),
+    };
+    let transform = &params.image.transform;
+    // Tiles are always returned in a zip
+    if let Some(parameter) = transform.delivery_param() {
+        return Err(AppError::ParameterNotSupported {
+            parameter,
+            route: "/tiles",
+        });
+    }
+    let mut timings = StageTimings::default();
+    let referer = state.outbound_referer(&headers);
+    let started = Instant::now();
+    let (image_bytes, source_location) = match params.image.source()? {
+        ImageSource::Url(url) => (state.fetch_image(url, referer).await?, url),
+        ImageSource::LocalFile(file) => (state.read_local_image(file).await?, file),
+    };
+    timings.record("fetch", started);
+    let assets = state.fetch_assets(transform, referer, &mut timings).await?;
+    let encode_options = transform.encode_options(
+        &state.config,
+        default_output_format(
+            state.config.url_default_format,
+            &image_bytes,
+            Some(source_location),
+        ),
+    )?;
+    let (img, mut operations) = decode_and_transform(
+        &state.config,
+        &image_bytes,
+        &assets,
+        transform,
+        &mut timings,
+    )?;
+
+    let (width, height) = img.dimensions();
+    let (columns, rows) = (width.div_ceil(tile_size), height.div_ceil(tile_size));
+    let tile_count = u64::from(columns) * u64::from(rows);
+    if tile_count > MAX_TILES {
+        return Err(AppError::InvalidTiles(format!(
+            "{}x{} image would be cut into {} tiles, over the limit of {}",
+            width, height, tile_count, MAX_TILES
+        )));
+    }
+    // Every tile shares the format settled for the whole image
+    let encode_options = encode_options.resolve_format(&img);
+    let img = flatten_for_format(img, transform, encode_options.format, &mut operations)?;
+
+    let started = Instant::now();
+    let extension = encode_options.format.name();
+    let mut files = Vec::with_capacity(tile_count as usize + 1);
+    let mut manifest_tiles = Vec::with_capacity(tile_count as usize);
+    for tile in ops::tile_image(&img, tile_size)? {
+        let name = format!("{}_{}.{}", tile.column, tile.row, extension);
+        manifest_tiles.push(json!({
+            "file": name,
+            "column": tile.column,
+            "row": tile.row,
+            "x": tile.x,
+            "y": tile.y,
+            "width": tile.image.width(),
+            "height": tile.image.height(),
+        }));
+        let encoded = ops::encode_image_to_bytes(tile.image, &encode_options)?;
+        files.push((name, encoded.bytes));
+    }
+    timings.record("encode", started);
+    let manifest = json!({
+        "width": width,
+        "height": height,
+        "tile_size": tile_size,
+        "columns": columns,
+        "rows": rows,
+        "format": extension,
+        "tiles": manifest_tiles,
+    });
+    files.push((
+        "manifest.json".to_string(),
+        manifest.to_string().into_bytes(),
+    ));
+
+    let mut response = zip_response(&files, "tiles.zip")?;
+    if let Ok(value) = HeaderValue::from_str(&operations.join(";")) {
+        response.headers_mut().insert("X-Image-Operations", value);
+    }
+    Ok(state.with_server_timing(response, &timings))
+}
+
+async fn process_image_from_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Processing image from upload");
+
+    let UploadForm {
+        images,
+        params: form_params,
+        ..
+    } = read_upload_form(&state, multipart).await?;
+
+    debug!("Form params from upload: {:?}", form_params);
+    state.check_storage(&form_params)?;
+
+    let mut timings = StageTimings::default();
+
+    // Several images are processed alike and returned together
+    if images.len() > 1 {
+        return process_uploaded_images(&state, &headers, &images, &form_params, timings).await;
+    }
+    let Some(UploadedImage {
+        bytes: image_bytes,
+        filename: image_filename,
+    }) = images.into_iter().next()
+    else {
+        return Err(AppError::MissingImageFile);
+    };
+
+    // A comma-separated output format returns every encoding in one zip
+    if let Some(formats) = form_params.output_format.as_deref()
+        && formats.contains(',')
+    {
+        let formats = parse_output_format_list(formats)?;
+        let assets = state
+            .fetch_assets(&form_params, state.outbound_referer(&headers), &mut timings)
+            .await?;
+        let (img, operations) = decode_and_transform(
+            &state.config,
+            &image_bytes,
+            &assets,
+            &form_params,
+            &mut timings,
+        )?;
+
+        let started = Instant::now();
+        let variants = encode_variants(&state.config, img, &form_params, &formats)?;
+        timings.record("encode", started);
+
+        let mut response = if let Some(StorageTarget::S3) = form_params.store {
+            Json(state.store_variants(&variants, &mut timings).await?).into_response()
+        } else {
+            let files: Vec<_> = variants
+                .into_iter()
+                .map(|(format, image)| (format!("image.{}", format.name()), image.bytes))
+                .collect();
+            zip_response(&files, "images.zip")?
+        };
+        if let Ok(value) = HeaderValue::from_str(&operations.join(";")) {
+            response.headers_mut().insert("X-Image-Operations", value);
+        }
+        return Ok(state.with_server_timing(response, &timings));
+    }
+
+    state.check_request_allowed(None, &form_params)?;
+    let cache_key = state.cache_key(
+        &[
+            b"upload",
+            &image_bytes,
+            image_filename.as_deref().unwrap_or_default().as_bytes(),
+        ],
+        &form_params,
+    );
+    if let Some(cached) = state.cache_get(cache_key.as_deref()).await {
+        return state
+            .deliver(cached.image, &cached.operations, &form_params, &mut timings)
+            .await;
+    }
+
+    let encode_options = form_params.encode_options(
+        &state.config,
+        default_output_format(
+            state.config.upload_default_format,
+            &image_bytes,
+            image_filename.as_deref(),
+        ),
+    )?;
+
+    let assets = state
+        .fetch_assets(&form_params, state.outbound_referer(&headers), &mut timings)
+        .await?;
+
+    state
+        .process_and_respond(
+            &image_bytes,
+            &assets,
+            &form_params,
+            encode_options,
+            cache_key.as_deref().map(|key| (key, None)),
+            timings,
+        )
+        .await
+}
+
+/// Processes every image of a multi-image upload with the same parameters.
+/// The outputs are returned in a zip named after the uploaded files, or with
+/// `store=s3`, stored and listed in upload order.
+async fn process_uploaded_images(
+    state: &AppState,
+    headers: &HeaderMap,
+    images: &[UploadedImage],
+    params: &TransformParams,
+    mut timings: StageTimings,
+) -> Result<Response, AppError> {
+    let formats = match params.output_format.as_deref() {
+        Some(formats) if formats.contains(',') => Some(parse_output_format_list(formats)?),
+        _ => None,
+    };
+    let assets = state
+        .fetch_assets(params, state.outbound_referer(headers), &mut timings)
+        .await?;
+
+    let mut outputs = Vec::with_capacity(images.len());
+    for image in images {
+        let variants = match &formats {
+            Some(formats) => {
+                let (img, _) = decode_and_transform(
+                    &state.config,
+                    &image.bytes,
+                    &assets,
+                    params,
+                    &mut timings,
+                )?;
+                let started = Instant::now();
+                let variants = encode_variants(&state.config, img, params, formats)?;
+                timings.record("encode", started);
+                variants
+            }
+            None => {
+                let encode_options = params.encode_options(
+                    &state.config,
+                    default_output_format(
+                        state.config.upload_default_format,
+                        &image.bytes,
+                        image.filename.as_deref(),
+                    ),
+                )?;
+                let (processed_image, _) = process_image(
+                    &state.config,
+                    &image.bytes,
+                    &assets,
+                    params,
+                    &encode_options,
+                    &mut timings,
+                )?;
+                // jpeg_or_png settles the format only once the image is decoded
+                let format = image::ImageFormat::from_mime_type(&processed_image.mime_type)
+                    .and_then(OutputFormat::from_image_format)
+                    .unwrap_or(encode_options.format);
+                vec![(format, processed_image)]
+            }
+        };
+        outputs.push((image.filename.as_deref(), variants));
+    }
+
+    let response = if let Some(StorageTarget::S3) = params.store {
+        let mut stored = Vec::with_capacity(outputs.len());
+        for (filename, variants) in &outputs {
+            let mut entry = match (&formats, variants.as_slice()) {
+                (None, [(_, processed_image)]) => {
+                    state.store_image(processed_image, &mut timings).await?
+                }
+                _ => state.store_variants(variants, &mut timings).await?,
+            };
+            entry["filename"] = json!(filename);
+            stored.push(entry);
+        }
+        Json(json!({ "images": stored })).into_response()
+    } else {
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+        for (index, (filename, variants)) in outputs.into_iter().enumerate() {
+            let stem = filename
+                .and_then(|filename| Path::new(filename).file_stem())
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| !stem.is_empty())
+                .map_or_else(|| format!("image-{}", index + 1), str::to_string);
+            // Files uploaded under the same name are told apart by position
+            let taken = files
+                .iter()
+                .any(|(name, _)| Path::new(name).file_stem() == Some(stem.as_ref()));
+            let stem = if taken {
+                format!("{}-{}", stem, index + 1)
+            } else {
+                stem
+            };
+            for (format, processed_image) in variants {
+                files.push((format!("{}.{}", stem, format.name()), processed_image.bytes));
+            }
+        }
+        zip_response(&files, "images.zip")?
+    };
+    Ok(state.with_server_timing(response, &timings))
+}
+
+/// Encodes a transformed image once for each of `formats`.
+fn encode_variants(
+    config: &Config,
+    img: DynamicImage,
+    params: &TransformParams,
+    formats: &[OutputFormat],
+) -> Result<Vec<(OutputFormat, ProcessedImage)>, AppError> {
+    let mut variants = Vec::with_capacity(formats.len());
+    for &format in formats {
+        let encode_options = params.encode_options_for(config, format)?;
+        let img = flatten_for_format(img.clone(), params, format, &mut Vec::new())?;
+        variants.push((format, ops::encode_image_to_bytes(img, &encode_options)?));
+    }
+    Ok(variants)
+}
+
+/// Reads the image and transform parameters from a multipart upload.
+async fn read_upload_form(
+    state: &AppState,
+    mut multipart: Multipart,
+) -> Result<UploadForm, AppError> {
+    let mut images = Vec::new();
+    let mut form_params = TransformParams::default();
+    let mut callback_url = None;
+    let mut field_count = 0;
+
+    while let Some(field) = multipart.next_field().await? {
+        field_count += 1;
+        if field_count > state.config.max_multipart_fields {
+            return Err(AppError::TooManyMultipartFields(
+                state.config.max_multipart_fields,
+            ));
+        }
+
+        let name = if let Some(name) = field.name() {
+            name.to_string()
+        } else {
+            continue;
+        };
+
+        match name.as_str() {
+            "image" => {
+                let filename = field.file_name().map(str::to_string);
+                let bytes = field.bytes().await?;
+                if bytes.is_empty() {
+                    return Err(AppError::EmptyImageFile);
+                }
+                images.push(UploadedImage { bytes, filename });
+            }
+            "page" => {
+                let value = field.text().await?;
+                let page = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| AppError::InvalidPage(value.clone()))?;
+                form_params.page = Some(page);
+            }
+            "w" => form_params.w = Some(parse_form_value("w", &field.text().await?)?),
+            "h" => form_params.h = Some(parse_form_value("h", &field.text().await?)?),
+            "crop_x" => {
+                form_params.crop_x = Some(parse_form_value("crop_x", &field.text().await?)?)
+            }
+            "crop_y" => {
+                form_params.crop_y = Some(parse_form_value("crop_y", &field.text().await?)?)
+            }
+            "crop_w" => {
+                form_params.crop_w = Some(parse_form_value("crop_w", &field.text().await?)?)
+            }
+            "crop_h" => {
+                form_params.crop_h = Some(parse_form_value("crop_h", &field.text().await?)?)
+            }
+            "crop_gravity" => form_params.crop_gravity = Some(field.text().await?.parse()?),
+            "crop_clamp" => {
+                form_params.crop_clamp = Some(parse_form_value("crop_clamp", &field.text().await?)?)
+            }
+            "crop_xf" => {
+                form_params.crop_xf = Some(parse_form_value("crop_xf", &field.text().await?)?)
+            }
+            "crop_yf" => {
+                form_params.crop_yf = Some(parse_form_value("crop_yf", &field.text().await?)?)
+            }
+            "crop_wf" => {
+                form_params.crop_wf = Some(parse_form_value("crop_wf", &field.text().await?)?)
+            }
+            "crop_hf" => {
+                form_params.crop_hf = Some(parse_form_value("crop_hf", &field.text().await?)?)
+            }
+            "unpremultiply" => {
+                form_params.unpremultiply =
+                    Some(parse_form_value("unpremultiply", &field.text().await?)?)
+            }
+            "premultiply" => {
+                form_params.premultiply =
+                    Some(parse_form_value("premultiply", &field.text().await?)?)
+            }
+            "debug_stamp" => {
+                form_params.debug_stamp =
+                    Some(parse_form_value("debug_stamp", &field.text().await?)?)
+            }
+            "blur_region" => form_params.blur_region = Some(field.text().await?),
+            "redact" => form_params.redact = Some(field.text().await?),
+            "redact_color" => form_params.redact_color = Some(field.text().await?),
+            "fit_width" => {
+                form_params.fit_width = Some(parse_form_value("fit_width", &field.text().await?)?)
+            }
+            "fit_height" => {
+                form_params.fit_height = Some(parse_form_value("fit_height", &field.text().await?)?)
+            }
+            "mode" => form_params.mode = Some(field.text().await?.parse()?),
+            "upscale_filter" => form_params.upscale_filter = Some(field.text().await?.parse()?),
+            "downscale_filter" => form_params.downscale_filter = Some(field.text().await?.parse()?),
+            "smart" => form_params.smart = Some(parse_form_value("smart", &field.text().await?)?),
+            "upscale" => form_params.upscale = Some(field.text().await?.parse()?),
+            "round_to" => {
+                form_params.round_to = Some(parse_form_value("round_to", &field.text().await?)?)
+            }
+            "round" => form_params.round = Some(field.text().await?.parse()?),
+            "post_sharpen" => {
+                form_params.post_sharpen =
+                    Some(parse_form_value("post_sharpen", &field.text().await?)?)
+            }
+            "filter" => form_params.filter = Some(field.text().await?),
+            "rotate_mode" => form_params.rotate_mode = Some(field.text().await?.parse()?),
+            "pipeline" => form_params.pipeline = Some(field.text().await?),
+            "lut_url" => form_params.lut_url = Some(field.text().await?),
+            "background_url" => form_params.background_url = Some(field.text().await?),
+            "background_gravity" => {
+                form_params.background_gravity = Some(field.text().await?.parse()?)
+            }
+            "flatten" => form_params.flatten = Some(field.text().await?),
+            "strict" => {
+                form_params.strict = Some(parse_form_value("strict", &field.text().await?)?)
+            }
+            "output_format" => form_params.output_format = Some(field.text().await?),
+            "quality" => form_params.quality = Some(field.text().await?.parse()?),
+            "png_compression" => form_params.png_compression = Some(field.text().await?),
+            "optimize" => {
+                form_params.optimize = Some(parse_form_value("optimize", &field.text().await?)?)
+            }
+            "jpeg_encoder" => form_params.jpeg_encoder = Some(field.text().await?.parse()?),
+            "effort" => form_params.effort = Some(field.text().await?),
+            "webp_alpha_quality" => {
+                form_params.webp_alpha_quality = Some(parse_form_value(
+                    "webp_alpha_quality",
+                    &field.text().await?,
+                )?)
+            }
+            "png_filter" => form_params.png_filter = Some(field.text().await?),
+            "store" => form_params.store = Some(field.text().await?.parse()?),
+            "dl" => form_params.dl = Some(field.text().await?),
+            "callback_url" => callback_url = Some(field.text().await?),
+            _ => {
+                // ignore
+            }
+        }
+    }
+
+    if images.is_empty() {
+        return Err(AppError::MissingImageFile);
+    }
+
+    Ok(UploadForm {
+        images,
+        params: form_params,
+        callback_url,
+    })
+}
+
+/// Parses a numeric or boolean form field, naming the field when the value
+/// is not valid rather than ignoring it.
+fn parse_form_value<T: FromStr>(name: &'static str, value: &str) -> Result<T, AppError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| AppError::InvalidFormField {
+            field: name,
+            value: value.to_string(),
+        })
+}
+
+/// Queues an upload for background processing and returns its job id right
+/// away; the result is posted to `callback_url` once it is ready.
+async fn submit_job(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Submitting background job");
+
+    let UploadForm {
+        images,
+        params,
+        callback_url,
+    } = read_upload_form(&state, multipart).await?;
+    let Ok([image]) = <[UploadedImage; 1]>::try_from(images) else {
+        return Err(AppError::DuplicateImageFile);
+    };
+    let callback_url = callback_url
+        .as_deref()
+        .ok_or(AppError::MissingCallbackUrl)
+        .and_then(|url| jobs::parse_callback_url(url, &state.config.allowed_callback_hosts))?;
+    state.check_storage(&params)?;
+    // Validated up front so bad parameters are reported to the client
+    let encode_options = params.encode_options(
+        &state.config,
+        default_output_format(
+            state.config.upload_default_format,
+            &image.bytes,
+            image.filename.as_deref(),
+        ),
+    )?;
+
+    let queue = state
+        .jobs
+        .as_ref()
+        .expect("job queue is started before the router");
+    let queued = queue.submit(ImageJob {
+        image,
+        params,
+        encode_options,
+        callback_url,
+        referer: state.outbound_referer(&headers).map(str::to_string),
+    })?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [("X-Queue-Position", queued.position.to_string())],
+        Json(json!({
+            "job_id": queued.id,
+            "queue_position": queued.position,
+        })),
+    ))
+}
+
+/// Secondary inputs fetched for a request alongside its source image.
+#[derive(Default)]
+struct RequestAssets {
+    background: Option<Bytes>,
+    lut: Option<Arc<Lut>>,
+}
+
+/// Decodes, transforms and encodes an image, returning the encoded output and
+/// the list of operations that were applied.
+fn process_image(
+    config: &Config,
+    image_bytes: &[u8],
+    assets: &RequestAssets,
+    params: &TransformParams,
+    encode_options: &EncodeOptions,
+    timings: &mut StageTimings,
+) -> Result<(ProcessedImage, Vec<String>), AppError> {
+    // Without the rotations, a JPEG source may pass through untouched, which
+    // keeps the tagged output lossless
+    if let Some((params, orientation)) = params.split_exif_rotation(encode_options)? {
+        let (mut processed_image, mut operations) = process_image(
+            config,
+            image_bytes,
+            assets,
+            &params,
+            encode_options,
+            timings,
+        )?;
+        processed_image.bytes = ops::set_jpeg_orientation(&processed_image.bytes, orientation);
+        operations.push(format!("orientation:{}", orientation));
+        return Ok((processed_image, operations));
+    }
+
+    if let Some(passthrough) = passthrough(config, image_bytes, params, encode_options)? {
+        return Ok(passthrough);
+    }
+
+    let (img, mut operations) = decode_and_transform(config, image_bytes, assets, params, timings)?;
+    let encode_options = &encode_options.resolve_format(&img);
+    let img = flatten_for_format(img, params, encode_options.format, &mut operations)?;
+
+    let started = Instant::now();
+    let processed_image = ops::encode_image_to_bytes(img, encode_options)?;
+    operations.push(encode_operation(encode_options, processed_image.quality));
+    timings.record("encode", started);
+
+    Ok((processed_image, operations))
+}
+
+/// Like [`process_image`], but encodes straight into the response body
+/// instead of buffering the output.
+fn stream_image(
+    config: &Config,
+    image_bytes: &[u8],
+    assets: &RequestAssets,
+    params: &TransformParams,
+    encode_options: EncodeOptions,
+    timings: &mut StageTimings,
+) -> Result<Response, AppError> {
+    if let Some((processed_image, operations)) =
+        passthrough(config, image_bytes, params, &encode_options)?
+    {
+        return send_image_response(processed_image, &operations, params.download());
+    }
+
+    let (img, mut operations) = decode_and_transform(config, image_bytes, assets, params, timings)?;
+    let encode_options = encode_options.resolve_format(&img);
+    let img = flatten_for_format(img, params, encode_options.format, &mut operations)?;
+
+    let encode_options = encode_options.for_image(&img);
+    let (quality, quality_clamped) = encode_options.effective_quality();
+    operations.push(encode_operation(&encode_options, quality));
+    // The encoded size is unknown until the body has been sent
+    let mut headers = image_headers(
+        encode_options.format.mime_type(),
+        quality,
+        quality_clamped,
+        img.dimensions(),
+        &operations,
+    )?;
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition(params.download()),
+    );
+    let body = stream::encode_to_body(img, encode_options);
+    Ok((StatusCode::OK, headers, body).into_response())
+}
+
+/// Re-encoding an untouched image into its own format only costs time and,
+/// for lossy formats, quality, so such requests get the original bytes back.
+/// CMYK JPEGs are always converted to RGB.
+fn passthrough(
+    config: &Config,
+    image_bytes: &[u8],
+    params: &TransformParams,
+    encode_options: &EncodeOptions,
+) -> Result<Option<(ProcessedImage, Vec<String>)>, AppError> {
+    let Ok(source_format) = image::guess_format(image_bytes) else {
+        return Ok(None);
+    };
+    if params.has_transformations()
+        || encode_options.format.image_format() != source_format
+        || (source_format == image::ImageFormat::Jpeg && ops::is_cmyk_jpeg(image_bytes))
+    {
+        return Ok(None);
+    }
+    let (width, height) = ops::check_source_size(
+        image_bytes,
+        config.max_source_dimension,
+        config.max_source_pixels,
+        config.allowed_input_formats.as_deref(),
+    )?;
+    let processed_image = ProcessedImage {
+        bytes: image_bytes.to_vec(),
+        mime_type: source_format.to_mime_type().to_string(),
+        width,
+        height,
+        quality: None,
+        quality_clamped: false,
+    };
+    Ok(Some((processed_image, vec!["passthrough".to_string()])))
+}
+
+/// Decodes an image and applies the requested transformations, returning the
+/// result and the list of operations that were applied.
+fn decode_and_transform(
+    config: &Config,
+    image_bytes: &[u8],
+    assets: &RequestAssets,
+    params: &TransformParams,
+    timings: &mut StageTimings,
+) -> Result<(DynamicImage, Vec<String>), AppError> {
+    let started = Instant::now();
+    let img = ops::decode_image_page(
+        image_bytes,
+        params.page.unwrap_or(1),
+        config.max_source_dimension,
+        config.max_source_pixels,
+        config.allowed_input_formats.as_deref(),
+    )?;
+    let background = assets
+        .background
+        .as_deref()
+        .map(|bytes| {
+            ops::decode_image(
+                bytes,
+                config.max_source_dimension,
+                config.max_source_pixels,
+                config.allowed_input_formats.as_deref(),
+            )
+        })
+        .transpose()?;
+    timings.record("decode", started);
+
+    let started = Instant::now();
+    let mut operations = Vec::new();
+    let mut img = apply_transformations(img, params, &mut operations)?;
+    if let Some(lut) = &assets.lut {
+        img = ops::apply_lut(&img, lut);
+        operations.push("lut".to_string());
+    }
+    if let Some(background) = background {
+        img = ops::composite_over_background(
+            img,
+            background,
+            params.background_gravity.unwrap_or(Gravity::Center),
+        );
+        operations.push("background".to_string());
+    }
+    if let Some(color) = &params.flatten {
+        let color = ops::parse_color(color)?;
+        img = ops::flatten(img, color);
+        operations.push(format!(
+            "flatten:{:02x}{:02x}{:02x}",
+            color[0], color[1], color[2]
+        ));
+    }
+    // Last, so compositing and filters see straight alpha
+    if params.premultiply == Some(true) {
+        img = ops::premultiply_alpha(img);
+        operations.push("premultiply".to_string());
+    }
+    if params.debug_stamp == Some(true) {
+        params.check_allowed(config)?;
+        img = ops::debug_stamp(img, &operations.join(";"));
+        operations.push("debug_stamp".to_string());
+    }
+    timings.record("transform", started);
+
+    Ok((img, operations))
+}
+
+/// Formats without an alpha channel would drop transparency and expose
+/// whatever color hides under it, so transparent images are flattened onto
+/// white for them, or rejected in strict mode.
+fn flatten_for_format(
+    img: DynamicImage,
+    params: &TransformParams,
+    format: OutputFormat,
+    operations: &mut Vec<String>,
+) -> Result<DynamicImage, AppError> {
+    if format.supports_alpha() || !ops::has_transparency(&img) {
+        return Ok(img);
+    }
+    if params.strict == Some(true) {
+        return Err(AppError::TransparencyNotSupported(format.name()));
+    }
+    operations.push("flatten:ffffff".to_string());
+    Ok(ops::flatten(img, FLATTEN_COLOR))
+}
+
+async fn sniff_image_format(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<SourceUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Sniffing image format from URL: {:?}", params);
+
+    ops::check_source_host(&params.url, &state.config.allowed_source_hosts)?;
+    let _permit = state.fetch_permit().await;
+    let prefix = ops::fetch_image_prefix_from_url(
+        &state.http_client,
+        &params.url,
+        state.outbound_referer(&headers),
+        SNIFF_SIZE,
+    )
+    .await?;
+    let format = image::guess_format(&prefix).map_err(|_| AppError::UnrecognizedImageFormat)?;
+
+    Ok(Json(json!({
+        "format": format!("{:?}", format).to_lowercase(),
+        "mime_type": format.to_mime_type(),
+    })))
+}
+
+async fn generate_favicon_set(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<SourceUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Generating favicon set from URL: {:?}", params);
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let img = ops::decode_image(
+        &image_bytes,
+        state.config.max_source_dimension,
+        state.config.max_source_pixels,
+        state.config.allowed_input_formats.as_deref(),
+    )?;
+    if img.width() != img.height() {
+        return Err(AppError::InvalidResizeDimensions(
+            "favicon source image must be square",
+        ));
+    }
+
+    let mut files = Vec::with_capacity(FAVICON_PNGS.len() + 1);
+    for &(name, size) in FAVICON_PNGS {
+        let icon = ops::resize_image(img.clone(), size, size, FilterType::Lanczos3);
+        let encoded = ops::encode_image_to_bytes(icon, &EncodeOptions::new(OutputFormat::Png))?;
+        files.push((name.to_string(), encoded.bytes));
+    }
+    let ico_images: Vec<_> = FAVICON_ICO_SIZES
+        .iter()
+        .map(|&size| ops::resize_image(img.clone(), size, size, FilterType::Lanczos3))
+        .collect();
+    files.push(("favicon.ico".to_string(), ops::encode_ico(&ico_images)?));
+
+    zip_response(&files, "favicons.zip")
+}
+
+/// Renders a social-share card for link previews from a background image, a
+/// title and an optional subtitle.
+async fn generate_og_card(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(params): Json<OgCardParams>,
+) -> Result<Response, AppError> {
+    debug!("Generating OpenGraph card: {:?}", params);
+
+    let format = match params.output_format.as_deref() {
+        None => OutputFormat::Png,
+        Some(format) => match format.parse()? {
+            format @ (OutputFormat::Png | OutputFormat::WebP) => format,
+            format => {
+                return Err(AppError::InvalidOgCard(format!(
+                    "output_format must be png or webp, found {}",
+                    format.name()
+                )));
+            }
+        },
+    };
+    if params.title.trim().is_empty() {
+        return Err(AppError::InvalidOgCard(
+            "title must not be empty".to_string(),
+        ));
+    }
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let background = ops::decode_image(
+        &image_bytes,
+        state.config.max_source_dimension,
+        state.config.max_source_pixels,
+        state.config.allowed_input_formats.as_deref(),
+    )?;
+    let card = ops::og_card(background, &params.title, params.subtitle.as_deref());
+    let encoded = ops::encode_image_to_bytes(card, &EncodeOptions::new(format))?;
+    send_image_response(encoded, &["og_card".to_string()], false)
+}
+
+fn zip_response(files: &[(String, Vec<u8>)], filename: &str) -> Result<Response, AppError> {
+    let archive = archive::create_zip_archive(files)?;
+    let disposition = format!("attachment; filename=\"{}\"", filename);
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+/// Parses a comma-separated list of output formats, dropping duplicates.
+fn parse_output_format_list(list: &str) -> Result<Vec<OutputFormat>, AppError> {
+    let mut formats = Vec::new();
+    for name in list.split(',').filter(|name| !name.trim().is_empty()) {
+        let format = name.parse()?;
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    Ok(formats)
+}
+
+async fn compute_perceptual_hash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<PerceptualHashParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Computing perceptual hash from URL: {:?}", params);
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let img = ops::decode_image(
+        &image_bytes,
+        state.config.max_source_dimension,
+        state.config.max_source_pixels,
+        state.config.allowed_input_formats.as_deref(),
+    )?;
+    let hash = ops::perceptual_hash(&img, params.algorithm);
+
+    Ok(Json(json!({
+        "algorithm": params.algorithm,
+        "hash": format!("{:016x}", hash),
+    })))
+}
+
+async fn compute_histogram(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<SourceUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Computing histogram from URL: {:?}", params);
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let img = ops::decode_image(
+        &image_bytes,
+        state.config.max_source_dimension,
+        state.config.max_source_pixels,
+        state.config.allowed_input_formats.as_deref(),
+    )?;
+
+    Ok(Json(ops::compute_histogram(&img)))
+}
+
+/// Reports whether an image has an alpha channel, and whether any pixel is
+/// actually transparent, so clients know when JPEG output is safe.
+async fn detect_alpha(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<SourceUrlParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Detecting alpha from URL: {:?}", params);
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let img = ops::decode_image(
+        &image_bytes,
+        state.config.max_source_dimension,
+        state.config.max_source_pixels,
+        state.config.allowed_input_formats.as_deref(),
+    )?;
+
+    Ok(Json(json!({
+        "has_alpha": img.color().has_alpha(),
+        "has_meaningful_alpha": ops::has_transparency(&img),
+    })))
+}
+
+/// Builds an HTML `srcset` attribute value with a `/url` candidate per
+/// requested width, so pages don't have to construct the URLs themselves.
+async fn generate_srcset(
+    State(state): State<AppState>,
+    Query(params): Query<SrcsetParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut widths = params
+        .widths
+        .split(',')
+        .map(|width| match width.trim().parse::<u32>() {
+            Ok(width) if width > 0 => Ok(width),
+            _ => Err(AppError::InvalidSrcset(format!(
+                "widths must be positive numbers, found {}",
+                width.trim()
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    widths.sort_unstable();
+    widths.dedup();
+
+    // The Host header is chosen by the client, so without a configured base
+    // the URLs are left relative instead of trusting it
+    let base = state
+        .config
+        .public_base_url
+        .as_ref()
+        .map(reqwest::Url::as_str);
+    let endpoint = format!(
+        "{}/url",
+        base.unwrap_or("http://localhost").trim_end_matches('/')
+    );
+    let endpoint = reqwest::Url::parse(&endpoint)
+        .map_err(|_| AppError::InvalidSrcset(format!("cannot build URLs from {}", endpoint)))?;
+    let candidates: Vec<_> = widths
+        .iter()
+        .map(|width| {
+            let mut url = endpoint.clone();
+            url.query_pairs_mut()
+                .append_pair("url", &params.url)
+                .append_pair("w", &width.to_string());
+            match base {
+                Some(_) => format!("{} {}w", url, width),
+                None => format!(
+                    "{}?{} {}w",
+                    url.path(),
+                    url.query().unwrap_or_default(),
+                    width
+                ),
+            }
+        })
+        .collect();
+
+    Ok(Json(json!({ "srcset": candidates.join(", ") })))
+}
+
+async fn read_exif(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ExifParams>,
+) -> Result<impl IntoResponse, AppError> {
+    debug!("Reading EXIF metadata from URL: {:?}", params);
+
+    let image_bytes = state
+        .fetch_image(&params.url, state.outbound_referer(&headers))
+        .await?;
+    let tags = ops::read_exif_tags(&image_bytes, params.omit_gps != Some(true))?;
+
+    Ok(Json(json!({ "tags": tags })))
+}
+
+async fn normalize_filter(
+    Query(params): Query<FilterParams>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(json!({
+        "filter": ops::normalize_filter_str(&params.filter)?,
+    })))
+}
+
+fn send_image_response(
+    processed_image: ProcessedImage,
+    operations: &[String],
+    download: bool,
+) -> Result<Response, AppError> {
+    let mut headers = image_headers(
+        &processed_image.mime_type,
+        processed_image.quality,
+        processed_image.quality_clamped,
+        (processed_image.width, processed_image.height),
+        operations,
+    )?;
+    headers.insert(
+        "X-Image-Bytes",
+        HeaderValue::from(processed_image.bytes.len()),
+    );
+    headers.insert(header::CONTENT_DISPOSITION, content_disposition(download));
+    Ok((StatusCode::OK, headers, processed_image.bytes).into_response())
+}
+
+/// `attachment` makes browsers save the image instead of displaying it.
+fn content_disposition(download: bool) -> HeaderValue {
+    HeaderValue::from_static(if download { "attachment" } else { "inline" })
+}
+
+fn image_headers(
+    mime_type: &str,
+    quality: Option<u8>,
+    quality_clamped: bool,
+    (width, height): (u32, u32),
+    operations: &[String],
+) -> Result<HeaderMap, AppError> {
+    let mut headers = HeaderMap::new();
+    match HeaderValue::from_str(mime_type) {
+        Ok(val) => {
+            headers.insert("Content-Type", val);
+        }
+        Err(_) => {
+            return Err(AppError::ImageProcessingError(
+                image::ImageError::Unsupported(
+                    image::error::UnsupportedError::from_format_and_kind(
+                        image::error::ImageFormatHint::Unknown,
+                        image::error::UnsupportedErrorKind::GenericFeature(
+                            "invalid MIME type generated".to_string(),
+                        ),
+                    ),
+                ),
+            ));
+        }
+    }
+    headers.insert("X-Image-Width", HeaderValue::from(width));
+    headers.insert("X-Image-Height", HeaderValue::from(height));
+    if operations
+        .iter()
+        .any(|operation| operation.starts_with("flatten:"))
+    {
+        headers.insert("X-Flattened", HeaderValue::from_static("true"));
+    }
+    if quality_clamped {
+        headers.insert("X-Quality-Clamped", HeaderValue::from_static("true"));
+        if let Some(quality) = quality {
+            headers.insert("X-Quality", HeaderValue::from(u16::from(quality)));
+        }
+    }
+    // Filter strings are client-supplied and may not be valid header values
+    if let Ok(val) = HeaderValue::from_str(&operations.join(";")) {
+        headers.insert("X-Image-Operations", val);
+    }
+    Ok(headers)
+}
+
+fn encode_operation(encode_options: &EncodeOptions, quality: Option<u8>) -> String {
+    match quality {
+        Some(quality) => format!("encode:{}@{}", encode_options.format.name(), quality),
+        None => format!("encode:{}", encode_options.format.name()),
+    }
+}
+
+fn apply_transformations(
+    mut img: DynamicImage,
+    params: &TransformParams,
+    operations: &mut Vec<String>,
+) -> Result<DynamicImage, AppError> {
+    let TransformParams {
+        w,
+        h,
+        crop_x,
+        crop_y,
+        crop_w,
+        crop_h,
+        crop_gravity,
+        crop_xf,
+        crop_yf,
+        crop_wf,
+        crop_hf,
+        ..
+    } = *params;
+
+    if params.unpremultiply == Some(true) {
+        img = ops::unpremultiply_alpha(img);
+        operations.push("unpremultiply".to_string());
+    }
+
+    // Blur regions are given in source image coordinates
+    if let Some(blur_region) = &params.blur_region {
+        let (region, sigma) = ops::parse_blur_region(blur_region)?;
+        img = ops::blur_region(img, region, sigma)?;
+        operations.push(format!("blur_region:{}:{}", region, sigma));
+    }
+    if let Some(redact) = &params.redact {
+        let color = match &params.redact_color {
+            Some(color) => ops::parse_color(color)?,
+            None => REDACT_COLOR,
+        };
+        for region in ops::parse_regions(redact)? {
+            img = ops::fill_rect(img, region, color)?;
+            operations.push(format!("redact:{}", region));
+        }
+    }
+
+    // A pipeline supersedes the crop, resize and filter parameters
+    if let Some(pipeline) = params.pipeline()? {
+        return pipeline.apply(img, operations);
+    }
+
+    // A fractional crop window needs all four fractions and no pixel crop
+    let (crop_x, crop_y, crop_w, crop_h) = match (crop_xf, crop_yf, crop_wf, crop_hf) {
+        (None, None, None, None) => (crop_x, crop_y, crop_w, crop_h),
+        _ if crop_x.is_some() || crop_y.is_some() || crop_w.is_some() || crop_h.is_some() => {
+            return Err(AppError::InvalidCropDimensions(
+                "fractional crop parameters cannot be combined with crop_x, crop_y, crop_w or crop_h",
+            ));
+        }
+        (Some(xf), Some(yf), Some(wf), Some(hf)) => {
+            let (x, y, w, h) =
+                ops::fractional_crop_window(xf, yf, wf, hf, img.width(), img.height())?;
+            (Some(x), Some(y), Some(w), Some(h))
+        }
+        _ => {
+            return Err(AppError::InvalidCropDimensions(
+                "crop_xf, crop_yf, crop_wf and crop_hf must be given together",
+            ));
+        }
+    };
+
+    // Position the crop window from the gravity, centered by default, when no
+    // explicit offset is given
+    let (crop_x, crop_y) = match (crop_x, crop_y, crop_w, crop_h) {
+        (None, None, Some(cw), Some(ch)) => {
+            let gravity = crop_gravity.unwrap_or(Gravity::Center);
+            let (x, y) = ops::gravity_offset(gravity, cw, ch, img.width(), img.height());
+            (Some(x), Some(y))
+        }
+        _ => (crop_x, crop_y),
+    };
+
+    // Crop if all crop parameters are present
+    if let (Some(cx), Some(cy), Some(cw), Some(ch)) = (crop_x, crop_y, crop_w, crop_h) {
+        if cw > 0 && ch > 0 {
+            let (cx, cy, cw, ch) = if params.crop_clamp == Some(true) {
+                ops::clamp_crop_window(cx, cy, cw, ch, img.width(), img.height())?
+            } else {
+                (cx, cy, cw, ch)
+            };
+            img = ops::crop_image(img, cx, cy, cw, ch)?;
+            operations.push(format!("crop:{},{},{}x{}", cx, cy, cw, ch));
+        } else {
+            return Err(AppError::InvalidCropDimensions(
+                "crop width and height must be greater than 0.",
+            ));
+        }
+    }
+
+    // fit_width/fit_height pin one side and always derive the other from
+    // the aspect ratio, so they take the single-dimension path below
+    let (w, h) = match (params.fit_width, params.fit_height) {
+        (None, None) => (w, h),
+        (Some(_), Some(_)) => {
+            return Err(AppError::InvalidResizeDimensions(
+                "fit_width and fit_height cannot be combined",
+            ));
+        }
+        _ if w.is_some() || h.is_some() || params.mode == Some(ResizeMode::Cover) => {
+            return Err(AppError::InvalidResizeDimensions(
+                "fit_width and fit_height cannot be combined with w, h or mode=cover",
+            ));
+        }
+        fit => fit,
+    };
+
+    // Resize if width or height is present
+    let cover = params.mode == Some(ResizeMode::Cover);
+    if cover && (w.is_none() || h.is_none()) {
+        return Err(AppError::InvalidResizeDimensions(
+            "mode=cover requires both w and h",
+        ));
+    }
+    let (current_w, current_h) = img.dimensions();
+    let target_w = w.unwrap_or(current_w);
+    let target_h = h.unwrap_or(current_h);
+
+    if w.is_some() || h.is_some() {
+        if target_w > 0 && target_h > 0 {
+            // If one dimension is not specified for resize, maintain aspect ratio
+            let (final_w, final_h) = if w.is_none() && h.is_some() {
+                // height specified, width auto
+                let aspect_ratio = current_w as f32 / current_h as f32;
+                ((target_h as f32 * aspect_ratio) as u32, target_h)
+            } else if w.is_some() && h.is_none() {
+                // width specified, height auto
+                let aspect_ratio = current_h as f32 / current_w as f32;
+                (target_w, (target_w as f32 * aspect_ratio) as u32)
+            } else {
+                // both specified or neither (no resize if neither)
+                (target_w, target_h)
+            };
+
+            // Rounded before the upscale check, which then sees the real target
+            let (final_w, final_h) = match params.round_to {
+                Some(0) => {
+                    return Err(AppError::InvalidResizeDimensions(
+                        "round_to must be greater than 0",
+                    ));
+                }
+                Some(multiple) if final_w > 0 && final_h > 0 => {
+                    let mode = params.round.unwrap_or_default();
+                    (
+                        ops::round_to_multiple(final_w, multiple, mode),
+                        ops::round_to_multiple(final_h, multiple, mode),
+                    )
+                }
+                _ => (final_w, final_h),
+            };
+
+            if final_w > 0 && final_h > 0 {
+                let (final_w, final_h) = ops::upscale_target(
+                    params.upscale.unwrap_or_default(),
+                    (final_w, final_h),
+                    (current_w, current_h),
+                )?;
+                let filter = ResizeFilter::for_resize(
+                    (current_w, current_h),
+                    (final_w, final_h),
+                    params.upscale_filter,
+                    params.downscale_filter,
+                );
+                if cover && params.smart == Some(true) {
+                    img = ops::smart_cover_image(img, final_w, final_h, filter.filter_type());
+                    operations.push(format!("cover:{}x{}:smart@{}", final_w, final_h, filter));
+                } else if cover {
+                    img = ops::cover_image(img, final_w, final_h, filter.filter_type());
+                    operations.push(format!("cover:{}x{}@{}", final_w, final_h, filter));
+                } else {
+                    img = ops::resize_image(img, final_w, final_h, filter.filter_type());
+                    operations.push(format!("resize:{}x{}@{}", final_w, final_h, filter));
+                }
+
+                // Downscaling softens detail, so restore some of it on request
+                if params.post_sharpen == Some(true) && (final_w < current_w || final_h < current_h)
+                {
+                    img = img.unsharpen(POST_SHARPEN_SIGMA, POST_SHARPEN_THRESHOLD);
+                    operations.push("post_sharpen".to_string());
+                }
+            } else if w.is_some() || h.is_some() {
+                // only error if a resize was intended
+                return Err(AppError::InvalidResizeDimensions(
+                    "resize width and height must result in dimensions greater than 0",
+                ));
+            }
+        } else if w.is_some() || h.is_some() {
+            // only error if a resize was intended
+            return Err(AppError::InvalidResizeDimensions(
+                "target resize width and height must be greater than 0",
+            ));
+        }
+    }
+
+    // Apply filter if present
+    if let Some(f_str) = &params.filter
+        && !f_str.trim().is_empty()
+    {
+        img = apply_filter_str(img, f_str)?;
+        operations.push(format!("filter:{}", f_str.trim()));
+    }
+
+    Ok(img)
+}
+
+/// Output format used when none was requested: the route's configured
+/// default, or else one inferred from the source image.
+fn default_output_format(
+    route_default: Option<OutputFormat>,
+    image_bytes: &[u8],
+    name: Option<&str>,
+) -> OutputFormat {
+    route_default.unwrap_or_else(|| infer_output_format(image_bytes, name))
+}
+
+/// Infers an output format from the source image: the format sniffed from
+/// the image bytes when it can be encoded, then the extension of the URL or
+/// file name, then PNG.
+fn infer_output_format(image_bytes: &[u8], name: Option<&str>) -> OutputFormat {
+    image::guess_format(image_bytes)
+        .ok()
+        .and_then(OutputFormat::from_image_format)
+        .or_else(|| {
+            name.and_then(|name| Path::new(name).extension())
+                .and_then(|os_str| os_str.to_str())
+                .and_then(|ext| ext.parse().ok())
+        })
+        .unwrap_or(OutputFormat::Png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+    use reqwest::multipart::{Form, Part};
+    use std::{
+        io::Cursor,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        time::SystemTime,
+    };
+
+    /// Serves `app` on an ephemeral port, returning its base URL.
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        format!("http://{}", addr)
+    }
+
+    async fn spawn_app(config: Config) -> String {
+        serve(router(build_state(config).await)).await
+    }
+
+    /// A `width`x`height` PNG filled with `color`.
+    fn png(width: u32, height: u32, color: [u8; 3]) -> Vec<u8> {
+        let img = image::RgbImage::from_pixel(width, height, image::Rgb(color));
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn image_part() -> Part {
+        Part::bytes(png(4, 4, [255, 0, 0])).file_name("red.png")
+    }
+
+    /// Serves `image` at `/image.png`, counting how often it is fetched.
+    async fn spawn_origin(image: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let app = Router::new().route(
+            "/image.png",
+            get(move || {
+                counter.fetch_add(1, AtomicOrdering::SeqCst);
+                let image = image.clone();
+                async move { ([(header::CONTENT_TYPE, "image/png")], image) }
+            }),
+        );
+        (serve(app).await, hits)
+    }
+
+    /// A fresh, empty directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-{}-{}",
+            name,
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Posts `form` to `/upload`.
+    async fn upload(base: &str, form: Form) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/upload", base))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_too_many_fields() {
+        let config = Config {
+            max_multipart_fields: 3,
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let mut form = Form::new().part("image", image_part());
+        for i in 0..3 {
+            form = form.text(format!("unknown_{}", i), "x");
+        }
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "too many multipart fields (maximum is 3)");
+    }
+
+    #[tokio::test]
+    async fn upload_accepts_fields_up_to_the_limit() {
+        let config = Config {
+            max_multipart_fields: 3,
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("w", "2")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn jobs_reject_a_second_image_field() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .part("image", image_part())
+            .text("callback_url", "https://example.com/done");
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/jobs", base))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_quality_is_reported_as_clamped() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "jpeg")
+            .text("quality", "150");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Quality-Clamped"], "true");
+        assert_eq!(response.headers()["X-Quality"], "100");
+
+        // Values past what fits a byte are clamped the same way
+        for (quality, effective) in [("300", "100"), ("1000", "100"), ("-5", "1")] {
+            let form = Form::new()
+                .part("image", image_part())
+                .text("output_format", "jpeg")
+                .text("quality", quality);
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::OK, "quality={}", quality);
+            assert_eq!(response.headers()["X-Quality-Clamped"], "true");
+            assert_eq!(response.headers()["X-Quality"], effective);
+        }
+
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "jpeg")
+            .text("quality", "90");
+        let response = upload(&base, form).await;
+        assert!(response.headers().get("X-Quality-Clamped").is_none());
+    }
+
+    #[tokio::test]
+    async fn operations_header_lists_crop_resize_and_filter() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", Part::bytes(png(8, 8, [0, 128, 255])))
+            .text("crop_x", "0")
+            .text("crop_y", "0")
+            .text("crop_w", "4")
+            .text("crop_h", "4")
+            .text("w", "2")
+            .text("filter", "grayscale")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["X-Image-Operations"],
+            "crop:0,0,4x4;resize:2x2@lanczos3;filter:grayscale;encode:png"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_body_matches_the_equivalent_query() {
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+        let client = reqwest::Client::new();
+
+        let get = client
+            .get(format!(
+                "{}/url?url={}/image.png&w=4&filter=invert&output_format=png",
+                base, origin
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(get.status(), StatusCode::OK);
+        let post = client
+            .post(format!("{}/url", base))
+            .json(&serde_json::json!({
+                "url": format!("{}/image.png", origin),
+                "w": 4,
+                "filter": "invert",
+                "output_format": "png",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(post.status(), StatusCode::OK);
+        assert_eq!(
+            post.headers()["X-Image-Operations"],
+            get.headers()["X-Image-Operations"]
+        );
+        assert_eq!(post.bytes().await.unwrap(), get.bytes().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sniff_detects_png_and_jpeg_magic_bytes() {
+        let base = spawn_app(Config::default()).await;
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(64, 64)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let origin = serve(
+            Router::new()
+                .route("/a", get(|| async { png(64, 64, [1, 2, 3]) }))
+                .route("/b", get(move || async move { jpeg }))
+                .route("/c", get(|| async { "not an image" })),
+        )
+        .await;
+        let client = reqwest::Client::new();
+
+        for (path, format) in [("a", "png"), ("b", "jpeg")] {
+            let response = client
+                .get(format!("{}/sniff?url={}/{}", base, origin, path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["format"], format);
+        }
+        let response = client
+            .get(format!("{}/sniff?url={}/c", base, origin))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn fetches_send_the_user_agent_and_forwarded_referer() {
+        let base = spawn_app(Config {
+            user_agent: "test-agent/1.0".to_string(),
+            forward_referer: true,
+            ..Config::default()
+        })
+        .await;
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&seen);
+        let origin = serve(Router::new().route(
+            "/image.png",
+            get(move |headers: HeaderMap| async move {
+                let header = |name: header::HeaderName| headers.get(name).cloned();
+                recorder
+                    .lock()
+                    .unwrap()
+                    .push((header(header::USER_AGENT), header(header::REFERER)));
+                png(4, 4, [0, 0, 0])
+            }),
+        ))
+        .await;
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/url?url={}/image.png", base, origin))
+            .header(header::REFERER, "https://example.com/page")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0.as_ref().unwrap(), "test-agent/1.0");
+        assert_eq!(seen[0].1.as_ref().unwrap(), "https://example.com/page");
+    }
+
+    #[tokio::test]
+    async fn rate_limited_clients_get_429_with_retry_after() {
+        let config = Config {
+            rate_limit_per_second: 0.5,
+            rate_limit_burst: Some(1),
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let client = reqwest::Client::new();
+        let upload = || {
+            client
+                .post(format!("{}/upload", base))
+                .multipart(Form::new().part("image", image_part()))
+                .send()
+        };
+
+        assert_eq!(upload().await.unwrap().status(), StatusCode::OK);
+        let response = upload().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=2).contains(&retry_after));
+    }
+
+    #[tokio::test]
+    async fn og_card_renders_a_1200x630_card() {
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(png(64, 48, [0, 128, 255])).await;
+        let client = reqwest::Client::new();
+
+        for (format, mime_type) in [("png", "image/png"), ("webp", "image/webp")] {
+            let response = client
+                .post(format!("{}/og-card", base))
+                .json(&json!({
+                    "url": format!("{}/image.png", origin),
+                    "title": "Resizing images in Rust",
+                    "subtitle": "example.com",
+                    "output_format": format,
+                }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], mime_type);
+            let card = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert_eq!(card.dimensions(), (1200, 630));
+        }
+
+        let response = client
+            .post(format!("{}/og-card", base))
+            .json(&json!({
+                "url": format!("{}/image.png", origin),
+                "title": "Resizing images in Rust",
+                "output_format": "jpeg",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "invalid og-card request: output_format must be png or webp, found jpeg"
+        );
+    }
+
+    #[tokio::test]
+    async fn jobs_reject_internal_callback_urls() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("callback_url", "http://127.0.0.1:9/done");
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/jobs", base))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "delivering callbacks to 127.0.0.1 is not allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn jobs_post_results_to_allowed_callbacks() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let callback = serve(Router::new().route(
+            "/done",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let sender = sender.clone();
+                async move {
+                    sender.send((headers, body)).await.unwrap();
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        ))
+        .await;
+        let config = Config {
+            allowed_callback_hosts: vec!["127.0.0.1".to_string()],
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("w", "2")
+            .text("output_format", "png")
+            .text("callback_url", format!("{}/done", callback));
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/jobs", base))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = response.json().await.unwrap();
+
+        let (headers, image) = tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(headers["x-job-id"], body["job_id"].as_str().unwrap());
+        assert_eq!(headers["x-job-status"], "done");
+        let image = image::load_from_memory(&image).unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn jobs_forward_the_referer_when_fetching_assets() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let callback = serve(Router::new().route(
+            "/done",
+            post(move || {
+                let sender = sender.clone();
+                async move {
+                    sender.send(()).await.unwrap();
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        ))
+        .await;
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&seen);
+        let origin = serve(Router::new().route(
+            "/background.png",
+            get(move |headers: HeaderMap| async move {
+                recorder
+                    .lock()
+                    .unwrap()
+                    .push(headers.get(header::REFERER).cloned());
+                png(4, 4, [0, 0, 0])
+            }),
+        ))
+        .await;
+        let config = Config {
+            allowed_callback_hosts: vec!["127.0.0.1".to_string()],
+            forward_referer: true,
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "png")
+            .text("background_url", format!("{}/background.png", origin))
+            .text("callback_url", format!("{}/done", callback));
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/jobs", base))
+            .header(header::REFERER, "https://example.com/page")
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].as_ref().unwrap(), "https://example.com/page");
+    }
+
+    #[tokio::test]
+    async fn disk_cache_serves_the_second_request() {
+        let dir = temp_dir("disk-cache-http");
+        let config = Config {
+            disk_cache_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+        let (origin, hits) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+        let url = format!(
+            "{}/url?url={}/image.png&w=4&output_format=png",
+            base, origin
+        );
+
+        let client = reqwest::Client::new();
+        let first = client.get(&url).send().await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let first = first.bytes().await.unwrap();
+        let entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("bin".as_ref()))
+            .count();
+        assert_eq!(entries, 1);
+
+        let second = client.get(&url).send().await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.bytes().await.unwrap(), first);
+        assert_eq!(hits.load(AtomicOrdering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn server_timing_lists_each_stage() {
+        let base = spawn_app(Config {
+            server_timing: true,
+            ..Config::default()
+        })
+        .await;
+        let (origin, _) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+
+        let response = reqwest::get(format!("{}/url?url={}/image.png&w=4", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let timing = response.headers()["Server-Timing"].to_str().unwrap();
+        let stages: Vec<_> = timing
+            .split(", ")
+            .map(|metric| metric.split(';').next().unwrap())
+            .collect();
+        assert_eq!(stages, ["fetch", "decode", "transform", "encode"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_sources_are_rejected_before_decoding() {
+        let base = spawn_app(Config {
+            max_source_dimension: 256,
+            max_source_pixels: 2000,
+            ..Config::default()
+        })
+        .await;
+
+        // Few pixels, but wider than the dimension limit
+        let form = Form::new().part("image", Part::bytes(png(300, 2, [0, 0, 0])));
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "source image is too large to process (300x2)"
+        );
+
+        // Within the dimension limit, but over the pixel count
+        let form = Form::new().part("image", Part::bytes(png(50, 50, [0, 0, 0])));
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let form = Form::new().part("image", Part::bytes(png(40, 40, [0, 0, 0])));
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn untransformed_jpeg_is_passed_through_byte_for_byte() {
+        let img = image::RgbImage::from_pixel(16, 16, image::Rgb([200, 40, 90]));
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(jpeg.clone()).await;
+
+        let response = reqwest::get(format!(
+            "{}/url?url={}/image.png&output_format=jpeg",
+            base, origin
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(response.bytes().await.unwrap(), jpeg);
+    }
+
+    #[tokio::test]
+    async fn post_sharpen_raises_local_contrast_after_downscaling() {
+        // Vertical stripes soften into a gradient when halved
+        let stripes = image::RgbImage::from_fn(64, 64, |x, _| {
+            if (x / 3) % 2 == 0 {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        });
+        let mut source = Vec::new();
+        DynamicImage::ImageRgb8(stripes)
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Png)
+            .unwrap();
+        let base = spawn_app(Config::default()).await;
+
+        let mut contrast = Vec::new();
+        for post_sharpen in ["false", "true"] {
+            let form = Form::new()
+                .part("image", Part::bytes(source.clone()))
+                .text("w", "32")
+                .text("h", "32")
+                .text("post_sharpen", post_sharpen);
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let output = image::load_from_memory(&response.bytes().await.unwrap())
+                .unwrap()
+                .to_luma8();
+            let sum: u32 = output
+                .rows()
+                .flat_map(|row| {
+                    let row: Vec<u8> = row.map(|pixel| pixel[0]).collect();
+                    (1..row.len())
+                        .map(|i| u32::from(row[i].abs_diff(row[i - 1])))
+                        .collect::<Vec<_>>()
+                })
+                .sum();
+            contrast.push(sum);
+        }
+        assert!(contrast[1] > contrast[0], "{:?}", contrast);
+    }
+
+    #[tokio::test]
+    async fn favicon_set_zip_contains_every_asset() {
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(png(64, 64, [30, 60, 90])).await;
+
+        let response = reqwest::get(format!("{}/favicon-set?url={}/image.png", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+
+        let bytes = response.bytes().await.unwrap();
+        let archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut names: Vec<_> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "android-chrome-192x192.png",
+                "android-chrome-512x512.png",
+                "apple-touch-icon.png",
+                "favicon-16x16.png",
+                "favicon-32x32.png",
+                "favicon.ico",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_files_are_served_only_when_enabled() {
+        let dir = temp_dir("local-files-http");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("image.png"), png(4, 4, [0, 255, 0])).unwrap();
+        let url = "/url?file=image.png&w=2&output_format=png";
+
+        let disabled = spawn_app(Config {
+            local_files_dir: dir.clone(),
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", disabled, url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let enabled = spawn_app(Config {
+            allow_local_files: true,
+            local_files_dir: dir.clone(),
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", enabled, url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = reqwest::get(format!("{}/url?file=../image.png", enabled))
+            .await
+            .unwrap();
+        assert_ne!(response.status(), StatusCode::OK);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn comma_separated_formats_return_a_zip_of_each_encoding() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "webp,jpeg");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+
+        let bytes = response.bytes().await.unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        for (name, format) in [
+            ("image.webp", ImageFormat::WebP),
+            ("image.jpeg", ImageFormat::Jpeg),
+        ] {
+            let mut file = archive.by_name(name).unwrap();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+            assert_eq!(image::guess_format(&contents).unwrap(), format);
+        }
+    }
+
+    #[tokio::test]
+    async fn source_cache_reuses_the_body_on_not_modified() {
+        let image = png(8, 8, [10, 20, 30]);
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&full_responses);
+        let origin = serve(Router::new().route(
+            "/image.png",
+            get(move |headers: HeaderMap| {
+                let image = image.clone();
+                let counter = Arc::clone(&counter);
+                async move {
+                    if headers
+                        .get(header::IF_NONE_MATCH)
+                        .is_some_and(|tag| tag == "\"v1\"")
+                    {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    counter.fetch_add(1, AtomicOrdering::SeqCst);
+                    ([(header::ETAG, "\"v1\"")], image).into_response()
+                }
+            }),
+        ))
+        .await;
+        // A zero max age revalidates on every request
+        let base = spawn_app(Config {
+            source_cache_entries: 4,
+            source_cache_max_age_secs: 0,
+            ..Config::default()
+        })
+        .await;
+        let url = format!(
+            "{}/url?url={}/image.png&w=4&output_format=png",
+            base, origin
+        );
+
+        let first = reqwest::get(&url).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let first = first.bytes().await.unwrap();
+        let second = reqwest::get(&url).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.bytes().await.unwrap(), first);
+        assert_eq!(full_responses.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_image_field_is_rejected() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new().part("image", Part::bytes(Vec::new()).file_name("empty.png"));
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "uploaded image file is empty.");
+    }
+
+    #[test]
+    fn output_format_is_sniffed_before_trusting_the_extension() {
+        let url = "https://cdn.example.com/image.txt";
+        assert_eq!(
+            infer_output_format(&png(2, 2, [0, 0, 0]), Some(url)),
+            OutputFormat::Png
+        );
+
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        assert_eq!(infer_output_format(&jpeg, Some(url)), OutputFormat::Jpeg);
+
+        // Unrecognised bytes fall back to the extension, then to PNG
+        assert_eq!(
+            infer_output_format(b"????", Some("photo.webp")),
+            OutputFormat::WebP
+        );
+        assert_eq!(infer_output_format(b"????", Some(url)), OutputFormat::Png);
+        assert_eq!(infer_output_format(b"????", None), OutputFormat::Png);
+    }
+
+    #[tokio::test]
+    async fn disallowed_source_hosts_are_forbidden() {
+        let (origin, hits) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+        let url = format!("/url?url={}/image.png&w=4", origin);
+
+        let allowed = spawn_app(Config {
+            allowed_source_hosts: vec!["127.0.0.1".to_string()],
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", allowed, url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let restricted = spawn_app(Config {
+            allowed_source_hosts: vec!["*.example.com".to_string()],
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", restricted, url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(hits.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn redirects_must_stay_on_allowed_hosts() {
+        let image = png(8, 8, [0, 128, 255]);
+        let origin = serve(
+            Router::new()
+                .route("/image.png", get(move || async move { image }))
+                .route(
+                    "/same-host",
+                    get(|| async { axum::response::Redirect::temporary("/image.png") }),
+                )
+                .route(
+                    "/other-host",
+                    get(|headers: HeaderMap| async move {
+                        // Same server, reached under a name that is not allowed
+                        let host = headers[header::HOST].to_str().unwrap().to_string();
+                        let port = host.rsplit(':').next().unwrap().to_string();
+                        axum::response::Redirect::temporary(&format!(
+                            "http://localhost:{}/image.png",
+                            port
+                        ))
+                    }),
+                ),
+        )
+        .await;
+        let base = spawn_app(Config {
+            allowed_source_hosts: vec!["127.0.0.1".to_string()],
+            ..Config::default()
+        })
+        .await;
+
+        let response = reqwest::get(format!("{}/url?url={}/same-host", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = reqwest::get(format!("{}/url?url={}/other-host", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "fetching images from localhost is not allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn disk_cache_is_not_consulted_for_disallowed_sources() {
+        let dir = temp_dir("disk-cache-policy");
+        let (origin, hits) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+        let url = format!("/url?url={}/image.png&w=4&output_format=png", origin);
+
+        let open = spawn_app(Config {
+            disk_cache_dir: Some(dir.clone()),
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", open, url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A server sharing the cache but with an allowlist must not serve the
+        // entry cached by the open one
+        let restricted = spawn_app(Config {
+            disk_cache_dir: Some(dir.clone()),
+            allowed_source_hosts: vec!["images.example.com".to_string()],
+            ..Config::default()
+        })
+        .await;
+        let response = reqwest::get(format!("{}{}", restricted, url))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(hits.load(AtomicOrdering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn responses_carry_security_headers() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new().part("image", image_part()).text("w", "2");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+            "nosniff"
+        );
+        assert_eq!(
+            response.headers()[header::CONTENT_SECURITY_POLICY],
+            CONTENT_SECURITY_POLICY
+        );
+    }
+
+    #[tokio::test]
+    async fn normalize_filter_endpoint_returns_the_canonical_chain() {
+        let base = spawn_app(Config::default()).await;
+        let response = reqwest::get(format!("{}/normalize-filter?filter=Grayscale,blur", base))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["filter"], "grayscale,blur:1");
+    }
+
+    #[tokio::test]
+    async fn streamed_output_matches_buffered_output() {
+        let buffered = spawn_app(Config::default()).await;
+        let streamed = spawn_app(Config {
+            stream_responses: true,
+            ..Config::default()
+        })
+        .await;
+        // Large enough to span several streamed chunks
+        let source = png(300, 300, [40, 80, 120]);
+
+        let mut bodies = Vec::new();
+        for base in [&buffered, &streamed] {
+            let form = Form::new()
+                .part("image", Part::bytes(source.clone()))
+                .text("filter", "invert")
+                .text("output_format", "bmp");
+            let response = upload(base, form).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/bmp");
+            let chunked = response.headers().get(header::CONTENT_LENGTH).is_none();
+            bodies.push((chunked, response.bytes().await.unwrap()));
+        }
+        assert_eq!((bodies[0].0, bodies[1].0), (false, true));
+        assert!(bodies[0].1.len() > 64 * 1024);
+        assert_eq!(bodies[0].1, bodies[1].1);
+    }
+
+    #[tokio::test]
+    async fn upscale_modes_on_an_undersized_source() {
+        let base = spawn_app(Config::default()).await;
+        let resize = |mode: &str| {
+            Form::new()
+                .part("image", Part::bytes(png(8, 4, [0, 0, 0])))
+                .text("w", "16")
+                .text("h", "8")
+                .text("upscale", mode.to_string())
+        };
+        let dimensions = |bytes: &[u8]| image::load_from_memory(bytes).unwrap().dimensions();
+
+        let response = upload(&base, resize("allow")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(dimensions(&response.bytes().await.unwrap()), (16, 8));
+
+        let response = upload(&base, resize("clamp")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(dimensions(&response.bytes().await.unwrap()), (8, 4));
+
+        let response = upload(&base, resize("deny")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "upscaling is not allowed: requested 16x8 but the source is 8x4"
+        );
+    }
+
+    #[tokio::test]
+    async fn dimension_and_size_headers_match_the_output() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("w", "5")
+            .text("h", "3")
+            .text("output_format", "jpeg");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+        let (width, height, size) = (
+            header("X-Image-Width"),
+            header("X-Image-Height"),
+            header("X-Image-Bytes"),
+        );
+        let bytes = response.bytes().await.unwrap();
+        let output = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(width, output.width().to_string());
+        assert_eq!(height, output.height().to_string());
+        assert_eq!(size, bytes.len().to_string());
+    }
+
+    #[tokio::test]
+    async fn default_output_format_is_configured_per_route() {
+        let base = spawn_app(Config {
+            upload_default_format: Some(OutputFormat::WebP),
+            ..Config::default()
+        })
+        .await;
+        let (origin, _) = spawn_origin(png(8, 8, [0, 128, 255])).await;
+
+        let form = Form::new().part("image", image_part()).text("w", "2");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+
+        let response = reqwest::get(format!("{}/url?url={}/image.png&w=2", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn sixteen_bit_grayscale_png_keeps_its_depth() {
+        let gray = image::ImageBuffer::<image::Luma<u16>, _>::from_fn(8, 8, |x, y| {
+            image::Luma([(x * 8000 + y * 100) as u16])
+        });
+        let mut source = Vec::new();
+        DynamicImage::ImageLuma16(gray)
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Png)
+            .unwrap();
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", Part::bytes(source))
+            .text("w", "4")
+            .text("h", "4")
+            .text("filter", "sharpen")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(output.color(), image::ColorType::L16);
+        assert_eq!(output.dimensions(), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn chromakey_needs_an_output_format_with_alpha() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("filter", "chromakey:00ff00:40")
+            .text("output_format", "jpeg");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn request_log_is_active_only_when_enabled() {
+        let dir = temp_dir("request-log");
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample_file = dir.join("samples.jsonl");
+        let config = |request_log| Config {
+            request_log,
+            request_log_sample_file: Some(sample_file.clone()),
+            request_log_sample_every: 1,
+            ..Config::default()
+        };
+        assert!(request_log::request_log_layer(&config(false)).is_none());
+        assert!(request_log::request_sampler(&config(false)).is_none());
+        assert!(request_log::request_log_layer(&config(true)).is_some());
+
+        let disabled = spawn_app(config(false)).await;
+        reqwest::get(format!("{}/normalize-filter?filter=invert", disabled))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!sample_file.exists());
+
+        let enabled = spawn_app(config(true)).await;
+        reqwest::get(format!("{}/normalize-filter?filter=invert", enabled))
+            .await
+            .unwrap();
+        reqwest::Client::new()
+            .post(format!("{}/url", enabled))
+            .json(&json!({ "url": "http://127.0.0.1:9/a.png", "w": 4 }))
+            .send()
+            .await
+            .unwrap();
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "jpeg");
+        upload(&enabled, form).await;
+
+        // Samples are written in the background
+        let started = Instant::now();
+        let samples = loop {
+            let samples = std::fs::read_to_string(&sample_file).unwrap_or_default();
+            if samples.lines().count() == 3 || started.elapsed() > Duration::from_secs(5) {
+                break samples;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        let samples: Vec<serde_json::Value> = samples
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0]["method"], "GET");
+        assert_eq!(samples[0]["uri"], "/normalize-filter?filter=invert");
+        assert_eq!(samples[1]["method"], "POST");
+        let body: serde_json::Value =
+            serde_json::from_str(samples[1]["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body["w"], 4);
+        // Uploads keep their parameters but not the image bytes
+        assert_eq!(samples[2]["fields"]["output_format"], "jpeg");
+        assert_eq!(samples[2]["files"][0]["filename"], "red.png");
+        assert!(samples[2]["files"][0]["bytes"].as_u64().unwrap() > 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn cover_mode_produces_exactly_the_requested_size() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", Part::bytes(png(173, 41, [90, 90, 90])))
+            .text("mode", "cover")
+            .text("w", "50")
+            .text("h", "50");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(output.dimensions(), (50, 50));
+
+        let form = Form::new()
+            .part("image", image_part())
+            .text("mode", "fill")
+            .text("w", "2");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Objects stored in a mock S3 bucket, by key.
+    type StoredObjects = Arc<std::sync::Mutex<Vec<(String, Bytes)>>>;
+
+    /// Starts the app configured to store into a mock bucket named `images`,
+    /// returning the app's and the bucket endpoint's base URLs.
+    async fn spawn_app_with_bucket() -> (String, String, StoredObjects) {
+        let puts = StoredObjects::default();
+        let recorded = Arc::clone(&puts);
+        let bucket = serve(Router::new().route(
+            "/images/:key",
+            axum::routing::put(
+                move |axum::extract::Path(key): axum::extract::Path<String>,
+                      headers: HeaderMap,
+                      body: Bytes| {
+                    let recorded = Arc::clone(&recorded);
+                    async move {
+                        let authorization = headers[header::AUTHORIZATION].to_str().unwrap();
+                        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=key-id/"));
+                        recorded.lock().unwrap().push((key, body));
+                        StatusCode::OK
+                    }
+                },
+            ),
+        ))
+        .await;
+        let base = spawn_app(Config {
+            s3_endpoint: Some(bucket.clone()),
+            s3_bucket: Some("images".to_string()),
+            s3_access_key_id: Some("key-id".to_string()),
+            s3_secret_access_key: Some("secret".to_string()),
+            ..Config::default()
+        })
+        .await;
+        (base, bucket, puts)
+    }
+
+    #[tokio::test]
+    async fn store_s3_puts_the_object_and_returns_its_url() {
+        let (base, bucket, puts) = spawn_app_with_bucket().await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("w", "2")
+            .text("output_format", "png")
+            .text("store", "s3");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let puts = puts.lock().unwrap();
+        assert_eq!(puts.len(), 1);
+        let (key, stored) = &puts[0];
+        assert_eq!(body["key"], key.as_str());
+        assert_eq!(body["url"], format!("{}/images/{}", bucket, key));
+        assert_eq!(body["bytes"], stored.len());
+        assert_eq!(
+            image::load_from_memory(stored).unwrap().dimensions(),
+            (2, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn outbound_fetches_are_bounded_by_the_limit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let image = png(4, 4, [0, 255, 0]);
+        let origin = {
+            let (in_flight, peak) = (Arc::clone(&in_flight), Arc::clone(&peak));
+            serve(Router::new().route(
+                "/image.png",
+                get(move || {
+                    let (in_flight, peak) = (Arc::clone(&in_flight), Arc::clone(&peak));
+                    let image = image.clone();
+                    async move {
+                        let now = in_flight.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+                        peak.fetch_max(now, AtomicOrdering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        in_flight.fetch_sub(1, AtomicOrdering::SeqCst);
+                        ([(header::CONTENT_TYPE, "image/png")], image)
+                    }
+                }),
+            ))
+            .await
+        };
+        let config = Config {
+            max_concurrent_fetches: 2,
+            ..Config::default()
+        };
+        let base = spawn_app(config).await;
+
+        let mut requests = tokio::task::JoinSet::new();
+        for width in 1..=6 {
+            let url = format!("{}/url?url={}/image.png&w={}", base, origin, width);
+            requests.spawn(async move { reqwest::get(url).await.unwrap().status() });
+        }
+        while let Some(status) = requests.join_next().await {
+            assert_eq!(status.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(peak.load(AtomicOrdering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn transparent_images_are_flattened_for_jpeg_unless_strict() {
+        let base = spawn_app(Config::default()).await;
+        let mut transparent = Vec::new();
+        DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([0, 0, 0, 0]),
+        ))
+        .write_to(&mut Cursor::new(&mut transparent), ImageFormat::Png)
+        .unwrap();
+        let part = || Part::bytes(transparent.clone()).file_name("clear.png");
+
+        let form = Form::new()
+            .part("image", part())
+            .text("output_format", "jpeg");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-flattened"], "true");
+        let flattened = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert!(
+            flattened
+                .to_rgb8()
+                .pixels()
+                .all(|p| p.0.iter().all(|&c| c > 250))
+        );
+
+        let form = Form::new()
+            .part("image", part())
+            .text("output_format", "jpeg")
+            .text("strict", "true");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let form = Form::new()
+            .part("image", image_part())
+            .text("output_format", "jpeg")
+            .text("strict", "true");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-flattened").is_none());
+    }
+
+    #[tokio::test]
+    async fn histogram_of_a_two_tone_image_has_two_spikes() {
+        let base = spawn_app(Config::default()).await;
+        let img = image::RgbImage::from_fn(4, 4, |x, _| {
+            if x < 2 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        let (origin, _) = spawn_origin(bytes).await;
+
+        let response = reqwest::get(format!("{}/histogram?url={}/image.png", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let bins = |channel: &str| -> Vec<u64> {
+            body[channel]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|count| count.as_u64().unwrap())
+                .collect()
+        };
+
+        let red = bins("red");
+        assert_eq!(red.len(), 256);
+        assert_eq!((red[0], red[255]), (8, 8));
+        assert_eq!(bins("green")[0], 16);
+        let blue = bins("blue");
+        assert_eq!((blue[0], blue[255]), (8, 8));
+        let luma = bins("luma");
+        assert_eq!(luma.iter().sum::<u64>(), 16);
+        assert_eq!(luma.iter().filter(|&&count| count == 8).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn round_to_snaps_the_resized_width_to_a_multiple() {
+        let base = spawn_app(Config::default()).await;
+        let resized_width = |round: Option<&'static str>| {
+            let base = base.clone();
+            async move {
+                let mut form = Form::new()
+                    .part(
+                        "image",
+                        Part::bytes(png(200, 200, [0, 0, 255])).file_name("blue.png"),
+                    )
+                    .text("w", "101")
+                    .text("round_to", "4")
+                    .text("output_format", "png");
+                if let Some(round) = round {
+                    form = form.text("round", round);
+                }
+                let response = upload(&base, form).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers()["X-Image-Width"]
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            }
+        };
+
+        assert_eq!(resized_width(None).await, "104");
+        assert_eq!(resized_width(Some("down")).await, "100");
+        assert_eq!(resized_width(Some("nearest")).await, "100");
+    }
+
+    #[tokio::test]
+    async fn crop_size_alone_crops_from_the_center() {
+        let base = spawn_app(Config::default()).await;
+        let img = image::RgbImage::from_fn(300, 300, |x, y| {
+            if (100..200).contains(&x) && (100..200).contains(&y) {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        let form = Form::new()
+            .part("image", Part::bytes(bytes).file_name("target.png"))
+            .text("crop_w", "100")
+            .text("crop_h", "100")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cropped = image::load_from_memory(&response.bytes().await.unwrap())
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(cropped.dimensions(), (100, 100));
+        assert!(cropped.pixels().all(|pixel| pixel.0 == [255, 0, 0]));
+    }
+
+    #[cfg(not(feature = "oxipng"))]
+    #[tokio::test]
+    async fn optimize_needs_the_oxipng_feature() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("optimize", "true")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(not(feature = "mozjpeg"))]
+    #[tokio::test]
+    async fn mozjpeg_needs_its_feature() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("jpeg_encoder", "mozjpeg")
+            .text("output_format", "jpeg");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(not(feature = "libwebp"))]
+    #[tokio::test]
+    async fn webp_alpha_quality_needs_the_libwebp_feature() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("webp_alpha_quality", "50")
+            .text("output_format", "webp");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(not(feature = "libwebp"))]
+    #[tokio::test]
+    async fn webp_effort_needs_the_libwebp_feature() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("effort", "slow")
+            .text("output_format", "webp");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "invalid encoder option: effort for webp output requires the service to be built with the libwebp feature"
+        );
+    }
+
+    #[cfg(feature = "libwebp")]
+    #[tokio::test]
+    async fn webp_alpha_quality_changes_transparent_output() {
+        let base = spawn_app(Config::default()).await;
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 4) as u8, 128, ((x + y) * 2) as u8])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let encode = |alpha_quality: &'static str| {
+            let form = Form::new()
+                .part("image", Part::bytes(png.clone()).file_name("alpha.png"))
+                .text("output_format", "webp")
+                .text("quality", "60")
+                .text("webp_alpha_quality", alpha_quality);
+            let base = base.clone();
+            async move {
+                let response = upload(&base, form).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                response.bytes().await.unwrap()
+            }
+        };
+
+        let low = encode("5").await;
+        let high = encode("100").await;
+        assert_ne!(low, high);
+        let decoded = image::load_from_memory(&high).unwrap();
+        assert!(decoded.color().has_alpha());
+
+        let form = Form::new()
+            .part("image", image_part())
+            .text("webp_alpha_quality", "101")
+            .text("output_format", "webp");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn has_alpha_distinguishes_opaque_and_transparent_pngs() {
+        let base = spawn_app(Config::default()).await;
+        let rgba_png = |alpha: u8| {
+            let mut img = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 255, 255]));
+            img.put_pixel(3, 3, image::Rgba([0, 0, 255, alpha]));
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgba8(img)
+                .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+                .unwrap();
+            bytes
+        };
+
+        for (image, expected) in [
+            (png(4, 4, [0, 0, 255]), (false, false)),
+            (rgba_png(255), (true, false)),
+            (rgba_png(128), (true, true)),
+        ] {
+            let (origin, _) = spawn_origin(image).await;
+            let response = reqwest::get(format!("{}/has-alpha?url={}/image.png", base, origin))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(
+                (
+                    body["has_alpha"].as_bool().unwrap(),
+                    body["has_meaningful_alpha"].as_bool().unwrap()
+                ),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_file_name_picks_the_default_output_format() {
+        let base = spawn_app(Config::default()).await;
+        let output_format = |bytes: Vec<u8>, name: &'static str| {
+            let base = base.clone();
+            async move {
+                let form = Form::new().part("image", Part::bytes(bytes).file_name(name));
+                let response = upload(&base, form).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                image::guess_format(&response.bytes().await.unwrap()).unwrap()
+            }
+        };
+        let encode = |format: ImageFormat| {
+            let mut bytes = Vec::new();
+            let img = image::RgbaImage::from_pixel(4, 4, image::Rgba([9, 9, 9, 255]));
+            // Icons embed RGBA PNGs, while JPEG has no alpha
+            let img = match format {
+                ImageFormat::Ico => DynamicImage::ImageRgba8(img),
+                _ => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()),
+            };
+            img.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+            bytes
+        };
+
+        assert_eq!(
+            output_format(encode(ImageFormat::Jpeg), "photo.jpg").await,
+            ImageFormat::Jpeg
+        );
+        // Icons can't be output, so the name decides
+        assert_eq!(
+            output_format(encode(ImageFormat::Ico), "photo.jpg").await,
+            ImageFormat::Jpeg
+        );
+        assert_eq!(
+            output_format(encode(ImageFormat::Ico), "photo.webp").await,
+            ImageFormat::WebP
+        );
+    }
+
+    #[tokio::test]
+    async fn lut_url_color_grades_the_upload() {
+        let base = spawn_app(Config::default()).await;
+        let cube = "LUT_3D_SIZE 2\n1 1 1\n0 1 1\n1 0 1\n0 0 1\n1 1 0\n0 1 0\n1 0 0\n0 0 0\n";
+        let (origin, hits) = spawn_origin(cube.as_bytes().to_vec()).await;
+
+        for _ in 0..2 {
+            let form = Form::new()
+                .part("image", image_part())
+                .text("lut_url", format!("{}/image.png", origin))
+                .text("output_format", "png");
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let graded = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            assert!(
+                graded
+                    .to_rgb8()
+                    .pixels()
+                    .all(|pixel| pixel.0 == [0, 255, 255])
+            );
+        }
+        assert_eq!(hits.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stored_variants_are_listed_in_a_manifest() {
+        let (base, bucket, puts) = spawn_app_with_bucket().await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("w", "2")
+            .text("output_format", "png,jpeg,webp")
+            .text("store", "s3");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let puts = puts.lock().unwrap();
+        assert_eq!(puts.len(), 4);
+        let stored = |key: &str| &puts.iter().find(|(stored, _)| stored == key).unwrap().1;
+
+        let manifest_key = body["manifest"]["key"].as_str().unwrap();
+        assert!(manifest_key.ends_with(".json"));
+        assert_eq!(
+            body["manifest"]["url"],
+            format!("{}/images/{}", bucket, manifest_key)
+        );
+        let manifest: serde_json::Value = serde_json::from_slice(stored(manifest_key)).unwrap();
+        assert_eq!(manifest["variants"], body["variants"]);
+
+        let variants = body["variants"].as_array().unwrap();
+        let formats: Vec<_> = variants
+            .iter()
+            .map(|v| v["format"].as_str().unwrap())
+            .collect();
+        assert_eq!(formats, ["png", "jpeg", "webp"]);
+        for variant in variants {
+            let bytes = stored(variant["key"].as_str().unwrap());
+            assert_eq!(variant["bytes"], bytes.len());
+            let img = image::load_from_memory(bytes).unwrap();
+            assert_eq!(
+                (variant["width"].as_u64(), variant["height"].as_u64()),
+                (Some(2), Some(2))
+            );
+            assert_eq!(img.dimensions(), (2, 2));
+            assert_eq!(
+                variant["mime_type"],
+                format!("image/{}", variant["format"].as_str().unwrap())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fit_width_and_fit_height_preserve_the_aspect_ratio() {
+        let base = spawn_app(Config::default()).await;
+        let resize = |fields: &'static [(&'static str, &'static str)]| {
+            let base = base.clone();
+            async move {
+                let mut form = Form::new()
+                    .part(
+                        "image",
+                        Part::bytes(png(200, 100, [0, 0, 255])).file_name("wide.png"),
+                    )
+                    .text("output_format", "png");
+                for &(name, value) in fields {
+                    form = form.text(name, value);
+                }
+                let response = upload(&base, form).await;
+                let status = response.status();
+                let bytes = response.bytes().await.unwrap();
+                (status == StatusCode::OK)
+                    .then(|| image::load_from_memory(&bytes).unwrap().dimensions())
+                    .ok_or(status)
+            }
+        };
+
+        assert_eq!(resize(&[("fit_width", "50")]).await, Ok((50, 25)));
+        assert_eq!(resize(&[("fit_height", "50")]).await, Ok((100, 50)));
+        assert_eq!(
+            resize(&[("fit_width", "50"), ("fit_height", "50")]).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            resize(&[("fit_width", "50"), ("h", "10")]).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn resize_filter_depends_on_the_direction() {
+        let base = spawn_app(Config::default()).await;
+        let resize_operation = |fields: &'static [(&'static str, &'static str)]| {
+            let base = base.clone();
+            async move {
+                let mut form = Form::new()
+                    .part("image", image_part())
+                    .text("output_format", "png");
+                for &(name, value) in fields {
+                    form = form.text(name, value);
+                }
+                let response = upload(&base, form).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let operations = response.headers()["X-Image-Operations"].to_str().unwrap();
+                operations
+                    .split(';')
+                    .find(|operation| operation.starts_with("resize:"))
+                    .unwrap()
+                    .to_string()
+            }
+        };
+
+        assert_eq!(
+            resize_operation(&[("w", "8"), ("upscale", "allow")]).await,
+            "resize:8x8@catmullrom"
+        );
+        assert_eq!(resize_operation(&[("w", "2")]).await, "resize:2x2@lanczos3");
+        assert_eq!(
+            resize_operation(&[
+                ("w", "8"),
+                ("upscale", "allow"),
+                ("upscale_filter", "nearest"),
+                ("downscale_filter", "gaussian"),
+            ])
+            .await,
+            "resize:8x8@nearest"
+        );
+        assert_eq!(
+            resize_operation(&[("w", "2"), ("downscale_filter", "bilinear")]).await,
+            "resize:2x2@triangle"
+        );
+    }
+
+    #[tokio::test]
+    async fn known_but_unsupported_formats_get_415() {
+        let base = spawn_app(Config::default()).await;
+        let mut heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic".to_vec();
+        heic.resize(64, 0);
+        let mut jxl = b"\0\0\0\x0cJXL \r\n\x87\n".to_vec();
+        jxl.resize(64, 0);
+
+        for (bytes, format) in [(heic, "heic"), (jxl, "jxl")] {
+            let form = Form::new().part("image", Part::bytes(bytes).file_name("photo"));
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            let body: serde_json::Value = response.json().await.unwrap();
+            let message = body["error"].as_str().unwrap();
+            assert!(message.contains(format), "{}", message);
+            assert!(message.contains("png"), "{}", message);
+        }
+    }
+
+    #[tokio::test]
+    async fn page_selects_a_tiff_page() {
+        let base = spawn_app(Config::default()).await;
+        let mut tiff = Cursor::new(Vec::new());
+        let mut encoder = tiff::encoder::TiffEncoder::new(&mut tiff).unwrap();
+        for rgb in [[255, 0, 0], [0, 0, 255]] {
+            let pixels = rgb.repeat(6 * 4);
+            encoder
+                .write_image::<tiff::encoder::colortype::RGB8>(6, 4, &pixels)
+                .unwrap();
+        }
+        let tiff = tiff.into_inner();
+        let convert = |page: &'static str| {
+            let form = Form::new()
+                .part("image", Part::bytes(tiff.clone()).file_name("scan.tiff"))
+                .text("page", page)
+                .text("output_format", "png");
+            upload(&base, form)
+        };
+
+        let response = convert("2").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let img = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(img.dimensions(), (6, 4));
+        assert_eq!(img.to_rgb8().get_pixel(3, 2).0, [0, 0, 255]);
+
+        let response = convert("1").await;
+        let img = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(img.to_rgb8().get_pixel(3, 2).0, [255, 0, 0]);
+
+        for page in ["3", "0"] {
+            let response = convert(page).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert!(body["error"].as_str().unwrap().contains("page"));
+        }
+    }
+
+    #[tokio::test]
+    async fn pixels_return_the_raw_buffer_described_by_headers() {
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(png(8, 6, [10, 20, 30])).await;
+
+        let response = reqwest::get(format!("{}/pixels?url={}/image.png&w=4", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        let header =
+            |name: &str| -> u32 { response.headers()[name].to_str().unwrap().parse().unwrap() };
+        let (width, height, channels) = (
+            header("X-Image-Width"),
+            header("X-Image-Height"),
+            header("X-Image-Channels"),
+        );
+        assert_eq!((width, height, channels), (4, 3, 3));
+        assert_eq!(header("X-Image-Bit-Depth"), 8);
+
+        let pixels = response.bytes().await.unwrap().to_vec();
+        let img = image::RgbImage::from_raw(width, height, pixels).unwrap();
+        assert!(img.pixels().all(|pixel| pixel.0 == [10, 20, 30]));
+
+        for option in [
+            "quality=80",
+            "output_format=png",
+            "store=s3",
+            "dl=1",
+            "pipeline=format(png)",
+        ] {
+            let response = reqwest::get(format!(
+                "{}/pixels?url={}/image.png&{}",
+                base, origin, option
+            ))
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", option);
+        }
+    }
+
+    #[tokio::test]
+    async fn npy_pixels_carry_the_image_shape() {
+        let base = spawn_app(Config::default()).await;
+        let (origin, _) = spawn_origin(png(8, 6, [10, 20, 30])).await;
+
+        let response = reqwest::get(format!(
+            "{}/pixels?url={}/image.png&w=4&output_format=npy",
+            base, origin
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let npy = response.bytes().await.unwrap();
+
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = usize::from(u16::from_le_bytes([npy[8], npy[9]]));
+        let data_start = 10 + header_len;
+        assert_eq!(data_start % 64, 0);
+        let header = std::str::from_utf8(&npy[10..data_start]).unwrap();
+        assert!(header.contains("'descr': '|u1'"), "{}", header);
+        assert!(header.contains("'shape': (3, 4, 3)"), "{}", header);
+        assert!(header.ends_with('\n'));
+        assert_eq!(npy.len() - data_start, 3 * 4 * 3);
+        assert!(
+            npy[data_start..]
+                .chunks(3)
+                .all(|pixel| pixel == [10, 20, 30])
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_dimension_images_get_422() {
+        let base = spawn_app(Config::default()).await;
+        // A GIF whose screen and only frame are 0x0
+        let gif = b"GIF89a\0\0\0\0\x80\0\0\0\0\0\xff\xff\xff\x2c\0\0\0\0\0\0\0\0\0\x02\0\x3b";
+
+        for w in [None, Some("2")] {
+            let mut form = Form::new().part("image", Part::bytes(&gif[..]).file_name("empty.gif"));
+            if let Some(w) = w {
+                form = form.text("w", w);
+            }
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["error"], "source image has no pixels (0x0)");
+        }
+    }
+
+    #[tokio::test]
+    async fn crop_clamp_shrinks_windows_past_the_edge() {
+        let base = spawn_app(Config::default()).await;
+        let crop = |clamp: bool| {
+            let base = base.clone();
+            async move {
+                let mut form = Form::new()
+                    .part(
+                        "image",
+                        Part::bytes(png(10, 8, [0, 255, 0])).file_name("green.png"),
+                    )
+                    .text("crop_x", "6")
+                    .text("crop_y", "4")
+                    .text("crop_w", "10")
+                    .text("crop_h", "10")
+                    .text("output_format", "png");
+                if clamp {
+                    form = form.text("crop_clamp", "true");
+                }
+                upload(&base, form).await
+            }
+        };
+
+        assert_eq!(crop(false).await.status(), StatusCode::BAD_REQUEST);
+        let response = crop(true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cropped = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(cropped.dimensions(), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn srcset_lists_a_candidate_per_width() {
+        let base = spawn_app(Config {
+            public_base_url: Some("https://img.example.com".parse().unwrap()),
+            ..Config::default()
+        })
+        .await;
+
+        let response = reqwest::get(format!(
+            "{}/srcset?url=https://cdn.example.com/a.png&widths=1280,320,640",
+            base
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["srcset"],
+            "https://img.example.com/url?url=https%3A%2F%2Fcdn.example.com%2Fa.png&w=320 320w, \
+             https://img.example.com/url?url=https%3A%2F%2Fcdn.example.com%2Fa.png&w=640 640w, \
+             https://img.example.com/url?url=https%3A%2F%2Fcdn.example.com%2Fa.png&w=1280 1280w"
+        );
+
+        let response = reqwest::get(format!("{}/srcset?url=x&widths=320,wide", base))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn srcset_urls_are_relative_without_a_public_base_url() {
+        let base = spawn_app(Config::default()).await;
+        let response = reqwest::Client::new()
+            .get(format!(
+                "{}/srcset?url=https://example.com/cat.jpg&widths=320,640",
+                base
+            ))
+            .header(header::HOST, "attacker.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["srcset"],
+            "/url?url=https%3A%2F%2Fexample.com%2Fcat.jpg&w=320 320w, \
+             /url?url=https%3A%2F%2Fexample.com%2Fcat.jpg&w=640 640w"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_uploaded_image_is_processed() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part(
+                "image",
+                Part::bytes(png(20, 10, [255, 0, 0])).file_name("red.png"),
+            )
+            .part(
+                "image",
+                Part::bytes(png(40, 40, [0, 0, 255])).file_name("blue.png"),
+            )
+            .text("w", "10")
+            .text("output_format", "png");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+
+        let bytes = response.bytes().await.unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+        for (name, dimensions) in [("red.png", (10, 5)), ("blue.png", (10, 10))] {
+            let mut file = archive.by_name(name).unwrap();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+            let img = image::load_from_memory(&contents).unwrap();
+            assert_eq!(img.dimensions(), dimensions);
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_get_a_larger_body_limit_than_json() {
+        let origin = spawn_origin(png(4, 4, [0, 0, 255])).await.0;
+        let base = spawn_app(Config {
+            max_json_body_bytes: 1024,
+            ..Config::default()
+        })
+        .await;
+
+        // Noise keeps the PNG from compressing below the JSON limit
+        let noise = image::RgbImage::from_fn(32, 32, |x, y| {
+            let value = (x * 7919 + y * 104_729) as u8;
+            image::Rgb([value, value.wrapping_mul(31), value.wrapping_mul(17)])
+        });
+        let mut image = Vec::new();
+        DynamicImage::ImageRgb8(noise)
+            .write_to(&mut Cursor::new(&mut image), ImageFormat::Png)
+            .unwrap();
+        assert!(image.len() > 2048);
+        let form = Form::new()
+            .part("image", Part::bytes(image.clone()).file_name("noise.png"))
+            .text("output_format", "png");
+        assert_eq!(upload(&base, form).await.status(), StatusCode::OK);
+
+        let body = json!({
+            "url": format!("{}/image.png", origin),
+            "filters": " ".repeat(image.len()),
+        });
+        let response = reqwest::Client::new()
+            .post(format!("{}/url", base))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn input_formats_outside_the_allowlist_get_415() {
+        let base = spawn_app(Config {
+            allowed_input_formats: Some(vec![ImageFormat::Png, ImageFormat::Jpeg]),
+            ..Config::default()
+        })
+        .await;
+        let encode = |format| {
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([9, 9, 9])))
+                .write_to(&mut Cursor::new(&mut bytes), format)
+                .unwrap();
+            bytes
+        };
+
+        for (format, name) in [(ImageFormat::Png, "a.png"), (ImageFormat::Jpeg, "a.jpg")] {
+            let form = Form::new()
+                .part("image", Part::bytes(encode(format)).file_name(name))
+                .text("w", "2");
+            assert_eq!(upload(&base, form).await.status(), StatusCode::OK);
+        }
+        // Both decoded and passed-through sources are checked
+        for w in [Some("2"), None] {
+            let mut form = Form::new()
+                .part(
+                    "image",
+                    Part::bytes(encode(ImageFormat::Bmp)).file_name("a.bmp"),
+                )
+                .text("output_format", "bmp");
+            if let Some(w) = w {
+                form = form.text("w", w);
+            }
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["error"], "image format not allowed: bmp");
+        }
+    }
+
+    #[tokio::test]
+    async fn jpeg_or_png_keeps_transparency_in_png() {
+        let base = spawn_app(Config::default()).await;
+        let rgba = |alpha| {
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+                4,
+                4,
+                image::Rgba([200, 0, 0, alpha]),
+            ))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+            bytes
+        };
+
+        for (alpha, content_type) in [(100, "image/png"), (255, "image/jpeg")] {
+            let form = Form::new()
+                .part("image", Part::bytes(rgba(alpha)).file_name("red.png"))
+                .text("w", "2")
+                .text("output_format", "jpeg_or_png");
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], content_type);
+        }
+    }
+
+    fn exif_orientation(jpeg: &[u8]) -> Option<u32> {
+        exif::Reader::new()
+            .read_from_container(&mut Cursor::new(jpeg))
+            .ok()?
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+            .value
+            .get_uint(0)
+    }
+
+    #[tokio::test]
+    async fn exif_rotate_mode_tags_jpegs_instead_of_rotating() {
+        let base = spawn_app(Config::default()).await;
+        let img =
+            image::RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8 * 16, y as u8 * 32, 0]));
+        let mut source = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Jpeg)
+            .unwrap();
+        let rotate = |format| {
+            Form::new()
+                .part("image", Part::bytes(source.clone()))
+                .text("filter", "rotate:90::true")
+                .text("rotate_mode", "exif")
+                .text("output_format", format)
+        };
+
+        let response = upload(&base, rotate("jpeg")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = response.bytes().await.unwrap();
+        assert_eq!(exif_orientation(&output), Some(6));
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgb8(),
+            image::load_from_memory(&source).unwrap().to_rgb8()
+        );
+
+        // Formats without orientation support get rotated pixels
+        let response = upload(&base, rotate("png")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = response.bytes().await.unwrap();
+        assert_eq!(exif_orientation(&output), None);
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().dimensions(),
+            (8, 16)
+        );
+    }
+
+    #[tokio::test]
+    async fn cmyk_jpegs_are_re_encoded_as_rgb() {
+        let base = spawn_app(Config::default()).await;
+        let source = include_bytes!("../tests/fixtures/cmyk-adobe.jpg");
+        assert!(ops::is_cmyk_jpeg(source));
+
+        // Untouched JPEG to JPEG requests would otherwise pass through
+        let form = Form::new()
+            .part("image", Part::bytes(source.to_vec()).file_name("print.jpg"))
+            .text("output_format", "jpeg");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = response.bytes().await.unwrap();
+        assert!(!ops::is_cmyk_jpeg(&output));
+
+        // Left half red, right half blue
+        let img = image::load_from_memory(&output).unwrap().to_rgb8();
+        for ((x, y), expected) in [((8, 16), [255, 0, 0]), ((56, 16), [0, 0, 255])] {
+            let pixel = img[(x, y)].0;
+            assert!(
+                pixel
+                    .iter()
+                    .zip(expected)
+                    .all(|(&c, e)| c.abs_diff(e) <= 24),
+                "{:?} at {},{}",
+                pixel,
+                x,
+                y
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn dl_sends_images_as_attachments() {
+        let (origin, _) = spawn_origin(png(4, 4, [1, 2, 3])).await;
+        let base = spawn_app(Config::default()).await;
+
+        for (query, disposition) in [
+            ("", "inline"),
+            ("&dl=0", "inline"),
+            ("&dl=1", "attachment"),
+            ("&dl=true", "attachment"),
+            ("&dl=1&w=2", "attachment"),
+        ] {
+            let response = reqwest::get(format!("{}/url?url={}/image.png{}", base, origin, query))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[header::CONTENT_DISPOSITION],
+                disposition,
+                "{}",
+                query
+            );
+        }
+
+        let form = Form::new().part("image", image_part()).text("dl", "1");
+        let response = upload(&base, form).await;
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment"
+        );
+    }
+
+    pub(super) async fn deliberate_panic() -> StatusCode {
+        panic!("deliberate panic")
+    }
+
+    #[tokio::test]
+    async fn handler_panics_become_500_errors() {
+        let base = spawn_app(Config::default()).await;
+
+        let response = reqwest::get(format!("{}/panic", base)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+            "nosniff"
+        );
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "internal server error");
+
+        // The server keeps serving other requests
+        let response = upload(&base, Form::new().part("image", image_part())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn tiles_cover_the_image_in_a_grid() {
+        let (origin, _) = spawn_origin(png(256, 200, [40, 80, 120])).await;
+        let base = spawn_app(Config::default()).await;
+
+        let response = reqwest::get(format!(
+            "{}/tiles?url={}/image.png&tile_size=128&output_format=png",
+            base, origin
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = response.bytes().await.unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 5);
+        let mut read = |name: &str| {
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut archive.by_name(name).unwrap(), &mut contents).unwrap();
+            contents
+        };
+        let manifest: serde_json::Value = serde_json::from_slice(&read("manifest.json")).unwrap();
+        assert_eq!(manifest["columns"], 2);
+        assert_eq!(manifest["rows"], 2);
+        for (name, dimensions) in [
+            ("0_0.png", (128, 128)),
+            ("1_0.png", (128, 128)),
+            ("0_1.png", (128, 72)),
+            ("1_1.png", (128, 72)),
+        ] {
+            let tile = image::load_from_memory(&read(name)).unwrap();
+            assert_eq!(tile.dimensions(), dimensions, "{}", name);
+        }
+
+        for query in [
+            "tile_size=128&store=s3",
+            "tile_size=128&dl=1",
+            "tile_size=0",
+        ] {
+            let response =
+                reqwest::get(format!("{}/tiles?url={}/image.png&{}", base, origin, query))
+                    .await
+                    .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn origin_last_modified_is_forwarded() {
+        const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let image = png(4, 4, [5, 6, 7]);
+        let origin = serve(Router::new().route(
+            "/image.png",
+            get(move || async move { ([(header::LAST_MODIFIED, LAST_MODIFIED)], image) }),
+        ))
+        .await;
+        let base = spawn_app(Config::default()).await;
+
+        let response = reqwest::get(format!("{}/url?url={}/image.png&w=2", base, origin))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], LAST_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn on_error_fallback_serves_the_fallback_image() {
+        let (origin, _) = spawn_origin(png(8, 8, [0, 128, 0])).await;
+        let base = spawn_app(Config::default()).await;
+        let missing = format!("{}/missing.png", origin);
+
+        let response = reqwest::get(format!("{}/url?url={}&w=2", base, missing))
+            .await
+            .unwrap();
+        assert!(!response.status().is_success());
+
+        let response = reqwest::get(format!(
+            "{}/url?url={}&w=2&output_format=png&on_error=fallback&fallback_url={}/image.png",
+            base, missing, origin
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-image-fallback"], "true");
+        let img = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(img.to_rgb8()[(0, 0)].0, [0, 128, 0]);
+    }
+
+    pub(super) async fn deliberately_slow() -> StatusCode {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        StatusCode::OK
+    }
+
+    #[tokio::test]
+    async fn requests_past_the_timeout_get_408() {
+        let base = spawn_app(Config {
+            request_timeout_secs: 1,
+            ..Config::default()
+        })
+        .await;
+
+        let started = Instant::now();
+        let response = reqwest::get(format!("{}/slow", base)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert_eq!(
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+            "nosniff"
+        );
+
+        // Requests that finish in time are unaffected
+        let response = upload(&base, Form::new().part("image", image_part())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn debug_stamp_marks_the_bottom_left_corner() {
+        let base = spawn_app(Config {
+            allow_debug_stamp: true,
+            ..Config::default()
+        })
+        .await;
+        let convert = |stamp: &'static str| {
+            let form = Form::new()
+                .part("image", Part::bytes(png(64, 64, [40, 160, 220])))
+                .text("filter", "grayscale")
+                .text("debug_stamp", stamp);
+            let base = base.clone();
+            async move {
+                let response = upload(&base, form).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let bytes = response.bytes().await.unwrap();
+                image::load_from_memory(&bytes).unwrap().to_rgb8()
+            }
+        };
+
+        let plain = convert("false").await;
+        let stamped = convert("true").await;
+        assert_eq!(stamped.dimensions(), plain.dimensions());
+        assert_ne!(stamped.get_pixel(1, 62), plain.get_pixel(1, 62));
+        assert_eq!(stamped.get_pixel(62, 1), plain.get_pixel(62, 1));
+
+        // Without ALLOW_DEBUG_STAMP the parameter is refused
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("debug_stamp", "true");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn pipelines_reject_the_parameters_they_replace() {
+        let base = spawn_app(Config::default()).await;
+        for (name, value) in [("upscale", "deny"), ("crop_clamp", "true"), ("w", "2")] {
+            let form = Form::new()
+                .part("image", image_part())
+                .text("pipeline", "resize(2)")
+                .text(name, value);
+            let response = upload(&base, form).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(
+                body["error"],
+                format!(
+                    "invalid pipeline: {} cannot be combined with a pipeline",
+                    name
+                )
+            );
+        }
+
+        let form = Form::new()
+            .part("image", Part::bytes(png(4, 4, [255, 0, 0])))
+            .text("pipeline", "resize(2)");
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let output = image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(output.dimensions(), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn cached_responses_keep_the_origin_last_modified() {
+        const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let dir = temp_dir("disk-cache-last-modified");
+        let base = spawn_app(Config {
+            disk_cache_dir: Some(dir.clone()),
+            ..Config::default()
+        })
+        .await;
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let origin = serve(Router::new().route(
+            "/image.png",
+            get(move || {
+                counter.fetch_add(1, AtomicOrdering::SeqCst);
+                async move {
+                    (
+                        [
+                            (header::CONTENT_TYPE, "image/png"),
+                            (header::LAST_MODIFIED, LAST_MODIFIED),
+                        ],
+                        png(8, 8, [0, 128, 255]),
+                    )
+                }
+            }),
+        ))
+        .await;
+        let url = format!(
+            "{}/url?url={}/image.png&w=4&output_format=png",
+            base, origin
+        );
+
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::LAST_MODIFIED], LAST_MODIFIED);
+        }
+        assert_eq!(hits.load(AtomicOrdering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_unknown_quality() {
+        let base = spawn_app(Config::default()).await;
+        let form = Form::new()
+            .part("image", image_part())
+            .text("quality", "hgih")
+            .text("output_format", "jpeg");
+
+        let response = upload(&base, form).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "invalid encoder option: unknown quality: hgih"
+        );
+    }
+
+    #[tokio::test]
+    async fn fractional_crops_need_all_four_fractions_alone() {
+        let base = spawn_app(Config::default()).await;
+        let crop = |fields: &[(&'static str, &'static str)]| {
+            let mut form = Form::new().part("image", Part::bytes(png(8, 8, [255, 0, 0])));
+            for &(name, value) in fields {
+                form = form.text(name, value);
+            }
+            upload(&base, form)
+        };
+        let full = [
+            ("crop_xf", "0.25"),
+            ("crop_yf", "0.25"),
+            ("crop_wf", "0.5"),
+            ("crop_hf", "0.5"),
+        ];
+
+        let response = crop(&full).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["X-Image-Operations"],
+            "crop:2,2,4x4;encode:png"
+        );
+
+        let mixed = [full[0], full[1], full[2], full[3], ("crop_w", "4")];
+        for fields in [&full[..1], &full[..3], &mixed[..]] {
+            let response = crop(fields).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", fields);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_form_fields_are_rejected() {
+        let base = spawn_app(Config::default()).await;
+        for (name, value, error) in [
+            ("upscale", "dney", "invalid upscale mode: dney"),
+            ("w", "wide", "invalid value for w: wide"),
+            ("strict", "maybe", "invalid value for strict: maybe"),
+            ("debug_stamp", "yes", "invalid value for debug_stamp: yes"),
+        ] {
+            let form = Form::new().part("image", image_part()).text(name, value);
+            let response = upload(&base, form).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{}={}",
+                name,
+                value
+            );
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["error"], error);
+        }
+    }
 }
@@ -1,163 +1,4301 @@
-use bytes::Bytes;
-use image::{DynamicImage, ImageFormat, codecs::jpeg::JpegEncoder, imageops};
-use std::io::Cursor;
+use bytes::{Bytes, BytesMut};
+use image::{
+    ColorType, DynamicImage, ExtendedColorType, Frame, GenericImage, GenericImageView, GrayImage,
+    ImageBuffer, ImageError, ImageFormat, ImageReader, Luma, LumaA, Rgba, RgbaImage,
+    codecs::{
+        avif::AvifEncoder,
+        bmp::BmpEncoder,
+        gif::GifEncoder,
+        ico::{IcoEncoder, IcoFrame},
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilterType, PngEncoder},
+        webp::WebPEncoder,
+    },
+    error::{DecodingError, ImageFormatHint, UnsupportedErrorKind},
+    imageops,
+};
+use imageproc::geometric_transformations::{Interpolation, Projection, warp_into};
+use reqwest::{Client, RequestBuilder, Url, header, redirect};
+use serde::{
+    Deserialize, Serialize,
+    de::{self, IntoDeserializer},
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    io::{Cursor, Write},
+    ops::Range,
+    path::Path,
+    str::FromStr,
+};
+use tokio::fs;
+use zune_jpeg::{
+    JpegDecoder,
+    zune_core::{colorspace::ColorSpace, options::DecoderOptions},
+};
 
-use crate::error::AppError;
+use crate::error::{AppError, RedirectNotAllowed};
+
+const DEFAULT_JPEG_QUALITY: u8 = 80;
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+const DEFAULT_WEBP_QUALITY: u8 = 75;
+/// Encoder effort on the 0-10 scale, where higher is slower but smaller. 6
+/// maps to AVIF speed 4, the encoder's own balanced default.
+const DEFAULT_EFFORT: u8 = 6;
+const MAX_EFFORT: u8 = 10;
+
+/// Image formats the service can encode to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Bmp,
+    Gif,
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::WebP => ImageFormat::WebP,
+            OutputFormat::Bmp => ImageFormat::Bmp,
+            OutputFormat::Gif => ImageFormat::Gif,
+            OutputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        self.image_format().to_mime_type()
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        self != OutputFormat::Jpeg
+    }
+
+    /// Maps a decoded image format to the matching output format, if the
+    /// service can encode it.
+    pub fn from_image_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Png => Some(OutputFormat::Png),
+            ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
+            ImageFormat::WebP => Some(OutputFormat::WebP),
+            ImageFormat::Bmp => Some(OutputFormat::Bmp),
+            ImageFormat::Gif => Some(OutputFormat::Gif),
+            ImageFormat::Avif => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            "bmp" => Ok(OutputFormat::Bmp),
+            "gif" => Ok(OutputFormat::Gif),
+            "avif" => Ok(OutputFormat::Avif),
+            _ => Err(AppError::UnsupportedOutputFormat(s.to_string())),
+        }
+    }
+}
+
+/// Named quality levels, mapped to a numeric quality per output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    High,
+    Max,
+    /// Chosen from the image content when encoding, see [`auto_quality`].
+    Auto,
+}
+
+/// Requested encoder quality, either a number or a preset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Quality {
+    Value(u8),
+    Preset(QualityPreset),
+}
+
+impl Quality {
+    /// Numeric quality to encode `format` with, or `None` when it depends on
+    /// the image content.
+    pub fn for_format(self, format: OutputFormat) -> Option<u8> {
+        let preset = match self {
+            Quality::Value(value) => return Some(value),
+            Quality::Preset(preset) => preset,
+        };
+        match (format, preset) {
+            (_, QualityPreset::Auto) => None,
+            (OutputFormat::WebP, QualityPreset::Low) => Some(60),
+            (OutputFormat::WebP, QualityPreset::Medium) => Some(75),
+            (OutputFormat::WebP, QualityPreset::High) => Some(90),
+            (_, QualityPreset::Low) => Some(50),
+            (_, QualityPreset::Medium) => Some(70),
+            (_, QualityPreset::High) => Some(85),
+            (_, QualityPreset::Max) => Some(100),
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // Saturated rather than rejected, so out-of-range values are clamped
+        // and reported like any other
+        if let Ok(value) = s.parse::<i64>() {
+            return Ok(Quality::Value(value.clamp(0, i64::from(u8::MAX)) as u8));
+        }
+        QualityPreset::deserialize(s.to_lowercase().into_deserializer())
+            .map(Quality::Preset)
+            .map_err(|_: de::value::Error| {
+                AppError::InvalidEncoderOption(format!("unknown quality: {}", s))
+            })
+    }
+}
+
+/// Encoder settings for a single output, parsed once from the request.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub format: OutputFormat,
+    /// Requested quality for lossy formats; clamped to 1-100 when encoding.
+    pub quality: Option<u8>,
+    pub png_compression: CompressionType,
+    pub png_filter: PngFilterType,
+    /// Encoder effort from 0 (fastest) to 10 (smallest output), for formats
+    /// whose encoder can trade speed for size.
+    pub effort: u8,
+    /// Whether PNG output is losslessly recompressed with oxipng after
+    /// encoding; only applied to buffered outputs.
+    pub optimize: bool,
+    pub jpeg_backend: JpegBackend,
+    /// Whether the quality is picked from the image content when no
+    /// explicit quality is set.
+    pub auto_quality: bool,
+    /// Whether JPEG output switches to PNG for images with meaningful
+    /// transparency, see [`EncodeOptions::resolve_format`].
+    pub png_if_transparent: bool,
+    /// Quality of the alpha channel in lossy WebP output, defaulting to the
+    /// color quality.
+    pub webp_alpha_quality: Option<u8>,
+}
+
+impl EncodeOptions {
+    pub fn new(format: OutputFormat) -> Self {
+        EncodeOptions {
+            format,
+            quality: None,
+            png_compression: CompressionType::default(),
+            png_filter: PngFilterType::default(),
+            effort: DEFAULT_EFFORT,
+            optimize: false,
+            jpeg_backend: JpegBackend::default(),
+            auto_quality: false,
+            png_if_transparent: false,
+            webp_alpha_quality: None,
+        }
+    }
+
+    /// Settings with the output format settled for `img`, which must be
+    /// called before transparency is flattened away: JPEG becomes PNG when
+    /// requested for transparent images.
+    pub fn resolve_format(&self, img: &DynamicImage) -> EncodeOptions {
+        let mut options = self.clone();
+        if options.png_if_transparent
+            && options.format == OutputFormat::Jpeg
+            && has_transparency(img)
+        {
+            options.format = OutputFormat::Png;
+        }
+        options.png_if_transparent = false;
+        options
+    }
+
+    /// Settings for encoding `img`, with an automatic quality resolved from
+    /// its content.
+    pub fn for_image(&self, img: &DynamicImage) -> EncodeOptions {
+        let mut options = self.clone();
+        if options.auto_quality
+            && options.quality.is_none()
+            && options.effective_quality().0.is_some()
+        {
+            options.quality = Some(auto_quality(img));
+        }
+        options.auto_quality = false;
+        options
+    }
+
+    /// Quality the encoder will use, if the format has one, and whether the
+    /// requested quality was out of range and had to be clamped.
+    pub fn effective_quality(&self) -> (Option<u8>, bool) {
+        match self.format {
+            OutputFormat::Jpeg | OutputFormat::Avif => {
+                let default = match self.format {
+                    OutputFormat::Avif => DEFAULT_AVIF_QUALITY,
+                    _ => DEFAULT_JPEG_QUALITY,
+                };
+                let requested = self.quality.unwrap_or(default);
+                let quality = requested.clamp(1, 100);
+                (Some(quality), quality != requested)
+            }
+            OutputFormat::WebP if self.lossy_webp() => {
+                let requested = self.quality.unwrap_or(DEFAULT_WEBP_QUALITY);
+                let quality = requested.clamp(1, 100);
+                (Some(quality), quality != requested)
+            }
+            _ => (None, false),
+        }
+    }
+
+    /// Whether WebP output is encoded lossily with libwebp, which takes a
+    /// quality, instead of losslessly.
+    fn lossy_webp(&self) -> bool {
+        cfg!(feature = "libwebp")
+            && (self.quality.is_some() || self.auto_quality || self.webp_alpha_quality.is_some())
+    }
+}
+
+/// Quality range that [`auto_quality`] picks from.
+const AUTO_QUALITY_RANGE: (u8, u8) = (70, 90);
+
+/// Picks a lossy quality suited to the image content. Photographs hide
+/// compression artifacts in their detail, so they get lower qualities, while
+/// flat graphics with few colors show them around every edge and get higher
+/// ones. Colors are counted on a thumbnail at 5 bits per channel.
+pub fn auto_quality(img: &DynamicImage) -> u8 {
+    let thumbnail = img.thumbnail(256, 256).to_rgb8();
+    let colors = thumbnail
+        .pixels()
+        .map(|pixel| pixel.0.map(|channel| channel >> 3))
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+    // 64 colors or fewer reads as a graphic, 4096 or more as a photograph
+    let photographic = (((colors.max(1) as f32).log2() - 6.0) / 6.0).clamp(0.0, 1.0);
+    let (low, high) = AUTO_QUALITY_RANGE;
+    (f32::from(high) - f32::from(high - low) * photographic).round() as u8
+}
+
+/// Converts an image to a color type the JPEG encoder accepts: 8-bit luma for
+/// grayscale images and 8-bit RGB otherwise, dropping any alpha channel.
+fn jpeg_compatible(img: DynamicImage) -> DynamicImage {
+    match img.color() {
+        ColorType::L8 | ColorType::Rgb8 => img,
+        ColorType::La8 | ColorType::L16 | ColorType::La16 => {
+            DynamicImage::ImageLuma8(img.to_luma8())
+        }
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+/// Converts high bit depth images to their 8-bit equivalent for encoders that
+/// only take 8 bits per channel. PNG keeps the full depth instead.
+fn eight_bit(img: DynamicImage) -> DynamicImage {
+    match img.color() {
+        ColorType::L16 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La16 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb16 | ColorType::Rgb32F => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba16 | ColorType::Rgba32F => DynamicImage::ImageRgba8(img.to_rgba8()),
+        _ => img,
+    }
+}
+
+/// Packs the given images into a multi-resolution ICO file.
+pub fn encode_ico(images: &[DynamicImage]) -> Result<Vec<u8>, AppError> {
+    let frames = images
+        .iter()
+        .map(|img| {
+            let rgba = img.to_rgba8();
+            IcoFrame::as_png(
+                rgba.as_raw(),
+                rgba.width(),
+                rgba.height(),
+                ExtendedColorType::Rgba8,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut buffer = Vec::new();
+    IcoEncoder::new(&mut buffer).encode_images(&frames)?;
+    Ok(buffer)
+}
+
+pub fn parse_png_compression(s: &str) -> Result<CompressionType, AppError> {
+    match s.trim().to_lowercase().as_str() {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        _ => Err(AppError::InvalidEncoderOption(format!(
+            "unknown png compression: {}",
+            s
+        ))),
+    }
+}
+
+/// Parses an encoder effort: a number from 0 to 10, or `fast` (0) or `slow`
+/// (10).
+pub fn parse_effort(s: &str) -> Result<u8, AppError> {
+    let effort = match s.trim().to_lowercase().as_str() {
+        "fast" => 0,
+        "slow" => MAX_EFFORT,
+        other => other.parse().unwrap_or(u8::MAX),
+    };
+    if effort > MAX_EFFORT {
+        return Err(AppError::InvalidEncoderOption(format!(
+            "unknown effort: {}",
+            s
+        )));
+    }
+    Ok(effort)
+}
+
+/// Library used to encode JPEG output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JpegBackend {
+    /// The `image` crate's baseline encoder.
+    #[default]
+    Default,
+    /// mozjpeg, which compresses better at the same quality; requires the
+    /// `mozjpeg` feature.
+    Mozjpeg,
+}
+
+impl FromStr for JpegBackend {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        JpegBackend::deserialize(s.trim().to_lowercase().into_deserializer()).map_err(
+            |_: de::value::Error| {
+                AppError::InvalidEncoderOption(format!("unknown jpeg encoder: {}", s))
+            },
+        )
+    }
+}
+
+pub fn parse_png_filter(s: &str) -> Result<PngFilterType, AppError> {
+    match s.trim().to_lowercase().as_str() {
+        "none" => Ok(PngFilterType::NoFilter),
+        "sub" => Ok(PngFilterType::Sub),
+        "up" => Ok(PngFilterType::Up),
+        "avg" => Ok(PngFilterType::Avg),
+        "paeth" => Ok(PngFilterType::Paeth),
+        "adaptive" => Ok(PngFilterType::Adaptive),
+        _ => Err(AppError::InvalidEncoderOption(format!(
+            "unknown png filter: {}",
+            s
+        ))),
+    }
+}
 
 pub struct ProcessedImage {
     pub bytes: Vec<u8>,
     pub mime_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// Quality the encoder actually used, for formats that take one.
+    pub quality: Option<u8>,
+    /// Whether the requested quality was out of range and had to be clamped.
+    pub quality_clamped: bool,
+}
+
+/// How `w` and `h` are applied when resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Resize to exactly `w`x`h`, or keep the aspect ratio when only one is given.
+    #[default]
+    Scale,
+    /// Scale to cover `w`x`h` while keeping the aspect ratio, then crop the
+    /// overflow evenly from both sides. Also accepted as `fill`, imgproxy's name.
+    #[serde(alias = "fill")]
+    Cover,
+}
+
+impl FromStr for ResizeMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResizeMode::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidResizeMode(s.to_string()))
+    }
+}
+
+/// How a resize larger than the source image is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpscaleMode {
+    /// Resize to the requested size.
+    #[default]
+    Allow,
+    /// Reject the request.
+    Deny,
+    /// Shrink the requested size, keeping its aspect ratio, to fit the source.
+    Clamp,
+}
+
+impl FromStr for UpscaleMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        UpscaleMode::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidUpscaleMode(s.to_string()))
+    }
+}
+
+/// How right-angle rotations reach a JPEG output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RotateMode {
+    /// Rotate the pixels.
+    #[default]
+    Pixels,
+    /// Write an EXIF orientation tag instead, leaving the pixels as they are.
+    Exif,
+}
+
+impl FromStr for RotateMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RotateMode::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidRotateMode(s.to_string()))
+    }
+}
+
+/// Interpolation used when resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Nearest,
+    #[serde(alias = "bilinear")]
+    Triangle,
+    #[serde(alias = "bicubic")]
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// Default for enlarging, which stays sharp without ringing much.
+    pub const UPSCALE_DEFAULT: ResizeFilter = ResizeFilter::CatmullRom;
+    /// Default for shrinking, which keeps the most detail.
+    pub const DOWNSCALE_DEFAULT: ResizeFilter = ResizeFilter::Lanczos3;
+
+    /// Picks the filter for resizing `source` to `target`, falling back to
+    /// the defaults. Sizes are compared by area, as an exact resize may
+    /// stretch one side while shrinking the other.
+    pub fn for_resize(
+        source: (u32, u32),
+        target: (u32, u32),
+        upscale: Option<ResizeFilter>,
+        downscale: Option<ResizeFilter>,
+    ) -> ResizeFilter {
+        let area = |(width, height): (u32, u32)| u64::from(width) * u64::from(height);
+        if area(target) > area(source) {
+            upscale.unwrap_or(ResizeFilter::UPSCALE_DEFAULT)
+        } else {
+            downscale.unwrap_or(ResizeFilter::DOWNSCALE_DEFAULT)
+        }
+    }
+
+    pub fn filter_type(self) -> imageops::FilterType {
+        match self {
+            ResizeFilter::Nearest => imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => imageops::FilterType::CatmullRom,
+            ResizeFilter::Gaussian => imageops::FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+impl fmt::Display for ResizeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmullrom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResizeFilter::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidResizeFilter(s.to_string()))
+    }
+}
+
+/// Which way [`round_to_multiple`] rounds sizes that are not a multiple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoundMode {
+    #[default]
+    Up,
+    Down,
+    Nearest,
+}
+
+impl FromStr for RoundMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RoundMode::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidRoundMode(s.to_string()))
+    }
+}
+
+/// Rounds `size` to a multiple of `multiple`, never going below one multiple.
+pub fn round_to_multiple(size: u32, multiple: u32, mode: RoundMode) -> u32 {
+    let down = size / multiple * multiple;
+    let rounded = match mode {
+        RoundMode::Down => down,
+        RoundMode::Up if down == size => size,
+        RoundMode::Up => down.saturating_add(multiple),
+        RoundMode::Nearest if size - down >= multiple.div_ceil(2) => down.saturating_add(multiple),
+        RoundMode::Nearest => down,
+    };
+    rounded.max(multiple)
+}
+
+/// Applies `mode` to a resize from `source` to `target`, returning the size to
+/// resize to.
+pub fn upscale_target(
+    mode: UpscaleMode,
+    (target_w, target_h): (u32, u32),
+    (source_w, source_h): (u32, u32),
+) -> Result<(u32, u32), AppError> {
+    if target_w <= source_w && target_h <= source_h {
+        return Ok((target_w, target_h));
+    }
+    match mode {
+        UpscaleMode::Allow => Ok((target_w, target_h)),
+        UpscaleMode::Deny => Err(AppError::UpscaleNotAllowed {
+            width: target_w,
+            height: target_h,
+            source_width: source_w,
+            source_height: source_h,
+        }),
+        UpscaleMode::Clamp => {
+            let scale = (f64::from(source_w) / f64::from(target_w))
+                .min(f64::from(source_h) / f64::from(target_h));
+            let scaled = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+            Ok((scaled(target_w), scaled(target_h)))
+        }
+    }
+}
+
+/// Anchor used to position a crop window when no explicit offset is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl FromStr for Gravity {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Gravity::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidGravity(s.to_string()))
+    }
+}
+
+/// Redirects followed before a fetch is abandoned, matching reqwest's default.
+const MAX_REDIRECTS: usize = 10;
+
+pub fn build_http_client(user_agent: &str, allowed_hosts: &[String]) -> Result<Client, AppError> {
+    let mut builder = Client::builder().user_agent(user_agent);
+    if !allowed_hosts.is_empty() {
+        // Redirects must not lead outside the allowlist either
+        let allowed_hosts = allowed_hosts.to_vec();
+        builder = builder.redirect(redirect::Policy::custom(move |attempt| {
+            let host = attempt.url().host_str().unwrap_or_default().to_string();
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if !allowed_hosts
+                .iter()
+                .any(|pattern| host_matches(&host, pattern))
+            {
+                attempt.error(RedirectNotAllowed(host))
+            } else {
+                attempt.follow()
+            }
+        }));
+    }
+    Ok(builder.build()?)
+}
+
+/// Rejects URLs whose host is not on the allowlist. An empty allowlist allows
+/// every host.
+pub fn check_source_host(url: &str, allowed_hosts: &[String]) -> Result<(), AppError> {
+    if allowed_hosts.is_empty() {
+        return Ok(());
+    }
+    let url = Url::parse(url).map_err(|_| AppError::InvalidImageSource("invalid image url"))?;
+    let host = url
+        .host_str()
+        .ok_or(AppError::InvalidImageSource("image url has no host"))?;
+    if allowed_hosts
+        .iter()
+        .any(|pattern| host_matches(host, pattern))
+    {
+        Ok(())
+    } else {
+        Err(AppError::SourceHostNotAllowed(host.to_string()))
+    }
+}
+
+/// Whether `host` matches an allowlist entry. `*.example.com` matches any
+/// subdomain of `example.com`, but not `example.com` itself.
+pub(crate) fn host_matches(host: &str, pattern: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let pattern = pattern.trim().trim_end_matches('.').to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/// Perceptual hashing algorithms supported by [`perceptual_hash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Average hash: each bit tells whether a pixel is brighter than the mean.
+    AHash,
+    /// Difference hash: each bit tells whether a pixel is brighter than its
+    /// right-hand neighbour.
+    DHash,
+    /// DCT hash: each bit tells whether a low-frequency coefficient is above
+    /// the median.
+    #[default]
+    PHash,
+}
+
+/// Cache validators sent by an image origin, used to revalidate a cached copy.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Default)]
+pub struct FetchedImage {
+    pub bytes: Bytes,
+    pub validators: Validators,
+}
+
+pub enum ConditionalFetch {
+    Modified(FetchedImage),
+    NotModified,
+}
+
+/// Fetches an image along with the cache validators the origin sent for it.
+pub async fn fetch_image_bytes_from_url(
+    client: &Client,
+    url: &str,
+    referer: Option<&str>,
+) -> Result<FetchedImage, AppError> {
+    match fetch_image_conditional(client, url, referer, None).await? {
+        ConditionalFetch::Modified(fetched) => Ok(fetched),
+        ConditionalFetch::NotModified => Err(AppError::ImageFetchError(
+            "server responded with 304 Not Modified to an unconditional request".to_string(),
+        )),
+    }
+}
+
+/// Fetches an image, sending `If-None-Match`/`If-Modified-Since` when
+/// validators from a previous fetch are given.
+pub async fn fetch_image_conditional(
+    client: &Client,
+    url: &str,
+    referer: Option<&str>,
+    validators: Option<&Validators>,
+) -> Result<ConditionalFetch, AppError> {
+    let mut request = with_referer(client.get(url), referer);
+    if let Some(validators) = validators {
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().await?;
+    if validators.is_some() && response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(ConditionalFetch::NotModified);
+    }
+    if !response.status().is_success() {
+        return Err(AppError::ImageFetchError(format!(
+            "failed to fetch image: server responded with {}",
+            response.status()
+        )));
+    }
+
+    let header_string = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header_string(header::ETAG),
+        last_modified: header_string(header::LAST_MODIFIED),
+    };
+    let bytes = response.bytes().await?;
+    Ok(ConditionalFetch::Modified(FetchedImage {
+        bytes,
+        validators,
+    }))
+}
+
+/// Fetches at most `max_bytes` from the start of the resource, using a range
+/// request when the server supports it and truncating the body otherwise.
+pub async fn fetch_image_prefix_from_url(
+    client: &Client,
+    url: &str,
+    referer: Option<&str>,
+    max_bytes: usize,
+) -> Result<Bytes, AppError> {
+    let request = client
+        .get(url)
+        .header(header::RANGE, format!("bytes=0-{}", max_bytes - 1));
+    let mut response = with_referer(request, referer).send().await?;
+    if !response.status().is_success() {
+        return Err(AppError::ImageFetchError(format!(
+            "failed to fetch image: server responded with {}",
+            response.status()
+        )));
+    }
+
+    // Servers that ignore the range header send the whole body, so stop
+    // reading once enough bytes have arrived.
+    let mut buffer = BytesMut::with_capacity(max_bytes);
+    while buffer.len() < max_bytes {
+        match response.chunk().await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    buffer.truncate(max_bytes);
+    Ok(buffer.freeze())
+}
+
+/// Reads an image from `path` relative to `base_dir`, refusing paths that
+/// resolve outside of it (e.g. via `..` or symlinks).
+pub async fn read_local_image(base_dir: &Path, path: &str) -> Result<Bytes, AppError> {
+    let not_found = |err: std::io::Error| match err.kind() {
+        std::io::ErrorKind::NotFound => AppError::LocalFileNotFound(path.to_string()),
+        _ => AppError::IoError(err),
+    };
+
+    let base_dir = fs::canonicalize(base_dir).await?;
+    let resolved = fs::canonicalize(base_dir.join(path))
+        .await
+        .map_err(not_found)?;
+    if !resolved.starts_with(&base_dir) {
+        return Err(AppError::LocalFileAccessDenied(
+            "path escapes the local files directory",
+        ));
+    }
+
+    Ok(Bytes::from(fs::read(&resolved).await.map_err(not_found)?))
+}
+
+fn with_referer(request: RequestBuilder, referer: Option<&str>) -> RequestBuilder {
+    match referer {
+        Some(referer) => request.header(header::REFERER, referer),
+        None => request,
+    }
+}
+
+/// Checks, from the image header alone, that the image's format is in
+/// `allowed_formats`, when given, and that it has pixels and its dimensions
+/// are within the given limits, and returns them.
+pub fn check_source_size(
+    bytes: &[u8],
+    max_dimension: u32,
+    max_pixels: u64,
+    allowed_formats: Option<&[ImageFormat]>,
+) -> Result<(u32, u32), AppError> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    if let (Some(allowed_formats), Some(format)) = (allowed_formats, reader.format())
+        && !allowed_formats.contains(&format)
+    {
+        return Err(AppError::InputFormatNotAllowed(
+            format!("{:?}", format).to_lowercase(),
+        ));
+    }
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|err| decode_error(err, bytes))?;
+    check_dimensions(width, height, max_dimension, max_pixels)?;
+    Ok((width, height))
+}
+
+fn check_dimensions(
+    width: u32,
+    height: u32,
+    max_dimension: u32,
+    max_pixels: u64,
+) -> Result<(), AppError> {
+    if width == 0 || height == 0 {
+        return Err(AppError::InvalidImageDimensions { width, height });
+    }
+    if width > max_dimension
+        || height > max_dimension
+        || u64::from(width) * u64::from(height) > max_pixels
+    {
+        return Err(AppError::SourceImageTooLarge { width, height });
+    }
+    Ok(())
+}
+
+/// Decodes an image after checking its format and that its dimensions are
+/// within the given limits, rejecting images without pixels.
+pub fn decode_image(
+    bytes: &[u8],
+    max_dimension: u32,
+    max_pixels: u64,
+    allowed_formats: Option<&[ImageFormat]>,
+) -> Result<DynamicImage, AppError> {
+    let (width, height) = check_source_size(bytes, max_dimension, max_pixels, allowed_formats)?;
+    let img =
+        if image::guess_format(bytes).ok() == Some(ImageFormat::Jpeg) && is_cmyk_jpeg(bytes) {
+            decode_cmyk_jpeg(bytes, width, height)
+        } else {
+            image::load_from_memory(bytes)
+        }
+        .map_err(|err| decode_error(err, bytes))?;
+    // Resize and crop math divides by the source dimensions
+    if img.width() == 0 || img.height() == 0 {
+        return Err(AppError::InvalidImageDimensions {
+            width: img.width(),
+            height: img.height(),
+        });
+    }
+    Ok(img)
+}
+
+/// Decodes one page, counted from 1, of a multi-page TIFF; images in other
+/// formats have a single page.
+pub fn decode_image_page(
+    bytes: &[u8],
+    page: u32,
+    max_dimension: u32,
+    max_pixels: u64,
+    allowed_formats: Option<&[ImageFormat]>,
+) -> Result<DynamicImage, AppError> {
+    if page == 0 {
+        return Err(AppError::InvalidPage(
+            "pages are counted from 1".to_string(),
+        ));
+    }
+    if page == 1 {
+        return decode_image(bytes, max_dimension, max_pixels, allowed_formats);
+    }
+    check_source_size(bytes, max_dimension, max_pixels, allowed_formats)?;
+    if image::guess_format(bytes).ok() != Some(ImageFormat::Tiff) {
+        return Err(page_out_of_range(page, 1));
+    }
+
+    let tiff_error = |err: tiff::TiffError| {
+        AppError::from(ImageError::Decoding(DecodingError::new(
+            ImageFormat::Tiff.into(),
+            err,
+        )))
+    };
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(bytes)).map_err(tiff_error)?;
+    let mut pages = 1;
+    while pages < page && decoder.more_images() {
+        decoder.next_image().map_err(tiff_error)?;
+        pages += 1;
+    }
+    if pages < page {
+        return Err(page_out_of_range(page, pages));
+    }
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    check_dimensions(width, height, max_dimension, max_pixels)?;
+
+    use tiff::{ColorType as TiffColor, decoder::DecodingResult};
+    let colortype = decoder.colortype().map_err(tiff_error)?;
+    let img = match (colortype, decoder.read_image().map_err(tiff_error)?) {
+        (TiffColor::Gray(8), DecodingResult::U8(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        }
+        (TiffColor::Gray(16), DecodingResult::U16(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma16)
+        }
+        (TiffColor::GrayA(8), DecodingResult::U8(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8)
+        }
+        (TiffColor::GrayA(16), DecodingResult::U16(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA16)
+        }
+        (TiffColor::RGB(8), DecodingResult::U8(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        (TiffColor::RGB(16), DecodingResult::U16(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb16)
+        }
+        (TiffColor::RGBA(8), DecodingResult::U8(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        }
+        (TiffColor::RGBA(16), DecodingResult::U16(pixels)) => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba16)
+        }
+        (colortype, _) => {
+            return Err(AppError::UnsupportedInputFormat(format!(
+                "tiff page with {:?} pixels",
+                colortype
+            )));
+        }
+    };
+    img.ok_or_else(|| {
+        std::io::Error::other(format!("tiff page {} is shorter than its dimensions", page)).into()
+    })
+}
+
+fn page_out_of_range(page: u32, pages: u32) -> AppError {
+    AppError::InvalidPage(format!(
+        "page {} is out of range, the image has {} page{}",
+        page,
+        pages,
+        if pages == 1 { "" } else { "s" }
+    ))
+}
+
+/// Reports inputs in formats this build cannot decode as such, naming the
+/// format when it can be told from the bytes, instead of as a processing
+/// failure.
+fn decode_error(err: ImageError, bytes: &[u8]) -> AppError {
+    let ImageError::Unsupported(unsupported) = &err else {
+        return err.into();
+    };
+    let UnsupportedErrorKind::Format(hint) = unsupported.kind() else {
+        return err.into();
+    };
+    match hint {
+        ImageFormatHint::Exact(format) => {
+            AppError::UnsupportedInputFormat(format!("{:?}", format).to_lowercase())
+        }
+        _ => match sniff_unsupported_format(bytes) {
+            Some(name) => AppError::UnsupportedInputFormat(name.to_string()),
+            None => AppError::UnrecognizedImageFormat,
+        },
+    }
+}
+
+/// Walks the marker segments of a JPEG up to its first scan, yielding each
+/// marker with its payload.
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    jpeg_segment_ranges(bytes).map(|(marker, range)| (marker, &bytes[range.start + 4..range.end]))
+}
+
+/// Like [`jpeg_segments`], but yields the byte range of each whole segment,
+/// marker included.
+fn jpeg_segment_ranges(bytes: &[u8]) -> impl Iterator<Item = (u8, Range<usize>)> + '_ {
+    let mut offset = if bytes.starts_with(&[0xFF, 0xD8]) {
+        2
+    } else {
+        bytes.len()
+    };
+    std::iter::from_fn(move || {
+        let &[0xFF, marker, len_hi, len_lo] = bytes.get(offset..offset + 4)? else {
+            return None;
+        };
+        // Entropy-coded data follows the start of scan
+        if marker == 0xDA {
+            return None;
+        }
+        // The length counts its own two bytes
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+        if len < 2 || offset + 2 + len > bytes.len() {
+            return None;
+        }
+        let range = offset..offset + 2 + len;
+        offset = range.end;
+        Some((marker, range))
+    })
+}
+
+/// The EXIF orientation tag value for a clockwise rotation by a multiple of
+/// 90 degrees.
+pub fn exif_orientation(degrees: u32) -> u16 {
+    match degrees % 360 {
+        90 => 6,
+        180 => 3,
+        270 => 8,
+        _ => 1,
+    }
+}
+
+/// Replaces the EXIF data of a JPEG with a minimal segment holding only the
+/// given orientation tag, so viewers rotate the image on display. Any other
+/// EXIF tags are dropped along with the old segment.
+pub fn set_jpeg_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(jpeg.len());
+    let mut copied = 0;
+    for (_, range) in jpeg_segment_ranges(jpeg).filter(|(marker, range)| {
+        *marker == 0xE1 && jpeg[range.start + 4..range.end].starts_with(b"Exif\0\0")
+    }) {
+        stripped.extend_from_slice(&jpeg[copied..range.start]);
+        copied = range.end;
+    }
+    stripped.extend_from_slice(&jpeg[copied..]);
+
+    // EXIF belongs after the JFIF header, if there is one
+    let insert_at = jpeg_segment_ranges(&stripped)
+        .take_while(|(marker, _)| *marker == 0xE0)
+        .last()
+        .map_or(2, |(_, range)| range.end);
+
+    let mut output = Vec::with_capacity(stripped.len() + 36);
+    output.extend_from_slice(&stripped[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1, 0, 34]);
+    output.extend_from_slice(b"Exif\0\0");
+    // Big-endian TIFF header with the first directory right after it
+    output.extend_from_slice(b"MM\0*");
+    output.extend_from_slice(&8u32.to_be_bytes());
+    // One entry: the orientation as a single SHORT, padded to four bytes
+    output.extend_from_slice(&1u16.to_be_bytes());
+    output.extend_from_slice(&0x0112u16.to_be_bytes());
+    output.extend_from_slice(&3u16.to_be_bytes());
+    output.extend_from_slice(&1u32.to_be_bytes());
+    output.extend_from_slice(&orientation.to_be_bytes());
+    output.extend_from_slice(&[0, 0]);
+    // No further directories
+    output.extend_from_slice(&0u32.to_be_bytes());
+    output.extend_from_slice(&stripped[insert_at..]);
+    output
+}
+
+/// Whether `bytes` is a JPEG with four color components, i.e. CMYK or YCCK
+/// as written by print software. These are poorly supported by viewers, so
+/// they are converted to RGB by [`decode_cmyk_jpeg`].
+pub fn is_cmyk_jpeg(bytes: &[u8]) -> bool {
+    jpeg_segments(bytes)
+        // Start of frame, except the DHT, JPG and DAC markers sharing the range
+        .find(|(marker, _)| matches!(marker, 0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC)))
+        // Precision, height and width come before the component count
+        .is_some_and(|(_, frame)| frame.get(5) == Some(&4))
+}
+
+/// The color transform from a JPEG's Adobe APP14 segment, if it has one: 0
+/// for CMYK and 2 for YCCK in four-component images.
+fn adobe_transform(bytes: &[u8]) -> Option<u8> {
+    jpeg_segments(bytes)
+        .find(|(marker, payload)| *marker == 0xEE && payload.starts_with(b"Adobe"))
+        // Version and two flag words come before the transform
+        .and_then(|(_, payload)| payload.get(11).copied())
+}
+
+/// Decodes a CMYK or YCCK JPEG of the given dimensions to RGB.
+///
+/// Files carrying an Adobe APP14 segment, which includes every YCCK file,
+/// store inverted channels, where 255 means no ink, as Photoshop writes them.
+/// Other files store plain ink amounts. YCCK keeps C, M and Y as YCbCr, which
+/// is converted back first.
+fn decode_cmyk_jpeg(bytes: &[u8], width: u32, height: u32) -> Result<DynamicImage, ImageError> {
+    let jpeg_error = |err| ImageError::Decoding(DecodingError::new(ImageFormat::Jpeg.into(), err));
+    let mut decoder = JpegDecoder::new(bytes);
+    decoder.decode_headers().map_err(jpeg_error)?;
+    // Asking for the input colorspace skips the decoder's own conversion,
+    // which assumes Adobe inversion
+    let colorspace = decoder.get_input_colorspace().unwrap_or(ColorSpace::CMYK);
+    decoder.set_options(
+        DecoderOptions::default()
+            .jpeg_set_out_colorspace(colorspace)
+            .set_max_width(width as usize)
+            .set_max_height(height as usize),
+    );
+    let raw = decoder.decode().map_err(jpeg_error)?;
+    let inverted = adobe_transform(bytes).is_some();
+
+    let rgb = raw
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let [c, m, y, k] = match colorspace {
+                ColorSpace::YCCK => {
+                    let [r, g, b] = ycbcr_to_rgb(pixel[0], pixel[1], pixel[2]);
+                    [255 - r, 255 - g, 255 - b, pixel[3]]
+                }
+                _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            };
+            // Channels as the share of light let through, from 0 to 255
+            let [c, m, y, k] = if inverted {
+                [c, m, y, k]
+            } else {
+                [255 - c, 255 - m, 255 - y, 255 - k]
+            };
+            let through = |channel: u8| ((u16::from(channel) * u16::from(k) + 127) / 255) as u8;
+            [through(c), through(m), through(y)]
+        })
+        .collect();
+    let img = image::RgbImage::from_raw(width, height, rgb).ok_or_else(|| {
+        ImageError::Decoding(DecodingError::from_format_hint(ImageFormat::Jpeg.into()))
+    })?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// JFIF YCbCr to RGB conversion.
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (f32::from(y), f32::from(cb) - 128.0, f32::from(cr) - 128.0);
+    [
+        y + 1.402 * cr,
+        y - 0.344_136 * cb - 0.714_136 * cr,
+        y + 1.772 * cb,
+    ]
+    .map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Recognizes common image formats the `image` crate has no support for.
+fn sniff_unsupported_format(bytes: &[u8]) -> Option<&'static str> {
+    const HEIC_BRANDS: [&[u8]; 8] = [
+        b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"hevm", b"hevs",
+    ];
+    if bytes.get(4..8) == Some(b"ftyp") {
+        let brand = bytes.get(8..12)?;
+        return match brand {
+            b"mif1" => Some("heif"),
+            _ if HEIC_BRANDS.contains(&brand) => Some("heic"),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(&[0xff, 0x0a]) || bytes.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n") {
+        return Some("jxl");
+    }
+    None
+}
+
+pub fn resize_image(
+    img: DynamicImage,
+    nwidth: u32,
+    nheight: u32,
+    filter: imageops::FilterType,
+) -> DynamicImage {
+    img.resize_exact(nwidth, nheight, filter)
+}
+
+/// Resizes to cover `width`x`height` and center-crops to exactly that size.
+pub fn cover_image(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: imageops::FilterType,
+) -> DynamicImage {
+    img.resize_to_fill(width, height, filter)
+}
+
+/// Longest side of the thumbnail that crop windows are scored on.
+const ENTROPY_SAMPLE_SIZE: u32 = 128;
+
+/// Like [`cover_image`], but crops toward the busiest part of the image
+/// instead of the center, so off-center subjects are kept in frame.
+pub fn smart_cover_image(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: imageops::FilterType,
+) -> DynamicImage {
+    let (src_w, src_h) = img.dimensions();
+    let scale = f64::max(
+        f64::from(width) / f64::from(src_w),
+        f64::from(height) / f64::from(src_h),
+    );
+    let scaled_w = ((f64::from(src_w) * scale).round() as u32).max(width);
+    let scaled_h = ((f64::from(src_h) * scale).round() as u32).max(height);
+    let scaled = img.resize_exact(scaled_w, scaled_h, filter);
+    let (x, y) = busiest_window(&scaled, width, height);
+    scaled.crop_imm(x, y, width, height)
+}
+
+/// Position of the `width`x`height` window with the highest luma entropy,
+/// preferring the one closest to the center on ties. Windows are scored on a
+/// thumbnail, so this stays cheap for large images.
+fn busiest_window(img: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let (img_w, img_h) = img.dimensions();
+    let (overflow_x, overflow_y) = (img_w - width, img_h - height);
+    if overflow_x == 0 && overflow_y == 0 {
+        return (0, 0);
+    }
+
+    let scale = (f64::from(ENTROPY_SAMPLE_SIZE) / f64::from(img_w.max(img_h))).min(1.0);
+    let thumb_size = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+    let sample = img
+        .resize_exact(
+            thumb_size(img_w),
+            thumb_size(img_h),
+            imageops::FilterType::Triangle,
+        )
+        .to_luma8();
+    let window_w = thumb_size(width).min(sample.width());
+    let window_h = thumb_size(height).min(sample.height());
+    let (max_x, max_y) = (sample.width() - window_w, sample.height() - window_h);
+
+    let center = (f64::from(max_x) / 2.0, f64::from(max_y) / 2.0);
+    let mut best = (f64::MIN, f64::MAX, 0, 0);
+    for y in 0..=max_y {
+        for x in 0..=max_x {
+            let entropy = window_entropy(&sample, x, y, window_w, window_h);
+            let distance = (f64::from(x) - center.0).abs() + (f64::from(y) - center.1).abs();
+            if entropy > best.0 + 1e-9 || (entropy > best.0 - 1e-9 && distance < best.1) {
+                best = (entropy, distance, x, y);
+            }
+        }
+    }
+
+    // Map the thumbnail position back to the same fraction of the overflow
+    let position = |offset: u32, max: u32, overflow: u32| match max {
+        0 => overflow / 2,
+        _ => (f64::from(offset) / f64::from(max) * f64::from(overflow)).round() as u32,
+    };
+    (
+        position(best.2, max_x, overflow_x),
+        position(best.3, max_y, overflow_y),
+    )
+}
+
+/// Shannon entropy, in bits, of the luma values in a window.
+fn window_entropy(img: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let mut counts = [0u32; 256];
+    for row in y..y + height {
+        for col in x..x + width {
+            counts[usize::from(img.get_pixel(col, row)[0])] += 1;
+        }
+    }
+    let total = f64::from(width * height);
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = f64::from(count) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+pub fn crop_image(
+    img: DynamicImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<DynamicImage, AppError> {
+    if x.saturating_add(width) > img.width() || y.saturating_add(height) > img.height() {
+        return Err(AppError::InvalidCropDimensions(
+            "crop window is outside the image bounds.",
+        ));
+    }
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+/// Shrinks a crop window to the part that lies within an image of
+/// `img_width`x`img_height`. Fails only when no part of the window does.
+pub fn clamp_crop_window(
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    img_width: u32,
+    img_height: u32,
+) -> Result<(u32, u32, u32, u32), AppError> {
+    if x >= img_width || y >= img_height {
+        return Err(AppError::InvalidCropDimensions(
+            "crop window starts outside the image bounds.",
+        ));
+    }
+    Ok((x, y, width.min(img_width - x), height.min(img_height - y)))
+}
+
+/// A tile cut by [`tile_image`], at `column`, `row` of the grid.
+pub struct Tile {
+    pub column: u32,
+    pub row: u32,
+    pub x: u32,
+    pub y: u32,
+    pub image: DynamicImage,
+}
+
+/// Cuts an image into `tile_size` square tiles, row by row. Tiles along the
+/// right and bottom edges are smaller when the image size is not a multiple
+/// of the tile size.
+pub fn tile_image(img: &DynamicImage, tile_size: u32) -> Result<Vec<Tile>, AppError> {
+    let (width, height) = img.dimensions();
+    let mut tiles = Vec::new();
+    for (row, y) in (0..height).step_by(tile_size as usize).enumerate() {
+        for (column, x) in (0..width).step_by(tile_size as usize).enumerate() {
+            let (x, y, tile_width, tile_height) =
+                clamp_crop_window(x, y, tile_size, tile_size, width, height)?;
+            tiles.push(Tile {
+                column: column as u32,
+                row: row as u32,
+                x,
+                y,
+                image: img.crop_imm(x, y, tile_width, tile_height),
+            });
+        }
+    }
+    Ok(tiles)
+}
+
+/// A rectangle within an image, given as `x,y,w,h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Checks that the region is non-empty and lies within an image of
+    /// `img_width`x`img_height`.
+    pub fn check_bounds(&self, img_width: u32, img_height: u32) -> Result<(), AppError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(AppError::InvalidRegion(
+                "width and height must be greater than 0".to_string(),
+            ));
+        }
+        if self.x.saturating_add(self.width) > img_width
+            || self.y.saturating_add(self.height) > img_height
+        {
+            return Err(AppError::InvalidRegion(format!(
+                "{} is outside the {}x{} image",
+                self, img_width, img_height
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Region {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::InvalidRegion(format!("expected x,y,w,h but got {}", s));
+        let values = s
+            .split(',')
+            .map(|part| part.trim().parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match values[..] {
+            [x, y, width, height] => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Parses a `x,y,w,h:sigma` blur region; sigma defaults to that of the blur
+/// filter.
+pub fn parse_blur_region(s: &str) -> Result<(Region, f32), AppError> {
+    let (region, sigma) = match s.split_once(':') {
+        Some((region, sigma)) => {
+            let sigma = sigma
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| AppError::InvalidRegion(format!("invalid blur sigma: {}", sigma)))?;
+            (region, sigma)
+        }
+        None => (s, 1.0),
+    };
+    Ok((region.parse()?, sigma))
+}
+
+/// Sigma above which [`blur`] switches to the box-blur approximation.
+const FAST_BLUR_MIN_SIGMA: f32 = 8.0;
+
+/// Gaussian blur. The exact kernel grows with sigma and gets very slow, so
+/// large sigmas are approximated with three successive box blurs, which cost
+/// the same at any radius and look nearly identical at that scale.
+pub fn blur(img: &DynamicImage, sigma: f32) -> DynamicImage {
+    if sigma > FAST_BLUR_MIN_SIGMA {
+        img.fast_blur(sigma)
+    } else {
+        img.blur(sigma)
+    }
+}
+
+/// Blurs only the pixels inside `region`, leaving the rest untouched.
+pub fn blur_region(
+    mut img: DynamicImage,
+    region: Region,
+    sigma: f32,
+) -> Result<DynamicImage, AppError> {
+    region.check_bounds(img.width(), img.height())?;
+    let blurred = img.crop_imm(region.x, region.y, region.width, region.height);
+    let blurred = blur(&blurred, sigma);
+    imageops::replace(&mut img, &blurred, region.x.into(), region.y.into());
+    Ok(img)
+}
+
+/// CSS color keywords accepted by [`parse_color`].
+const NAMED_COLORS: &[(&str, [u8; 4])] = &[
+    ("transparent", [0, 0, 0, 0]),
+    ("black", [0, 0, 0, 255]),
+    ("white", [255, 255, 255, 255]),
+    ("gray", [128, 128, 128, 255]),
+    ("grey", [128, 128, 128, 255]),
+    ("silver", [192, 192, 192, 255]),
+    ("lightgray", [211, 211, 211, 255]),
+    ("lightgrey", [211, 211, 211, 255]),
+    ("darkgray", [169, 169, 169, 255]),
+    ("darkgrey", [169, 169, 169, 255]),
+    ("red", [255, 0, 0, 255]),
+    ("maroon", [128, 0, 0, 255]),
+    ("orange", [255, 165, 0, 255]),
+    ("gold", [255, 215, 0, 255]),
+    ("yellow", [255, 255, 0, 255]),
+    ("olive", [128, 128, 0, 255]),
+    ("lime", [0, 255, 0, 255]),
+    ("green", [0, 128, 0, 255]),
+    ("teal", [0, 128, 128, 255]),
+    ("aqua", [0, 255, 255, 255]),
+    ("cyan", [0, 255, 255, 255]),
+    ("blue", [0, 0, 255, 255]),
+    ("navy", [0, 0, 128, 255]),
+    ("purple", [128, 0, 128, 255]),
+    ("fuchsia", [255, 0, 255, 255]),
+    ("magenta", [255, 0, 255, 255]),
+    ("pink", [255, 192, 203, 255]),
+    ("brown", [165, 42, 42, 255]),
+    ("indigo", [75, 0, 130, 255]),
+    ("violet", [238, 130, 238, 255]),
+];
+
+/// Parses a color given as hex (`rgb`, `rgba`, `rrggbb` or `rrggbbaa`, with
+/// or without a leading `#`) or as a CSS color keyword such as `white`.
+pub fn parse_color(s: &str) -> Result<Rgba<u8>, AppError> {
+    let invalid = || AppError::InvalidColor(s.to_string());
+    let trimmed = s.trim();
+    if let Some(&(_, rgba)) = NAMED_COLORS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(trimmed))
+    {
+        return Ok(Rgba(rgba));
+    }
+
+    let hex = trimmed.strip_prefix('#').unwrap_or(trimmed);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..=i], 16).map_err(|_| invalid());
+    let pair = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    match hex.len() {
+        // Shorthand digits are doubled, so `f80` is `ff8800`
+        3 | 4 => {
+            let alpha = if hex.len() == 4 { digit(3)? * 17 } else { 255 };
+            Ok(Rgba([
+                digit(0)? * 17,
+                digit(1)? * 17,
+                digit(2)? * 17,
+                alpha,
+            ]))
+        }
+        6 | 8 => {
+            let alpha = if hex.len() == 8 { pair(6)? } else { 255 };
+            Ok(Rgba([pair(0)?, pair(2)?, pair(4)?, alpha]))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses semicolon-separated `x,y,w,h` regions.
+pub fn parse_regions(s: &str) -> Result<Vec<Region>, AppError> {
+    s.split(';')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Fills `region` with a solid color.
+pub fn fill_rect(
+    mut img: DynamicImage,
+    region: Region,
+    color: Rgba<u8>,
+) -> Result<DynamicImage, AppError> {
+    region.check_bounds(img.width(), img.height())?;
+    for y in region.y..region.y + region.height {
+        for x in region.x..region.x + region.width {
+            img.put_pixel(x, y, color);
+        }
+    }
+    Ok(img)
+}
+
+/// Returns the top-left corner of a `width`x`height` window anchored at
+/// `gravity` inside an image of `img_width`x`img_height`.
+pub fn gravity_offset(
+    gravity: Gravity,
+    width: u32,
+    height: u32,
+    img_width: u32,
+    img_height: u32,
+) -> (u32, u32) {
+    let max_x = img_width.saturating_sub(width);
+    let max_y = img_height.saturating_sub(height);
+
+    let x = match gravity {
+        Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+        Gravity::East | Gravity::NorthEast | Gravity::SouthEast => max_x,
+        Gravity::Center | Gravity::North | Gravity::South => max_x / 2,
+    };
+    let y = match gravity {
+        Gravity::North | Gravity::NorthWest | Gravity::NorthEast => 0,
+        Gravity::South | Gravity::SouthWest | Gravity::SouthEast => max_y,
+        Gravity::Center | Gravity::West | Gravity::East => max_y / 2,
+    };
+
+    (x, y)
+}
+
+/// Converts a crop window given as fractions of the image size into pixels.
+pub fn fractional_crop_window(
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    img_width: u32,
+    img_height: u32,
+) -> Result<(u32, u32, u32, u32), AppError> {
+    let in_unit_range = |v: f64| (0.0..=1.0).contains(&v);
+    if ![x, y, width, height].into_iter().all(in_unit_range) {
+        return Err(AppError::InvalidCropDimensions(
+            "fractional crop values must be between 0 and 1.",
+        ));
+    }
+    if x + width > 1.0 || y + height > 1.0 {
+        return Err(AppError::InvalidCropDimensions(
+            "fractional crop window extends past the image bounds.",
+        ));
+    }
+
+    let to_pixels = |fraction: f64, size: u32| (fraction * f64::from(size)).round() as u32;
+    let px = to_pixels(x, img_width);
+    let py = to_pixels(y, img_height);
+    let pw = to_pixels(width, img_width).min(img_width - px);
+    let ph = to_pixels(height, img_height).min(img_height - py);
+
+    Ok((px, py, pw, ph))
+}
+
+/// Computes a 64-bit perceptual hash; similar images produce hashes with a
+/// small Hamming distance.
+pub fn perceptual_hash(img: &DynamicImage, algorithm: HashAlgorithm) -> u64 {
+    let grayscale = |width: u32, height: u32| -> Vec<f64> {
+        img.resize_exact(width, height, imageops::FilterType::Triangle)
+            .to_luma8()
+            .pixels()
+            .map(|p| f64::from(p.0[0]))
+            .collect()
+    };
+    let to_bits = |bits: &mut dyn Iterator<Item = bool>| {
+        bits.fold(0u64, |hash, bit| (hash << 1) | u64::from(bit))
+    };
+
+    match algorithm {
+        HashAlgorithm::AHash => {
+            let pixels = grayscale(8, 8);
+            let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+            to_bits(&mut pixels.iter().map(|&p| p > mean))
+        }
+        HashAlgorithm::DHash => {
+            let pixels = grayscale(9, 8);
+            to_bits(
+                &mut pixels
+                    .chunks(9)
+                    .flat_map(|row| row.windows(2).map(|pair| pair[0] > pair[1])),
+            )
+        }
+        HashAlgorithm::PHash => {
+            const SIZE: usize = 32;
+            let pixels = grayscale(SIZE as u32, SIZE as u32);
+
+            // 2D DCT-II, keeping only the 8x8 lowest frequencies
+            let mut coefficients = Vec::with_capacity(64);
+            for v in 0..8 {
+                for u in 0..8 {
+                    let mut sum = 0.0;
+                    for y in 0..SIZE {
+                        for x in 0..SIZE {
+                            sum += pixels[y * SIZE + x]
+                                * ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI
+                                    / (2 * SIZE) as f64)
+                                    .cos()
+                                * ((2 * y + 1) as f64 * v as f64 * std::f64::consts::PI
+                                    / (2 * SIZE) as f64)
+                                    .cos();
+                        }
+                    }
+                    coefficients.push(sum);
+                }
+            }
+
+            // The DC term only reflects overall brightness, so leave it out
+            // of the median.
+            let mut sorted = coefficients[1..].to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let median = sorted[sorted.len() / 2];
+            to_bits(&mut coefficients.iter().map(|&c| c > median))
+        }
+    }
+}
+
+/// Composites `foreground` over `background`. The background is scaled to
+/// cover the foreground and the excess is cropped away around `gravity`.
+pub fn composite_over_background(
+    foreground: DynamicImage,
+    background: DynamicImage,
+    gravity: Gravity,
+) -> DynamicImage {
+    let (width, height) = (foreground.width(), foreground.height());
+    let scale = f64::max(
+        f64::from(width) / f64::from(background.width()),
+        f64::from(height) / f64::from(background.height()),
+    );
+    let scaled_width = ((f64::from(background.width()) * scale).ceil() as u32).max(width);
+    let scaled_height = ((f64::from(background.height()) * scale).ceil() as u32).max(height);
+    let scaled =
+        background.resize_exact(scaled_width, scaled_height, imageops::FilterType::Triangle);
+
+    let (x, y) = gravity_offset(gravity, width, height, scaled_width, scaled_height);
+    let mut canvas = scaled.crop_imm(x, y, width, height).to_rgba8();
+    imageops::overlay(&mut canvas, &foreground.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// A single parsed filter with its parameters filled in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Grayscale {
+        method: GrayscaleMethod,
+        strength: f32,
+    },
+    Sepia {
+        strength: f32,
+    },
+    Invert,
+    Blur {
+        sigma: f32,
+    },
+    Sharpen {
+        sigma: f32,
+        threshold: i32,
+    },
+    Brighten {
+        value: i32,
+    },
+    Contrast {
+        value: f32,
+    },
+    Alpha,
+    Autocrop {
+        color_tolerance: u8,
+        alpha_tolerance: u8,
+        alpha_only: bool,
+    },
+    Chromakey {
+        color: [u8; 3],
+        tolerance: u8,
+    },
+    Autoenhance {
+        intensity: f32,
+    },
+    Removebg {
+        tolerance: u8,
+    },
+    Temperature {
+        value: f32,
+    },
+    Rotate {
+        degrees: f32,
+        background: Rgba<u8>,
+        expand: bool,
+    },
+    Perspective {
+        corners: [(f32, f32); 4],
+        background: Rgba<u8>,
+    },
+}
+
+impl Filter {
+    pub fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            // Kept in the image's own format when fully applied
+            Filter::Grayscale { method, strength } if strength >= 1.0 => grayscale(&img, method),
+            Filter::Grayscale { method, strength } => {
+                let gray = grayscale(&img, method);
+                blend(img, &gray, strength)
+            }
+            Filter::Sepia { strength } => {
+                let toned = sepia(&img);
+                blend(img, &toned, strength)
+            }
+            Filter::Invert => {
+                let mut mutable_img = img;
+                imageops::invert(&mut mutable_img);
+                mutable_img
+            }
+            Filter::Blur { sigma } => blur(&img, sigma),
+            // Sharpens in the image's own pixel format, preserving bit depth
+            Filter::Sharpen { sigma, threshold } => img.unsharpen(sigma, threshold),
+            Filter::Brighten { value } => img.brighten(value),
+            Filter::Contrast { value } => img.adjust_contrast(value),
+            Filter::Alpha => extract_alpha(&img),
+            Filter::Autocrop {
+                color_tolerance,
+                alpha_tolerance,
+                alpha_only,
+            } => autocrop(img, color_tolerance, alpha_tolerance, alpha_only),
+            Filter::Chromakey { color, tolerance } => chromakey(&img, color, tolerance),
+            Filter::Autoenhance { intensity } => autoenhance(img, intensity),
+            Filter::Removebg { tolerance } => remove_background(&img, tolerance),
+            Filter::Temperature { value } => adjust_temperature(&img, value),
+            Filter::Rotate {
+                degrees,
+                background,
+                expand,
+            } => rotate(&img, degrees, background, expand),
+            Filter::Perspective {
+                corners,
+                background,
+            } => perspective(&img, corners, background),
+        }
+    }
+
+    /// Whether the filter's output relies on transparency.
+    pub fn needs_alpha(self) -> bool {
+        matches!(self, Filter::Chromakey { .. } | Filter::Removebg { .. })
+    }
+
+    /// The clockwise angle of a rotation that only moves pixels, as 0, 90,
+    /// 180 or 270. Quarter turns qualify only with `expand`, as they
+    /// otherwise cut off the sides of non-square images.
+    pub fn right_angle(self) -> Option<u32> {
+        let Filter::Rotate {
+            degrees, expand, ..
+        } = self
+        else {
+            return None;
+        };
+        let degrees = degrees.rem_euclid(360.0);
+        let quarter_turn = degrees == 90.0 || degrees == 270.0;
+        ((degrees == 0.0 || degrees == 180.0) || (quarter_turn && expand)).then_some(degrees as u32)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = AppError;
+
+    fn from_str(filter_str: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = filter_str.trim().split(':').collect();
+        let filter_name = parts[0].to_lowercase();
+
+        match filter_name.as_str() {
+            // Example: "grayscale:0.5"
+            // Example: "grayscale:average:0.5", where both parameters are optional
+            "grayscale" | "greyscale" => {
+                let method = parts
+                    .get(1)
+                    .filter(|part| part.trim().parse::<f32>().is_err());
+                // The strength follows the method when one is given
+                let strength_parts = if method.is_some() {
+                    &parts[1..]
+                } else {
+                    &parts[..]
+                };
+                Ok(Filter::Grayscale {
+                    method: method
+                        .map(|method| method.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                    strength: filter_strength(strength_parts, "invalid grayscale strength")?,
+                })
+            }
+            "sepia" => Ok(Filter::Sepia {
+                strength: filter_strength(&parts, "invalid sepia strength")?,
+            }),
+            "invert" => Ok(Filter::Invert),
+            "blur" => Ok(Filter::Blur {
+                sigma: filter_param(&parts, 1, 1.0, "invalid blur sigma value")?,
+            }),
+            "sharpen" => Ok(Filter::Sharpen {
+                sigma: filter_param(&parts, 1, 1.0, "invalid sharpen sigma value")?,
+                // A common default threshold for unsharpen mask, may need tuning
+                threshold: filter_param(&parts, 2, 0, "invalid sharpen threshold value")?,
+            }),
+            // Example: "brighten:10"
+            "brighten" => Ok(Filter::Brighten {
+                value: filter_param(&parts, 1, 10, "invalid brighten value.")?,
+            }),
+            // Example: "contrast:15.5"
+            "contrast" => Ok(Filter::Contrast {
+                value: filter_param(&parts, 1, 10.0, "invalid contrast value.")?,
+            }),
+            "alpha" => Ok(Filter::Alpha),
+            // Examples: "autocrop:10:32", "trim:alpha:32"
+            "autocrop" | "trim" => {
+                if parts
+                    .get(1)
+                    .is_some_and(|mode| mode.eq_ignore_ascii_case("alpha"))
+                {
+                    return Ok(Filter::Autocrop {
+                        color_tolerance: 0,
+                        alpha_tolerance: filter_param(
+                            &parts,
+                            2,
+                            0,
+                            "invalid trim alpha tolerance",
+                        )?,
+                        alpha_only: true,
+                    });
+                }
+                Ok(Filter::Autocrop {
+                    color_tolerance: filter_param(&parts, 1, 0, "invalid trim color tolerance")?,
+                    alpha_tolerance: filter_param(&parts, 2, 0, "invalid trim alpha tolerance")?,
+                    alpha_only: false,
+                })
+            }
+            // Example: "chromakey:00ff00:40"
+            "chromakey" => {
+                let color = parts
+                    .get(1)
+                    .ok_or_else(|| {
+                        AppError::InvalidFilterParameters("chromakey requires a color".to_string())
+                    })
+                    .and_then(|color| parse_color(color))?;
+                Ok(Filter::Chromakey {
+                    color: [color[0], color[1], color[2]],
+                    tolerance: filter_param(&parts, 2, 0, "invalid chromakey tolerance value")?,
+                })
+            }
+            // Example: "autoenhance:0.5"
+            "autoenhance" | "auto-enhance" => {
+                let intensity = filter_param(&parts, 1, 1.0, "invalid autoenhance intensity")?;
+                if !(0.0..=1.0).contains(&intensity) {
+                    return Err(AppError::InvalidFilterParameters(
+                        "autoenhance intensity must be between 0.0 and 1.0".to_string(),
+                    ));
+                }
+                Ok(Filter::Autoenhance { intensity })
+            }
+            // Example: "removebg:24"
+            "removebg" => Ok(Filter::Removebg {
+                tolerance: filter_param(
+                    &parts,
+                    1,
+                    REMOVEBG_DEFAULT_TOLERANCE,
+                    "invalid removebg tolerance value",
+                )?,
+            }),
+            // Examples: "temperature:30", "temperature:3200"
+            "temperature" => {
+                let value = filter_param(&parts, 1, 0.0, "invalid temperature value")?;
+                if !(-100.0..=100.0).contains(&value) && !KELVIN_RANGE.contains(&value) {
+                    return Err(AppError::InvalidFilterParameters(format!(
+                        "temperature must be an offset between -100 and 100 or a kelvin value between {} and {}",
+                        KELVIN_RANGE.start(),
+                        KELVIN_RANGE.end()
+                    )));
+                }
+                Ok(Filter::Temperature { value })
+            }
+            // Examples: "rotate:45", "rotate:-10:white:true"
+            "rotate" => {
+                let degrees: f32 = filter_param(&parts, 1, 0.0, "invalid rotate angle")?;
+                if !degrees.is_finite() {
+                    return Err(AppError::InvalidFilterParameters(
+                        "invalid rotate angle".to_string(),
+                    ));
+                }
+                let background = match parts.get(2).filter(|color| !color.trim().is_empty()) {
+                    Some(color) => parse_color(color)?,
+                    None => Rgba([0, 0, 0, 0]),
+                };
+                Ok(Filter::Rotate {
+                    degrees,
+                    background,
+                    expand: filter_param(&parts, 3, false, "invalid rotate expand flag")?,
+                })
+            }
+            // Example: "perspective:20:0:80:0:100:100:0:100"
+            "perspective" => {
+                let invalid = || {
+                    AppError::InvalidFilterParameters(
+                        "perspective requires 8 corner coordinates".to_string(),
+                    )
+                };
+                let coords = parts
+                    .get(1..9)
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|coord| coord.trim().parse::<f32>().ok().filter(|c| c.is_finite()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(invalid)?;
+                let corners = [0, 1, 2, 3].map(|i| (coords[i * 2], coords[i * 2 + 1]));
+                if Projection::from_control_points(UNIT_SQUARE, corners).is_none() {
+                    return Err(AppError::InvalidFilterParameters(
+                        "perspective corners must form a quadrilateral".to_string(),
+                    ));
+                }
+                let background = match parts.get(9).filter(|color| !color.trim().is_empty()) {
+                    Some(color) => parse_color(color)?,
+                    None => Rgba([0, 0, 0, 0]),
+                };
+                Ok(Filter::Perspective {
+                    corners,
+                    background,
+                })
+            }
+            // Add more filters here
+            _ => Err(AppError::UnsupportedFilter(filter_name)),
+        }
+    }
+}
+
+/// Formats the canonical form of a filter: lowercase name, aliases resolved
+/// and every parameter spelled out.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Defaults are left out, so equivalent chains normalize alike
+            Filter::Grayscale { method, strength } => {
+                write!(f, "grayscale")?;
+                if *method != GrayscaleMethod::default() {
+                    write!(f, ":{}", method)?;
+                }
+                if *strength < 1.0 {
+                    write!(f, ":{}", strength)?;
+                }
+                Ok(())
+            }
+            Filter::Sepia { strength } if *strength >= 1.0 => write!(f, "sepia"),
+            Filter::Sepia { strength } => write!(f, "sepia:{}", strength),
+            Filter::Invert => write!(f, "invert"),
+            Filter::Blur { sigma } => write!(f, "blur:{}", sigma),
+            Filter::Sharpen { sigma, threshold } => write!(f, "sharpen:{}:{}", sigma, threshold),
+            Filter::Brighten { value } => write!(f, "brighten:{}", value),
+            Filter::Contrast { value } => write!(f, "contrast:{}", value),
+            Filter::Alpha => write!(f, "alpha"),
+            Filter::Autocrop {
+                alpha_tolerance,
+                alpha_only: true,
+                ..
+            } => write!(f, "autocrop:alpha:{}", alpha_tolerance),
+            Filter::Autocrop {
+                color_tolerance,
+                alpha_tolerance,
+                alpha_only: false,
+            } => write!(f, "autocrop:{}:{}", color_tolerance, alpha_tolerance),
+            Filter::Chromakey {
+                color: [r, g, b],
+                tolerance,
+            } => write!(f, "chromakey:{:02x}{:02x}{:02x}:{}", r, g, b, tolerance),
+            Filter::Autoenhance { intensity } => write!(f, "autoenhance:{}", intensity),
+            Filter::Removebg { tolerance } => write!(f, "removebg:{}", tolerance),
+            Filter::Temperature { value } => write!(f, "temperature:{}", value),
+            Filter::Rotate {
+                degrees,
+                background: Rgba([r, g, b, a]),
+                expand,
+            } => write!(
+                f,
+                "rotate:{}:{:02x}{:02x}{:02x}{:02x}:{}",
+                degrees, r, g, b, a, expand
+            ),
+            Filter::Perspective {
+                corners,
+                background: Rgba([r, g, b, a]),
+            } => {
+                write!(f, "perspective")?;
+                for (x, y) in corners {
+                    write!(f, ":{}:{}", x, y)?;
+                }
+                write!(f, ":{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+            }
+        }
+    }
+}
+
+/// Parses the filter parameter at `index`, or returns `default` when absent.
+fn filter_param<T: FromStr>(
+    parts: &[&str],
+    index: usize,
+    default: T,
+    message: &str,
+) -> Result<T, AppError> {
+    match parts.get(index) {
+        Some(part) => part
+            .trim()
+            .parse()
+            .map_err(|_| AppError::InvalidFilterParameters(message.to_string())),
+        None => Ok(default),
+    }
+}
+
+/// Parses the optional 0.0-1.0 strength of a tonal filter, 1.0 by default.
+fn filter_strength(parts: &[&str], message: &str) -> Result<f32, AppError> {
+    let strength = filter_param(parts, 1, 1.0, message)?;
+    if !(0.0..=1.0).contains(&strength) {
+        return Err(AppError::InvalidFilterParameters(message.to_string()));
+    }
+    Ok(strength)
+}
+
+/// Parses a comma-separated chain of filters, applied in order.
+pub fn parse_filter_chain(filter_str: &str) -> Result<Vec<Filter>, AppError> {
+    filter_str
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Canonical form of a filter chain, suitable for cache keys.
+pub fn normalize_filter_str(filter_str: &str) -> Result<String, AppError> {
+    let filters = parse_filter_chain(filter_str)?;
+    Ok(filters
+        .iter()
+        .map(Filter::to_string)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+pub fn apply_filter_str(img: DynamicImage, filter_str: &str) -> Result<DynamicImage, AppError> {
+    let filters = parse_filter_chain(filter_str)?;
+    Ok(filters
+        .into_iter()
+        .fold(img, |img, filter| filter.apply(img)))
+}
+
+/// Reads the EXIF tags of the primary image, keyed by tag name, without
+/// decoding any pixels. Images without EXIF data yield no tags.
+pub fn read_exif_tags(
+    image_bytes: &[u8],
+    include_gps: bool,
+) -> Result<BTreeMap<String, String>, AppError> {
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(image_bytes)) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(exif
+        .fields()
+        .filter(|field| field.ifd_num == exif::In::PRIMARY)
+        .filter(|field| include_gps || field.tag.context() != exif::Context::Gps)
+        .map(|field| {
+            let value = match &field.value {
+                // Displayed quoted otherwise
+                exif::Value::Ascii(strings) => strings
+                    .iter()
+                    .map(|string| String::from_utf8_lossy(string))
+                    .filter(|string| !string.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                _ => field.display_value().with_unit(&exif).to_string(),
+            };
+            (field.tag.to_string(), value)
+        })
+        .collect())
+}
+
+/// Renders the alpha channel as a grayscale image where white is opaque and
+/// black is transparent. Images without alpha come out all white.
+pub fn extract_alpha(img: &DynamicImage) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        Luma([rgba.get_pixel(x, y).0[3]])
+    });
+    DynamicImage::ImageLuma8(alpha)
+}
+
+/// Makes pixels whose channels are all within `tolerance` of `color` fully
+/// transparent.
+pub fn chromakey(img: &DynamicImage, color: [u8; 3], tolerance: u8) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        if pixel.0[..3]
+            .iter()
+            .zip(color)
+            .all(|(&channel, key)| channel.abs_diff(key) <= tolerance)
+        {
+            pixel.0[3] = 0;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Default per-channel tolerance of the `removebg` filter, enough to absorb
+/// compression noise in a uniform background.
+const REMOVEBG_DEFAULT_TOLERANCE: u8 = 16;
+
+/// Makes the background of a simple product shot transparent: flood-fills
+/// from the image borders through pixels whose channels are all within
+/// `tolerance` of the background color, taken as the per-channel median of
+/// the border pixels. Regions of background color enclosed by the subject are
+/// kept.
+pub fn remove_background(img: &DynamicImage, tolerance: u8) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let border: Vec<(u32, u32)> = (0..width)
+        .flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((0..height).flat_map(|y| [(0, y), (width - 1, y)]))
+        .collect();
+
+    let mut background = [0u8; 3];
+    for (channel, value) in background.iter_mut().enumerate() {
+        let mut samples: Vec<u8> = border
+            .iter()
+            .map(|&(x, y)| rgba.get_pixel(x, y).0[channel])
+            .collect();
+        samples.sort_unstable();
+        *value = samples[samples.len() / 2];
+    }
+    let is_background = |pixel: &Rgba<u8>| {
+        pixel.0[..3]
+            .iter()
+            .zip(background)
+            .all(|(&channel, key)| channel.abs_diff(key) <= tolerance)
+    };
+
+    let mut visited = vec![false; width as usize * height as usize];
+    let mut queue = VecDeque::new();
+    for (x, y) in border {
+        let index = (y * width + x) as usize;
+        if !visited[index] && is_background(rgba.get_pixel(x, y)) {
+            visited[index] = true;
+            queue.push_back((x, y));
+        }
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        rgba.get_pixel_mut(x, y).0[3] = 0;
+        let neighbors = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for (nx, ny) in neighbors {
+            if nx >= width || ny >= height {
+                continue;
+            }
+            let index = (ny * width + nx) as usize;
+            if !visited[index] && is_background(rgba.get_pixel(nx, ny)) {
+                visited[index] = true;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Color temperatures accepted by the `temperature` filter, in kelvin.
+const KELVIN_RANGE: std::ops::RangeInclusive<f32> = 1000.0..=40000.0;
+/// Color temperature that leaves an image unchanged.
+const NEUTRAL_KELVIN: f32 = 6500.0;
+/// Red and blue gain change of a `temperature` offset of 100.
+const TEMPERATURE_OFFSET_GAIN: f32 = 0.3;
+
+/// Shifts the white balance by scaling the red and blue channels. `value`
+/// is either an offset from -100 (cool) to 100 (warm), or a kelvin value
+/// whose light color the image is tinted toward, relative to daylight
+/// (6500K): lower values are warmer, higher ones cooler.
+pub fn adjust_temperature(img: &DynamicImage, value: f32) -> DynamicImage {
+    let gains = if KELVIN_RANGE.contains(&value) {
+        let light = kelvin_to_rgb(value);
+        let neutral = kelvin_to_rgb(NEUTRAL_KELVIN);
+        let [red, green, blue] = [0, 1, 2].map(|channel| light[channel] / neutral[channel]);
+        // Relative to green, so the brightness stays about the same as with offsets
+        [red / green, 1.0, blue / green]
+    } else {
+        let shift = value / 100.0 * TEMPERATURE_OFFSET_GAIN;
+        [1.0 + shift, 1.0, 1.0 - shift]
+    };
+
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        for (channel, gain) in pixel.0[..3].iter_mut().zip(gains) {
+            *channel = (f32::from(*channel) * gain).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).into_rgb8())
+    }
+}
+
+/// Approximates the color of black-body light at `kelvin`, on a 0-255 scale
+/// (Tanner Helland's fit of the CIE color matching data).
+fn kelvin_to_rgb(kelvin: f32) -> [f32; 3] {
+    let temp = kelvin / 100.0;
+    let red = if temp <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (temp - 60.0).powf(-0.13320476)
+    };
+    let green = if temp <= 66.0 {
+        99.4708 * temp.ln() - 161.11957
+    } else {
+        288.12216 * (temp - 60.0).powf(-0.07551485)
+    };
+    let blue = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (temp - 10.0).ln() - 305.0448
+    };
+    [red, green, blue].map(|channel| channel.clamp(0.0, 255.0))
+}
+
+/// Rotates an image clockwise by `degrees` around its center, filling the
+/// exposed corners with `background`. With `expand`, the canvas grows to
+/// hold the whole rotated image; otherwise it keeps its size and the corners
+/// of the image are cut off.
+pub fn rotate(
+    img: &DynamicImage,
+    degrees: f32,
+    background: Rgba<u8>,
+    expand: bool,
+) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    // Right angles are exact pixel moves, as long as the canvas fits
+    let fits = expand || width == height;
+    match degrees.rem_euclid(360.0) {
+        0.0 => return img.clone(),
+        90.0 if fits => return img.rotate90(),
+        180.0 => return img.rotate180(),
+        270.0 if fits => return img.rotate270(),
+        _ => {}
+    }
+
+    let theta = degrees.to_radians();
+    let (out_width, out_height) = if expand {
+        let (sin, cos) = (theta.sin().abs(), theta.cos().abs());
+        // Tolerates rounding error at right angles, so 90 degrees swaps the
+        // sides exactly
+        let side = |a: u32, b: u32| ((a as f32 * cos + b as f32 * sin - 1e-3).ceil() as u32).max(1);
+        (side(width, height), side(height, width))
+    } else {
+        (width, height)
+    };
+
+    // Pixels are sampled at integer coordinates, so the center lies between
+    // the middle pixels
+    let center = |size: u32| (size as f32 - 1.0) / 2.0;
+    let projection = Projection::translate(center(out_width), center(out_height))
+        * Projection::rotate(theta)
+        * Projection::translate(-center(width), -center(height));
+    warp_over_background(img, projection, (out_width, out_height), background)
+}
+
+/// Corners of the unit square, in the order perspective corners are given.
+const UNIT_SQUARE: [(f32, f32); 4] = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+
+/// Maps the image's top-left, top-right, bottom-right and bottom-left
+/// corners to `corners`, in pixels of the same-sized canvas, filling the
+/// area outside the warped image with `background`.
+pub fn perspective(
+    img: &DynamicImage,
+    corners: [(f32, f32); 4],
+    background: Rgba<u8>,
+) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let (right, bottom) = ((width - 1) as f32, (height - 1) as f32);
+    let source = UNIT_SQUARE.map(|(x, y)| (x * right, y * bottom));
+    // Only fails for a one pixel wide or high source
+    let Some(projection) = Projection::from_control_points(source, corners) else {
+        return img.clone();
+    };
+    warp_over_background(img, projection, (width, height), background)
+}
+
+/// Applies `projection` to an image, sampling bilinearly into a canvas of
+/// `size` filled with `background` where no source pixel maps to.
+fn warp_over_background(
+    img: &DynamicImage,
+    projection: Projection,
+    (width, height): (u32, u32),
+    background: Rgba<u8>,
+) -> DynamicImage {
+    // Bilinear sampling needs a neighbor past each pixel, so the edges are
+    // framed with the background, which also smooths them into it
+    let mut framed = RgbaImage::from_pixel(img.width() + 2, img.height() + 2, background);
+    imageops::replace(&mut framed, &img.to_rgba8(), 1, 1);
+    let projection = projection * Projection::translate(-1.0, -1.0);
+
+    let mut warped = RgbaImage::new(width, height);
+    warp_into(
+        &framed,
+        &projection,
+        Interpolation::Bilinear,
+        background,
+        &mut warped,
+    );
+    let warped = DynamicImage::ImageRgba8(warped);
+    if img.color().has_alpha() || background.0[3] < 255 {
+        warped
+    } else {
+        DynamicImage::ImageRgb8(warped.into_rgb8())
+    }
+}
+
+/// Share of the darkest and of the brightest pixels clipped by
+/// [`stretch_levels`], so a few outliers don't prevent stretching.
+const LEVELS_CLIP: f32 = 0.005;
+/// Saturation gain of [`autoenhance`] at full intensity.
+const AUTOENHANCE_SATURATION: f32 = 0.2;
+const AUTOENHANCE_SHARPEN_SIGMA: f32 = 0.8;
+const AUTOENHANCE_SHARPEN_THRESHOLD: i32 = 2;
+
+/// One-shot photo improvement: stretches levels to the full range, boosts
+/// saturation and sharpens lightly. Each step is scaled by `intensity`
+/// (0.0-1.0), so 0 leaves the image untouched.
+pub fn autoenhance(img: DynamicImage, intensity: f32) -> DynamicImage {
+    if intensity <= 0.0 {
+        return img;
+    }
+    let img = stretch_levels(img, intensity);
+    let img = saturate(img, 1.0 + AUTOENHANCE_SATURATION * intensity);
+    img.unsharpen(
+        AUTOENHANCE_SHARPEN_SIGMA * intensity,
+        AUTOENHANCE_SHARPEN_THRESHOLD,
+    )
+}
+
+fn luma(r: f32, g: f32, b: f32) -> f32 {
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+/// Maps the luma range of the visible pixels, minus the clipped extremes, to
+/// the full 0-255 range, blended with the original by `amount`.
+fn stretch_levels(img: DynamicImage, amount: f32) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+
+    let mut histogram = [0u32; 256];
+    for pixel in rgba.pixels().filter(|pixel| pixel.0[3] > 0) {
+        let [r, g, b, _] = pixel.0.map(f32::from);
+        histogram[luma(r, g, b).round() as usize] += 1;
+    }
+    let total: u32 = histogram.iter().sum();
+    let clip = (total as f32 * LEVELS_CLIP) as u32;
+    let (Some(low), Some(high)) = (
+        first_unclipped_bin(&histogram, clip, 0..256),
+        first_unclipped_bin(&histogram, clip, (0..256).rev()),
+    ) else {
+        return restore_alpha(rgba, has_alpha);
+    };
+    if high <= low {
+        return restore_alpha(rgba, has_alpha);
+    }
+
+    let (low, scale) = (low as f32, 255.0 / (high - low) as f32);
+    for pixel in rgba.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            let value = f32::from(*channel);
+            let stretched = (value - low) * scale;
+            *channel = (value + (stretched - value) * amount)
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+    restore_alpha(rgba, has_alpha)
+}
+
+/// First bin, in the order given, past the `clip` pixels at that end.
+fn first_unclipped_bin(
+    histogram: &[u32; 256],
+    clip: u32,
+    mut bins: impl Iterator<Item = usize>,
+) -> Option<usize> {
+    let mut seen = 0;
+    bins.find(|&bin| {
+        seen += histogram[bin];
+        seen > clip
+    })
+}
+
+/// Scales each pixel's distance from its gray value by `factor`.
+fn saturate(img: DynamicImage, factor: f32) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let [r, g, b, _] = pixel.0.map(f32::from);
+        let gray = luma(r, g, b);
+        for channel in &mut pixel.0[..3] {
+            let value = f32::from(*channel);
+            *channel = (gray + (value - gray) * factor).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    restore_alpha(rgba, has_alpha)
+}
+
+/// Drops the alpha channel again from images that didn't have one.
+fn restore_alpha(rgba: RgbaImage, has_alpha: bool) -> DynamicImage {
+    let img = DynamicImage::ImageRgba8(rgba);
+    if has_alpha {
+        img
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    }
+}
+
+/// Formula used to turn colors into shades of gray.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrayscaleMethod {
+    /// Rec. 601 luma, weighting green most, as in classic photo editors.
+    Luminosity,
+    /// Rec. 709 luma, the weights of sRGB.
+    #[default]
+    Bt709,
+    /// Mean of the three channels.
+    Average,
+    /// Midpoint of the brightest and darkest channels.
+    Lightness,
+}
+
+impl GrayscaleMethod {
+    /// Gray level of an 8-bit color.
+    fn gray(self, rgb: [u8; 3]) -> u8 {
+        let [r, g, b] = rgb.map(u32::from);
+        let value = match self {
+            GrayscaleMethod::Luminosity => (299 * r + 587 * g + 114 * b + 500) / 1000,
+            GrayscaleMethod::Bt709 => (2126 * r + 7152 * g + 722 * b + 5000) / 10000,
+            GrayscaleMethod::Average => (r + g + b + 1) / 3,
+            GrayscaleMethod::Lightness => (r.max(g).max(b) + r.min(g).min(b)).div_ceil(2),
+        };
+        value as u8
+    }
+}
+
+impl fmt::Display for GrayscaleMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            GrayscaleMethod::Luminosity => "luminosity",
+            GrayscaleMethod::Bt709 => "bt709",
+            GrayscaleMethod::Average => "average",
+            GrayscaleMethod::Lightness => "lightness",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for GrayscaleMethod {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "luminosity" | "bt601" => Ok(GrayscaleMethod::Luminosity),
+            "bt709" => Ok(GrayscaleMethod::Bt709),
+            "average" => Ok(GrayscaleMethod::Average),
+            "lightness" => Ok(GrayscaleMethod::Lightness),
+            _ => Err(AppError::InvalidFilterParameters(format!(
+                "unknown grayscale method: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Converts to grayscale with the given formula, keeping any alpha channel.
+/// Rec. 709 is what `image` uses, so it keeps the source bit depth; the other
+/// formulas work on 8-bit channels.
+pub fn grayscale(img: &DynamicImage, method: GrayscaleMethod) -> DynamicImage {
+    if method == GrayscaleMethod::Bt709 {
+        return img.grayscale();
+    }
+
+    let gray = |rgb: [u8; 3]| method.gray(rgb);
+    let rgba = img.to_rgba8();
+    if img.color().has_alpha() {
+        DynamicImage::ImageLumaA8(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            LumaA([gray([r, g, b]), a])
+        }))
+    } else {
+        DynamicImage::ImageLuma8(ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, _] = rgba.get_pixel(x, y).0;
+            Luma([gray([r, g, b])])
+        }))
+    }
+}
+
+/// Classic sepia tone matrix, applied to 8-bit RGB.
+pub fn sepia(img: &DynamicImage) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let [r, g, b, _] = pixel.0.map(f32::from);
+        let toned = [
+            0.393 * r + 0.769 * g + 0.189 * b,
+            0.349 * r + 0.686 * g + 0.168 * b,
+            0.272 * r + 0.534 * g + 0.131 * b,
+        ];
+        for (channel, value) in pixel.0[..3].iter_mut().zip(toned) {
+            *channel = value.round().min(255.0) as u8;
+        }
+    }
+    restore_alpha(rgba, has_alpha)
+}
+
+/// Linearly interpolates from `original` (at 0.0) to `applied` (at 1.0).
+/// Both images must have the same dimensions.
+pub fn blend(original: DynamicImage, applied: &DynamicImage, amount: f32) -> DynamicImage {
+    if amount <= 0.0 {
+        return original;
+    }
+    let has_alpha = original.color().has_alpha();
+    let mut rgba = original.to_rgba8();
+    let applied = applied.to_rgba8();
+    for (pixel, target) in rgba.pixels_mut().zip(applied.pixels()) {
+        for (channel, &goal) in pixel.0.iter_mut().zip(&target.0) {
+            let value = f32::from(*channel);
+            *channel = (value + (f32::from(goal) - value) * amount).round() as u8;
+        }
+    }
+    restore_alpha(rgba, has_alpha)
+}
+
+/// Largest `LUT_3D_SIZE` accepted, which bounds a parsed LUT to about 25 MB.
+const MAX_LUT_SIZE: usize = 128;
+
+/// A 3D color lookup table parsed from a `.cube` file.
+#[derive(Debug)]
+pub struct Lut {
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    /// Output colors with red varying fastest, then green, then blue.
+    table: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    /// Parses the Adobe/Resolve `.cube` format. Only 3D LUTs are supported.
+    pub fn parse_cube(text: &str) -> Result<Self, AppError> {
+        let invalid = |msg: String| AppError::InvalidLut(msg);
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let keyword = parts.next().unwrap_or_default();
+            match keyword {
+                "TITLE" => {}
+                "LUT_1D_SIZE" => return Err(invalid("1D LUTs are not supported".to_string())),
+                "LUT_3D_SIZE" => {
+                    let value = parts
+                        .next()
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .filter(|value| (2..=MAX_LUT_SIZE).contains(value))
+                        .ok_or_else(|| {
+                            invalid(format!(
+                                "LUT_3D_SIZE must be between 2 and {}",
+                                MAX_LUT_SIZE
+                            ))
+                        })?;
+                    size = Some(value);
+                    table.reserve(value.pow(3));
+                }
+                "DOMAIN_MIN" | "DOMAIN_MAX" => {
+                    let values = parse_lut_triple(parts)
+                        .ok_or_else(|| invalid(format!("malformed {}", keyword)))?;
+                    if keyword == "DOMAIN_MIN" {
+                        domain_min = values;
+                    } else {
+                        domain_max = values;
+                    }
+                }
+                _ => {
+                    let values = parse_lut_triple(line.split_whitespace())
+                        .ok_or_else(|| invalid(format!("malformed line: {}", line)))?;
+                    table.push(values);
+                }
+            }
+        }
+
+        let size = size.ok_or_else(|| invalid("missing LUT_3D_SIZE".to_string()))?;
+        if table.len() != size.pow(3) {
+            return Err(invalid(format!(
+                "expected {} entries, found {}",
+                size.pow(3),
+                table.len()
+            )));
+        }
+        if (0..3).any(|channel| domain_max[channel] <= domain_min[channel]) {
+            return Err(invalid("DOMAIN_MAX must exceed DOMAIN_MIN".to_string()));
+        }
+        Ok(Lut {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> [f32; 3] {
+        self.table[r + self.size * (g + self.size * b)]
+    }
+
+    /// Looks up a color with components in 0-1, trilinearly interpolating
+    /// between the surrounding entries.
+    fn lookup(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let max = (self.size - 1) as f32;
+        let position = [0, 1, 2].map(|channel| {
+            let range = self.domain_max[channel] - self.domain_min[channel];
+            ((rgb[channel] - self.domain_min[channel]) / range * max).clamp(0.0, max)
+        });
+        let low = position.map(|p| (p.floor() as usize).min(self.size - 2));
+        let [fr, fg, fb] = [0, 1, 2].map(|channel| position[channel] - low[channel] as f32);
+        let [r, g, b] = low;
+
+        let lerp = |a: [f32; 3], b: [f32; 3], t: f32| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t);
+        let c00 = lerp(self.entry(r, g, b), self.entry(r + 1, g, b), fr);
+        let c10 = lerp(self.entry(r, g + 1, b), self.entry(r + 1, g + 1, b), fr);
+        let c01 = lerp(self.entry(r, g, b + 1), self.entry(r + 1, g, b + 1), fr);
+        let c11 = lerp(
+            self.entry(r, g + 1, b + 1),
+            self.entry(r + 1, g + 1, b + 1),
+            fr,
+        );
+        lerp(lerp(c00, c10, fg), lerp(c01, c11, fg), fb)
+    }
+}
+
+fn parse_lut_triple<'a>(mut parts: impl Iterator<Item = &'a str>) -> Option<[f32; 3]> {
+    let values = [parts.next()?, parts.next()?, parts.next()?].map(|part| part.parse::<f32>());
+    if parts.next().is_some() {
+        return None;
+    }
+    let [r, g, b] = values;
+    Some([r.ok()?, g.ok()?, b.ok()?])
+}
+
+/// Color grades an image through a 3D LUT, in 8-bit RGB.
+pub fn apply_lut(img: &DynamicImage, lut: &Lut) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let [r, g, b, _] = pixel.0.map(|channel| f32::from(channel) / 255.0);
+        let graded = lut.lookup([r, g, b]);
+        for (channel, value) in pixel.0[..3].iter_mut().zip(graded) {
+            *channel = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    restore_alpha(rgba, has_alpha)
+}
+
+/// Per-channel counts of pixel values, 256 bins each.
+#[derive(Debug, Serialize)]
+pub struct Histogram {
+    pub red: Vec<u32>,
+    pub green: Vec<u32>,
+    pub blue: Vec<u32>,
+    pub luma: Vec<u32>,
+}
+
+/// Counts the 8-bit red, green, blue and luma values of every pixel.
+pub fn compute_histogram(img: &DynamicImage) -> Histogram {
+    let mut histogram = Histogram {
+        red: vec![0; 256],
+        green: vec![0; 256],
+        blue: vec![0; 256],
+        luma: vec![0; 256],
+    };
+    for pixel in img.to_rgb8().pixels() {
+        let [r, g, b] = pixel.0;
+        histogram.red[usize::from(r)] += 1;
+        histogram.green[usize::from(g)] += 1;
+        histogram.blue[usize::from(b)] += 1;
+        let value = luma(f32::from(r), f32::from(g), f32::from(b));
+        histogram.luma[value.round() as usize] += 1;
+    }
+    histogram
+}
+
+/// Whether any pixel is less than fully opaque.
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.pixels().any(|(_, _, pixel)| pixel.0[3] < u8::MAX)
+}
+
+/// Composites an image onto a solid color, removing its alpha channel.
+pub fn flatten(img: DynamicImage, color: Rgba<u8>) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let background = Rgba([color[0], color[1], color[2], u8::MAX]);
+    let mut canvas = RgbaImage::from_pixel(img.width(), img.height(), background);
+    imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+}
+
+/// Multiplies each pixel's color channels by its alpha. Opaque images are
+/// returned unchanged.
+pub fn premultiply_alpha(img: DynamicImage) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = u16::from(pixel.0[3]);
+        for channel in &mut pixel.0[..3] {
+            *channel = ((u16::from(*channel) * alpha + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Reverses [`premultiply_alpha`] for inputs stored with premultiplied alpha.
+/// Fully transparent pixels come out black.
+pub fn unpremultiply_alpha(img: DynamicImage) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = u16::from(pixel.0[3]);
+        for channel in &mut pixel.0[..3] {
+            *channel = match alpha {
+                0 => 0,
+                _ => ((u16::from(*channel) * 255 + alpha / 2) / alpha).min(255) as u8,
+            };
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Rows of a 3x5 pixel font, most significant of the three bits leftmost,
+/// covering what operation strings and card titles are made of.
+const STAMP_GLYPHS: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('a', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('b', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('c', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('d', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('e', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('f', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('g', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('h', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('i', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('j', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('k', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('l', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('m', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('n', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('o', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('p', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('r', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('s', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('t', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('u', [0b101, 0b101, 0b101, 0b101, 0b011]),
+    ('v', [0b101, 0b101, 0b101, 0b010, 0b010]),
+    ('w', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('x', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    (';', [0b000, 0b010, 0b000, 0b010, 0b100]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('@', [0b010, 0b101, 0b111, 0b100, 0b011]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('"', [0b101, 0b101, 0b000, 0b000, 0b000]),
+    ('&', [0b010, 0b101, 0b010, 0b101, 0b011]),
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+];
+/// Drawn for characters missing from [`STAMP_GLYPHS`].
+const STAMP_UNKNOWN_GLYPH: [u8; 5] = [0b111, 0b001, 0b010, 0b000, 0b010];
+/// Width of a glyph plus the gap after it, and height of a line, in font pixels.
+const STAMP_ADVANCE: (u32, u32) = (4, 6);
+/// Opacity of the box behind the stamped text.
+const STAMP_BOX_ALPHA: f32 = 0.7;
+
+/// Writes `text` in small white letters on a dark box in the bottom-left
+/// corner, wrapping it to the image width. The letters are scaled up with
+/// the image so they stay readable; parts not fitting the image are cut off.
+/// The result is 8-bit, with the color type of the source otherwise.
+pub fn debug_stamp(img: DynamicImage, text: &str) -> DynamicImage {
+    let color = img.color();
+    let mut rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let scale = (width.min(height) / 256).max(1);
+    let (advance_x, advance_y) = (STAMP_ADVANCE.0 * scale, STAMP_ADVANCE.1 * scale);
+    let padding = scale;
+
+    let chars: Vec<char> = text.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let per_line = (width.saturating_sub(padding * 2) / advance_x).max(1) as usize;
+    let lines: Vec<&[char]> = chars.chunks(per_line).collect();
+    let longest = lines.iter().map(|line| line.len()).max().unwrap_or(0) as u32;
+    let box_width = (longest * advance_x + padding * 2).min(width);
+    let box_height = (lines.len() as u32 * advance_y + padding * 2).min(height);
+    let top = height - box_height;
+
+    for y in top..height {
+        for x in 0..box_width {
+            let pixel = rgba.get_pixel_mut(x, y);
+            for channel in &mut pixel.0[..3] {
+                *channel = (f32::from(*channel) * (1.0 - STAMP_BOX_ALPHA)).round() as u8;
+            }
+            pixel.0[3] = pixel.0[3].max((STAMP_BOX_ALPHA * 255.0).round() as u8);
+        }
+    }
+    for (row, line) in lines.iter().enumerate() {
+        let line_top = top + padding + row as u32 * advance_y;
+        draw_glyphs(&mut rgba, line, (padding, line_top), scale);
+    }
+
+    let stamped = DynamicImage::ImageRgba8(rgba);
+    match (color.has_color(), color.has_alpha()) {
+        (true, true) => stamped,
+        (true, false) => DynamicImage::ImageRgb8(stamped.to_rgb8()),
+        (false, true) => DynamicImage::ImageLumaA8(stamped.to_luma_alpha8()),
+        (false, false) => DynamicImage::ImageLuma8(stamped.to_luma8()),
+    }
+}
+
+/// Draws a line of text in white in the [`STAMP_GLYPHS`] font, with its
+/// top-left corner at `(left, top)` and each font pixel `scale` pixels wide.
+/// Parts outside the image are cut off.
+fn draw_glyphs(rgba: &mut RgbaImage, line: &[char], (left, top): (u32, u32), scale: u32) {
+    let (width, height) = rgba.dimensions();
+    for (column, c) in line.iter().enumerate() {
+        let glyph = STAMP_GLYPHS
+            .iter()
+            .find(|(glyph_char, _)| glyph_char == c)
+            .map_or(STAMP_UNKNOWN_GLYPH, |&(_, glyph)| glyph);
+        let glyph_left = left + column as u32 * STAMP_ADVANCE.0 * scale;
+        for (glyph_y, bits) in glyph.iter().enumerate() {
+            for glyph_x in 0..3 {
+                if bits & (0b100 >> glyph_x) == 0 {
+                    continue;
+                }
+                let x0 = glyph_left + glyph_x * scale;
+                let y0 = top + glyph_y as u32 * scale;
+                for y in y0..(y0 + scale).min(height) {
+                    for x in x0..(x0 + scale).min(width) {
+                        rgba.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Size of the cards made by [`og_card`], as recommended for OpenGraph images.
+pub const OG_CARD_SIZE: (u32, u32) = (1200, 630);
+/// Font pixel sizes of the card title and subtitle.
+const OG_CARD_SCALES: (u32, u32) = (12, 6);
+/// Lines of title and subtitle shown; longer text is cut off with an ellipsis.
+const OG_CARD_MAX_LINES: (usize, usize) = (3, 2);
+/// Space kept clear around the card text, and between title and subtitle.
+const OG_CARD_MARGIN: u32 = 64;
+const OG_CARD_TEXT_GAP: u32 = 24;
+/// Brightness the gradient leaves the background with at its top and bottom.
+const OG_CARD_SHADE: (f32, f32) = (0.8, 0.25);
+
+/// Renders a social-share card: `background` cropped to fill
+/// [`OG_CARD_SIZE`] and darkened by a gradient towards the bottom, where
+/// `title` and `subtitle` are written in the [`debug_stamp`] font.
+pub fn og_card(background: DynamicImage, title: &str, subtitle: Option<&str>) -> DynamicImage {
+    let (width, height) = OG_CARD_SIZE;
+    let mut card =
+        cover_image(background, width, height, imageops::FilterType::Lanczos3).to_rgba8();
+    for (_, y, pixel) in card.enumerate_pixels_mut() {
+        let shade =
+            OG_CARD_SHADE.0 + (OG_CARD_SHADE.1 - OG_CARD_SHADE.0) * y as f32 / (height - 1) as f32;
+        for channel in &mut pixel.0[..3] {
+            *channel = (f32::from(*channel) * shade).round() as u8;
+        }
+        pixel.0[3] = 255;
+    }
+
+    let text_width = width - 2 * OG_CARD_MARGIN;
+    let (title_scale, subtitle_scale) = OG_CARD_SCALES;
+    let title_lines = wrap_text(
+        title,
+        text_width / (STAMP_ADVANCE.0 * title_scale),
+        OG_CARD_MAX_LINES.0,
+    );
+    let subtitle_lines = subtitle.map_or_else(Vec::new, |subtitle| {
+        wrap_text(
+            subtitle,
+            text_width / (STAMP_ADVANCE.0 * subtitle_scale),
+            OG_CARD_MAX_LINES.1,
+        )
+    });
+
+    // Stacked upwards from the bottom margin, subtitle last
+    let subtitle_height = subtitle_lines.len() as u32 * STAMP_ADVANCE.1 * subtitle_scale;
+    let subtitle_top = height - OG_CARD_MARGIN - subtitle_height;
+    let title_bottom = match subtitle_height {
+        0 => subtitle_top,
+        _ => subtitle_top - OG_CARD_TEXT_GAP,
+    };
+    let title_top = title_bottom - title_lines.len() as u32 * STAMP_ADVANCE.1 * title_scale;
+    for (lines, top, scale) in [
+        (&title_lines, title_top, title_scale),
+        (&subtitle_lines, subtitle_top, subtitle_scale),
+    ] {
+        for (row, line) in lines.iter().enumerate() {
+            let line_top = top + row as u32 * STAMP_ADVANCE.1 * scale;
+            draw_glyphs(&mut card, line, (OG_CARD_MARGIN, line_top), scale);
+        }
+    }
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(card).to_rgb8())
 }
 
-pub async fn fetch_image_bytes_from_url(url: &str) -> Result<Bytes, AppError> {
-    let response = reqwest::get(url).await?;
-    if !response.status().is_success() {
-        return Err(AppError::ImageFetchError(format!(
-            "failed to fetch image: server responded with {}",
-            response.status()
-        )));
+/// Wraps `text` at spaces into lines of at most `per_line` characters,
+/// splitting words longer than a line. Lines past `max_lines` are dropped,
+/// with an ellipsis marking the cut.
+fn wrap_text(text: &str, per_line: u32, max_lines: usize) -> Vec<Vec<char>> {
+    let per_line = (per_line as usize).max(4);
+    let mut lines: Vec<Vec<char>> = Vec::new();
+    let mut line = Vec::new();
+    for word in text.split_whitespace() {
+        let word: Vec<char> = word.chars().map(|c| c.to_ascii_lowercase()).collect();
+        if !line.is_empty() && line.len() + 1 + word.len() > per_line {
+            lines.push(std::mem::take(&mut line));
+        }
+        for chunk in word.chunks(per_line) {
+            if !line.is_empty() {
+                if line.len() + 1 + chunk.len() > per_line {
+                    lines.push(std::mem::take(&mut line));
+                } else {
+                    line.push(' ');
+                }
+            }
+            line.extend_from_slice(chunk);
+        }
     }
-    let bytes = response.bytes().await?;
-    Ok(bytes)
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        let last = lines.last_mut().expect("max_lines is at least 1");
+        last.truncate(per_line - 3);
+        last.extend(['.', '.', '.']);
+    }
+    lines
 }
 
-pub fn resize_image(
+/// Crops away the borders matching the top-left pixel. A pixel counts as
+/// border when its alpha is at most `alpha_tolerance`, or when every color
+/// channel is within `color_tolerance` and its alpha within `alpha_tolerance`
+/// of the top-left pixel. With `alpha_only`, only the first test is used, so
+/// opaque borders are kept. Images with no content to crop to are returned
+/// unchanged.
+pub fn autocrop(
     img: DynamicImage,
-    nwidth: u32,
-    nheight: u32,
-    filter: imageops::FilterType,
+    color_tolerance: u8,
+    alpha_tolerance: u8,
+    alpha_only: bool,
 ) -> DynamicImage {
-    img.resize_exact(nwidth, nheight, filter)
+    let rgba = img.to_rgba8();
+    let background = *rgba.get_pixel(0, 0);
+    let is_content = |x, y| {
+        let pixel = rgba.get_pixel(x, y);
+        if pixel.0[3] <= alpha_tolerance {
+            return false;
+        }
+        alpha_only
+            || pixel.0[3].abs_diff(background.0[3]) > alpha_tolerance
+            || pixel.0[..3]
+                .iter()
+                .zip(&background.0[..3])
+                .any(|(&channel, &key)| channel.abs_diff(key) > color_tolerance)
+    };
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for y in 0..rgba.height() {
+        for x in 0..rgba.width() {
+            if is_content(x, y) {
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+        }
+    }
+    if min_x > max_x {
+        return img;
+    }
+    img.crop_imm(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)
 }
 
-pub fn crop_image(
+/// Flattens an image to 8-bit samples, keeping alpha only when the image has
+/// it. Returns the interleaved pixel rows and the number of channels.
+pub fn raw_pixels(img: DynamicImage) -> (Vec<u8>, u16) {
+    if img.color().has_alpha() {
+        (img.into_rgba8().into_raw(), 4)
+    } else {
+        (img.into_rgb8().into_raw(), 3)
+    }
+}
+
+/// Serializes the pixels from [`raw_pixels`] as a NumPy `.npy` (version 1.0)
+/// array of `uint8` with shape `(height, width, channels)`.
+pub fn encode_npy(img: DynamicImage) -> Vec<u8> {
+    let (width, height) = img.dimensions();
+    let (pixels, channels) = raw_pixels(img);
+    let mut header = format!(
+        "{{'descr': '|u1', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+        height, width, channels
+    );
+    // Magic, version and header length take 10 bytes, and the data must start
+    // on a 64-byte boundary, after a newline-terminated header
+    let padding = 63 - (NPY_PREAMBLE_LEN + header.len()) % 64;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    let mut npy = Vec::with_capacity(NPY_PREAMBLE_LEN + header.len() + pixels.len());
+    npy.extend_from_slice(b"\x93NUMPY\x01\x00");
+    npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    npy.extend_from_slice(header.as_bytes());
+    npy.extend_from_slice(&pixels);
+    npy
+}
+
+const NPY_PREAMBLE_LEN: usize = 10;
+
+pub fn encode_image_to_bytes(
     img: DynamicImage,
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
-) -> Result<DynamicImage, AppError> {
-    if x.saturating_add(width) > img.width() || y.saturating_add(height) > img.height() {
-        return Err(AppError::InvalidCropDimensions(
-            "crop window is outside the image bounds.",
-        ));
+    options: &EncodeOptions,
+) -> Result<ProcessedImage, AppError> {
+    let (width, height) = (img.width(), img.height());
+    let options = &options.for_image(&img);
+    let mut buffer = Vec::new();
+    encode_image(img, options, &mut buffer)?;
+    #[cfg(feature = "oxipng")]
+    if options.optimize && options.format == OutputFormat::Png {
+        buffer = optimize_png(&buffer, options.effort)?;
     }
-    Ok(img.crop_imm(x, y, width, height))
+    let (quality, quality_clamped) = options.effective_quality();
+
+    Ok(ProcessedImage {
+        bytes: buffer,
+        mime_type: options.format.mime_type().to_string(),
+        width,
+        height,
+        quality,
+        quality_clamped,
+    })
 }
 
-pub fn apply_filter_str(img: DynamicImage, filter_str: &str) -> Result<DynamicImage, AppError> {
-    let parts: Vec<&str> = filter_str.split(':').collect();
-    let filter_name = parts[0].to_lowercase();
-
-    match filter_name.as_str() {
-        "grayscale" => Ok(img.grayscale()),
-        "invert" => {
-            let mut mutable_img = img;
-            imageops::invert(&mut mutable_img);
-            Ok(mutable_img)
-        }
-        "blur" => {
-            let sigma = if parts.len() > 1 {
-                parts[1].parse::<f32>().map_err(|_| {
-                    AppError::InvalidFilterParameters("invalid blur sigma value".to_string())
-                })?
-            } else {
-                1.0 // Default sigma
+/// Recompresses an encoded PNG with oxipng, using one of its presets (0-6)
+/// scaled from the encoder effort.
+#[cfg(feature = "oxipng")]
+fn optimize_png(png: &[u8], effort: u8) -> Result<Vec<u8>, AppError> {
+    let options = oxipng::Options::from_preset(effort.min(MAX_EFFORT) * 6 / MAX_EFFORT);
+    oxipng::optimize_from_memory(png, &options)
+        .map_err(|err| AppError::OptimizationError(err.to_string()))
+}
+
+/// Encodes an 8-bit grayscale or RGB image with mozjpeg.
+#[cfg(feature = "mozjpeg")]
+fn encode_mozjpeg<W: Write>(img: &DynamicImage, quality: u8, writer: W) -> Result<(), AppError> {
+    use mozjpeg::{ColorSpace, Compress};
+
+    let color_space = match img.color() {
+        ColorType::L8 => ColorSpace::JCS_GRAYSCALE,
+        _ => ColorSpace::JCS_RGB,
+    };
+    // mozjpeg reports errors by unwinding
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut compress = Compress::new(color_space);
+        compress.set_size(img.width() as usize, img.height() as usize);
+        compress.set_quality(f32::from(quality));
+        let mut started = compress.start_compress(writer)?;
+        started.write_scanlines(img.as_bytes())?;
+        started.finish().map(drop)
+    }))
+    .map_err(|_| std::io::Error::other("mozjpeg failed to encode the image"))??;
+    Ok(())
+}
+
+/// Encodes an image as lossy WebP with libwebp, with the alpha channel at its
+/// own quality and the compression method (0-6) scaled from the effort.
+#[cfg(feature = "libwebp")]
+fn encode_libwebp<W: Write>(
+    img: DynamicImage,
+    options: &EncodeOptions,
+    quality: u8,
+    mut writer: W,
+) -> Result<(), AppError> {
+    let (width, height) = (img.width(), img.height());
+    let (pixels, has_alpha) = if img.color().has_alpha() {
+        (img.to_rgba8().into_raw(), true)
+    } else {
+        (img.to_rgb8().into_raw(), false)
+    };
+    let encoder = if has_alpha {
+        webp::Encoder::from_rgba(&pixels, width, height)
+    } else {
+        webp::Encoder::from_rgb(&pixels, width, height)
+    };
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| std::io::Error::other("libwebp failed to set up the encoder"))?;
+    config.lossless = 0;
+    config.quality = f32::from(quality);
+    config.alpha_quality = i32::from(options.webp_alpha_quality.unwrap_or(quality).min(100));
+    config.method = i32::from(options.effort.min(MAX_EFFORT) * 6 / MAX_EFFORT);
+    let webp = encoder.encode_advanced(&config).map_err(|err| {
+        std::io::Error::other(format!("libwebp failed to encode the image: {:?}", err))
+    })?;
+    writer.write_all(&webp)?;
+    Ok(())
+}
+
+/// Encodes an image into `writer`, which only needs to support sequential
+/// writes so the output can be streamed.
+pub fn encode_image<W: Write>(
+    img: DynamicImage,
+    options: &EncodeOptions,
+    mut writer: W,
+) -> Result<(), AppError> {
+    match options.format {
+        OutputFormat::Jpeg => {
+            let (quality, _) = options.effective_quality();
+            let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+            let img = jpeg_compatible(img);
+            match options.jpeg_backend {
+                #[cfg(feature = "mozjpeg")]
+                JpegBackend::Mozjpeg => encode_mozjpeg(&img, quality, writer)?,
+                _ => img.write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality))?,
+            }
+        }
+        OutputFormat::Png => {
+            img.write_with_encoder(PngEncoder::new_with_quality(
+                &mut writer,
+                options.png_compression,
+                options.png_filter,
+            ))?;
+        }
+        OutputFormat::WebP => match options.effective_quality() {
+            #[cfg(feature = "libwebp")]
+            (Some(quality), _) => encode_libwebp(img, options, quality, writer)?,
+            _ => eight_bit(img).write_with_encoder(WebPEncoder::new_lossless(&mut writer))?,
+        },
+        OutputFormat::Bmp => {
+            eight_bit(img).write_with_encoder(BmpEncoder::new(&mut writer))?;
+        }
+        OutputFormat::Gif => {
+            GifEncoder::new(&mut writer).encode_frame(Frame::new(img.to_rgba8()))?;
+        }
+        OutputFormat::Avif => {
+            let (quality, _) = options.effective_quality();
+            // AVIF speed runs the other way, from 1 (slowest) to 10
+            let speed = (MAX_EFFORT - options.effort).max(1);
+            eight_bit(img).write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut writer,
+                speed,
+                quality.unwrap_or(DEFAULT_AVIF_QUALITY),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn north_gravity_crops_from_the_top() {
+        assert_eq!(gravity_offset(Gravity::North, 40, 30, 100, 80), (30, 0));
+        assert_eq!(gravity_offset(Gravity::NorthWest, 40, 30, 100, 80), (0, 0));
+    }
+
+    #[test]
+    fn south_gravity_crops_from_the_bottom() {
+        assert_eq!(gravity_offset(Gravity::South, 40, 30, 100, 80), (30, 50));
+        assert_eq!(
+            gravity_offset(Gravity::SouthEast, 40, 30, 100, 80),
+            (60, 50)
+        );
+        assert_eq!("south".parse::<Gravity>().unwrap(), Gravity::South);
+    }
+
+    #[test]
+    fn centered_half_size_fractional_crop() {
+        assert_eq!(
+            fractional_crop_window(0.25, 0.25, 0.5, 0.5, 200, 100).unwrap(),
+            (50, 25, 100, 50)
+        );
+    }
+
+    #[test]
+    fn fractional_crops_must_stay_inside_the_image() {
+        assert!(fractional_crop_window(-0.1, 0.0, 0.5, 0.5, 100, 100).is_err());
+        assert!(fractional_crop_window(0.0, 0.0, 1.5, 0.5, 100, 100).is_err());
+        assert!(fractional_crop_window(0.6, 0.0, 0.5, 0.5, 100, 100).is_err());
+    }
+
+    #[test]
+    fn encode_options_select_each_output_format() {
+        let img = DynamicImage::new_rgb8(8, 8);
+        for format in [
+            OutputFormat::Png,
+            OutputFormat::Jpeg,
+            OutputFormat::WebP,
+            OutputFormat::Bmp,
+            OutputFormat::Gif,
+        ] {
+            let processed =
+                encode_image_to_bytes(img.clone(), &EncodeOptions::new(format)).unwrap();
+            assert_eq!(processed.mime_type, format.mime_type());
+            assert_eq!(
+                image::guess_format(&processed.bytes).unwrap(),
+                format.image_format()
+            );
+            assert_eq!(format.name().parse::<OutputFormat>().unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn encode_options_carry_jpeg_quality() {
+        let options = EncodeOptions {
+            quality: Some(120),
+            ..EncodeOptions::new(OutputFormat::Jpeg)
+        };
+        let processed = encode_image_to_bytes(DynamicImage::new_rgb8(8, 8), &options).unwrap();
+        assert_eq!(processed.quality, Some(100));
+        assert!(processed.quality_clamped);
+
+        let processed = encode_image_to_bytes(
+            DynamicImage::new_rgb8(8, 8),
+            &EncodeOptions::new(OutputFormat::Png),
+        )
+        .unwrap();
+        assert_eq!(processed.quality, None);
+    }
+
+    #[test]
+    fn best_png_compression_is_no_larger_than_fast() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 128, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x ^ y) % 7 * 30) as u8])
+        }));
+        let encode = |compression| {
+            let options = EncodeOptions {
+                png_compression: compression,
+                ..EncodeOptions::new(OutputFormat::Png)
             };
-            Ok(img.blur(sigma))
+            encode_image_to_bytes(img.clone(), &options).unwrap().bytes
+        };
+
+        let fast = encode(parse_png_compression("fast").unwrap());
+        let best = encode(parse_png_compression("best").unwrap());
+        assert!(
+            best.len() <= fast.len(),
+            "best {} > fast {}",
+            best.len(),
+            fast.len()
+        );
+        assert!(parse_png_compression("smallest").is_err());
+    }
+
+    #[test]
+    fn grayscale_of_transparent_rgba_encodes_to_png_and_jpeg() {
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            8,
+            8,
+            image::Rgba([200, 100, 50, 0]),
+        ));
+        let gray = apply_filter_str(rgba, "grayscale").unwrap();
+
+        let png =
+            encode_image_to_bytes(gray.clone(), &EncodeOptions::new(OutputFormat::Png)).unwrap();
+        let decoded = image::load_from_memory(&png.bytes).unwrap();
+        assert_eq!(decoded.color(), ColorType::La8);
+
+        let jpeg = encode_image_to_bytes(gray, &EncodeOptions::new(OutputFormat::Jpeg)).unwrap();
+        let decoded = image::load_from_memory(&jpeg.bytes).unwrap();
+        assert_eq!(decoded.color(), ColorType::L8);
+    }
+
+    /// A 256x256 image with large features, which survive resizing.
+    fn shapes() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+            let in_circle = (x as i32 - 80).pow(2) + (y as i32 - 90).pow(2) < 50 * 50;
+            let in_bar = (150..220).contains(&x) && (40..230).contains(&y);
+            match (in_circle, in_bar) {
+                (true, _) => image::Rgb([240, 200, 40]),
+                (_, true) => image::Rgb([30, 60, 160]),
+                _ => image::Rgb([(x / 2) as u8, (y / 2) as u8, 128]),
+            }
+        }))
+    }
+
+    #[test]
+    fn perceptual_hashes_survive_resizing() {
+        let original = shapes();
+        let resized = original.resize_exact(97, 131, imageops::FilterType::Lanczos3);
+        let different = original.rotate180();
+        for algorithm in [
+            HashAlgorithm::AHash,
+            HashAlgorithm::DHash,
+            HashAlgorithm::PHash,
+        ] {
+            let hash = perceptual_hash(&original, algorithm);
+            let distance = |img| (hash ^ perceptual_hash(img, algorithm)).count_ones();
+            assert!(
+                distance(&resized) <= 6,
+                "{:?}: {}",
+                algorithm,
+                distance(&resized)
+            );
+            assert!(
+                distance(&different) >= 20,
+                "{:?}: {}",
+                algorithm,
+                distance(&different)
+            );
+        }
+    }
+
+    #[test]
+    fn alpha_filter_renders_transparency_as_black() {
+        let half = image::RgbaImage::from_fn(8, 4, |x, _| {
+            image::Rgba([255, 0, 0, if x < 4 { 0 } else { 255 }])
+        });
+        let png = encode_image_to_bytes(
+            DynamicImage::ImageRgba8(half),
+            &EncodeOptions::new(OutputFormat::Png),
+        )
+        .unwrap();
+        let img = image::load_from_memory(&png.bytes).unwrap();
+
+        let alpha = apply_filter_str(img, "alpha").unwrap().to_luma8();
+        assert_eq!(alpha.get_pixel(0, 0).0, [0]);
+        assert_eq!(alpha.get_pixel(7, 3).0, [255]);
+    }
+
+    #[test]
+    fn alpha_filter_on_an_opaque_jpeg_is_all_white() {
+        let jpeg = encode_image_to_bytes(
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 4, image::Rgb([10, 20, 30]))),
+            &EncodeOptions::new(OutputFormat::Jpeg),
+        )
+        .unwrap();
+        let img = image::load_from_memory(&jpeg.bytes).unwrap();
+
+        let alpha = apply_filter_str(img, "alpha").unwrap().to_luma8();
+        assert_eq!(alpha.dimensions(), (8, 4));
+        assert!(alpha.pixels().all(|pixel| pixel.0 == [255]));
+    }
+
+    #[test]
+    fn background_shows_through_and_blends_at_soft_edges() {
+        // An opaque red square with a half-transparent border, on nothing
+        let foreground = image::RgbaImage::from_fn(10, 10, |x, y| match (x, y) {
+            (3..=6, 3..=6) => image::Rgba([255, 0, 0, 255]),
+            (2..=7, 2..=7) => image::Rgba([255, 0, 0, 128]),
+            _ => image::Rgba([0, 0, 0, 0]),
+        });
+        let background = image::RgbImage::from_pixel(40, 20, image::Rgb([0, 0, 255]));
+
+        let composite = composite_over_background(
+            DynamicImage::ImageRgba8(foreground),
+            DynamicImage::ImageRgb8(background),
+            Gravity::Center,
+        )
+        .to_rgba8();
+        assert_eq!(composite.dimensions(), (10, 10));
+        assert_eq!(composite.get_pixel(0, 0).0, [0, 0, 255, 255]);
+        assert_eq!(composite.get_pixel(5, 5).0, [255, 0, 0, 255]);
+
+        let [r, g, b, a] = composite.get_pixel(2, 5).0;
+        assert!(
+            (120..=136).contains(&r) && (120..=136).contains(&b),
+            "{:?}",
+            (r, b)
+        );
+        assert_eq!(g, 0);
+        assert!(a >= 254);
+    }
+
+    #[tokio::test]
+    async fn local_images_must_stay_inside_the_base_directory() {
+        let root = std::env::temp_dir().join(format!("local-files-{}", std::process::id()));
+        let base = root.join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(base.join("inside.png"), b"inside").unwrap();
+        std::fs::write(root.join("outside.png"), b"outside").unwrap();
+
+        let bytes = read_local_image(&base, "inside.png").await.unwrap();
+        assert_eq!(&bytes[..], b"inside");
+
+        let escape = read_local_image(&base, "../outside.png").await;
+        assert!(matches!(escape, Err(AppError::LocalFileAccessDenied(_))));
+
+        let absolute = root.join("outside.png");
+        let absolute = read_local_image(&base, absolute.to_str().unwrap()).await;
+        assert!(matches!(absolute, Err(AppError::LocalFileAccessDenied(_))));
+
+        let missing = read_local_image(&base, "missing.png").await;
+        assert!(matches!(missing, Err(AppError::LocalFileNotFound(_))));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn source_hosts_are_checked_against_the_allowlist() {
+        let allowed = ["cdn.example.org".to_string(), "*.example.com".to_string()];
+        assert!(check_source_host("https://cdn.example.org/a.png", &allowed).is_ok());
+        assert!(matches!(
+            check_source_host("https://evil.test/a.png", &allowed),
+            Err(AppError::SourceHostNotAllowed(host)) if host == "evil.test"
+        ));
+        assert!(check_source_host("https://anything.goes/a.png", &[]).is_ok());
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        assert!(host_matches("img.example.com", "*.example.com"));
+        assert!(host_matches("a.b.Example.com.", "*.example.com"));
+        assert!(!host_matches("example.com", "*.example.com"));
+        assert!(!host_matches("badexample.com", "*.example.com"));
+        assert!(!host_matches("img.example.com", "example.com"));
+    }
+
+    #[test]
+    fn autocrop_trims_to_the_opaque_shape() {
+        let mut canvas = image::RgbaImage::new(64, 48);
+        for y in 20..26 {
+            for x in 30..40 {
+                canvas.put_pixel(x, y, image::Rgba([255, 255, 0, 255]));
+            }
         }
-        "sharpen" => {
-            let sigma = if parts.len() > 1 {
-                parts[1].parse::<f32>().map_err(|_| {
-                    AppError::InvalidFilterParameters("invalid sharpen sigma value".to_string())
-                })?
+
+        let cropped = apply_filter_str(DynamicImage::ImageRgba8(canvas), "autocrop").unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (10, 6));
+        assert!(cropped.to_rgba8().pixels().all(|pixel| pixel.0[3] == 255));
+    }
+
+    #[test]
+    fn autocrop_trims_a_uniform_background_without_alpha() {
+        let img = image::RgbImage::from_fn(20, 20, |x, y| {
+            if (5..15).contains(&x) && (8..12).contains(&y) {
+                image::Rgb([0, 0, 0])
             } else {
-                1.0 // Default sigma
+                image::Rgb([255, 255, 255])
+            }
+        });
+
+        let cropped = autocrop(DynamicImage::ImageRgb8(img), 0, 0, false);
+        assert_eq!((cropped.width(), cropped.height()), (10, 4));
+    }
+
+    #[test]
+    fn quality_presets_map_to_numbers_per_format() {
+        let high: Quality = "high".parse().unwrap();
+        assert_eq!(high.for_format(OutputFormat::Jpeg), Some(85));
+        assert_eq!(high.for_format(OutputFormat::WebP), Some(90));
+        assert_eq!("70".parse::<Quality>().unwrap(), Quality::Value(70));
+        assert!("hgih".parse::<Quality>().is_err());
+    }
+
+    #[test]
+    fn high_quality_preset_produces_a_larger_jpeg_than_low() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }));
+        let encode = |preset: &str| {
+            let options = EncodeOptions {
+                quality: preset
+                    .parse::<Quality>()
+                    .unwrap()
+                    .for_format(OutputFormat::Jpeg),
+                ..EncodeOptions::new(OutputFormat::Jpeg)
             };
-            let threshold = if parts.len() > 2 {
-                parts[2].parse::<i32>().map_err(|_| {
-                    AppError::InvalidFilterParameters("invalid sharpen threshold value".to_string())
-                })?
+            encode_image_to_bytes(img.clone(), &options).unwrap()
+        };
+
+        let (low, high) = (encode("low"), encode("high"));
+        assert_eq!(high.quality, Some(85));
+        assert!(high.bytes.len() > low.bytes.len());
+    }
+
+    #[test]
+    fn filter_chains_normalize_to_canonical_form() {
+        assert_eq!(
+            normalize_filter_str("Grayscale,blur").unwrap(),
+            "grayscale,blur:1"
+        );
+        assert_eq!(
+            normalize_filter_str(" greyscale , SHARPEN:2 ").unwrap(),
+            "grayscale,sharpen:2:0"
+        );
+        assert!(normalize_filter_str("grayscale,sparkle").is_err());
+    }
+
+    /// A small JPEG carrying an EXIF segment with the given fields.
+    fn jpeg_with_exif(fields: &[exif::Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        // The APP1 length counts itself and the "Exif\0\0" prefix
+        let len = u16::try_from(tiff.len() + 8).unwrap();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&len.to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    #[test]
+    fn exif_tags_are_read_and_gps_can_be_omitted() {
+        let field = |tag, value| exif::Field {
+            tag,
+            ifd_num: exif::In::PRIMARY,
+            value,
+        };
+        let jpeg = jpeg_with_exif(&[
+            field(exif::Tag::Make, exif::Value::Ascii(vec![b"Canon".to_vec()])),
+            field(
+                exif::Tag::Model,
+                exif::Value::Ascii(vec![b"EOS 5D".to_vec()]),
+            ),
+            field(
+                exif::Tag::ExposureTime,
+                exif::Value::Rational(vec![exif::Rational { num: 1, denom: 250 }]),
+            ),
+            field(
+                exif::Tag::GPSLatitudeRef,
+                exif::Value::Ascii(vec![b"N".to_vec()]),
+            ),
+        ]);
+
+        let tags = read_exif_tags(&jpeg, true).unwrap();
+        assert_eq!(tags["Make"], "Canon");
+        assert_eq!(tags["Model"], "EOS 5D");
+        assert_eq!(tags["ExposureTime"], "1/250 s");
+        assert_eq!(tags["GPSLatitudeRef"], "N");
+
+        let tags = read_exif_tags(&jpeg, false).unwrap();
+        assert_eq!(tags["Make"], "Canon");
+        assert!(!tags.contains_key("GPSLatitudeRef"));
+
+        let plain = encode_image_to_bytes(
+            DynamicImage::new_rgb8(4, 4),
+            &EncodeOptions::new(OutputFormat::Png),
+        )
+        .unwrap();
+        assert!(read_exif_tags(&plain.bytes, true).unwrap().is_empty());
+    }
+
+    /// A black and white checkerboard of 1px squares.
+    fn checkerboard(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            if (x + y) % 2 == 0 {
+                image::Rgb([255, 255, 255])
             } else {
-                // A common default threshold for unsharpen mask, may need tuning
-                0
-            };
-            // Convert to Rgba8 buffer, apply unsharpen, then convert back to DynamicImage
-            let rgba8 = img.to_rgba8();
-            let result = imageops::unsharpen(&rgba8, sigma, threshold);
-            Ok(image::DynamicImage::ImageRgba8(result))
-        }
-        // Example: "brighten:10"
-        "brighten" => {
-            let value = if parts.len() > 1 {
-                parts[1].trim().parse::<i32>().map_err(|_| {
-                    AppError::InvalidFilterParameters("invalid brighten value.".to_string())
-                })?
+                image::Rgb([0, 0, 0])
+            }
+        }))
+    }
+
+    #[test]
+    fn blur_region_only_touches_pixels_inside_it() {
+        let original = checkerboard(20, 20);
+        let (region, sigma) = parse_blur_region("5,5,10,10:2").unwrap();
+        let blurred = blur_region(original.clone(), region, sigma)
+            .unwrap()
+            .to_rgb8();
+        let original = original.to_rgb8();
+
+        for (x, y, pixel) in blurred.enumerate_pixels() {
+            let inside = (5..15).contains(&x) && (5..15).contains(&y);
+            if inside {
+                assert!(pixel.0[0] > 0 && pixel.0[0] < 255, "({}, {})", x, y);
             } else {
-                10 // Default brighten value
-            };
-            Ok(img.brighten(value))
-        }
-        // Example: "contrast:15.5"
-        "contrast" => {
-            let value = if parts.len() > 1 {
-                parts[1].trim().parse::<f32>().map_err(|_| {
-                    AppError::InvalidFilterParameters("invalid contrast value.".to_string())
-                })?
+                assert_eq!(pixel, original.get_pixel(x, y), "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn blur_region_must_lie_within_the_image() {
+        let (region, sigma) = parse_blur_region("15,0,10,10").unwrap();
+        assert_eq!(sigma, 1.0);
+        assert!(matches!(
+            blur_region(checkerboard(20, 20), region, sigma),
+            Err(AppError::InvalidRegion(_))
+        ));
+        assert!(parse_blur_region("1,2,3").is_err());
+    }
+
+    #[test]
+    fn fill_rect_paints_only_the_region() {
+        let original = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            30,
+            30,
+            image::Rgb([200, 150, 100]),
+        ));
+        let region = parse_regions("5,5,10,10").unwrap()[0];
+        let black = parse_color("#000000").unwrap();
+        let filled = fill_rect(original, region, black).unwrap().to_rgb8();
+
+        for (x, y, pixel) in filled.enumerate_pixels() {
+            let inside = (5..15).contains(&x) && (5..15).contains(&y);
+            let expected = if inside { [0, 0, 0] } else { [200, 150, 100] };
+            assert_eq!(pixel.0, expected, "({}, {})", x, y);
+        }
+        assert!(fill_rect(DynamicImage::new_rgb8(10, 10), region, black).is_err());
+    }
+
+    #[test]
+    fn chromakey_removes_a_green_background() {
+        let img = image::RgbImage::from_fn(16, 16, |x, y| {
+            if (4..12).contains(&x) && (4..12).contains(&y) {
+                image::Rgb([200, 30, 30])
             } else {
-                10.0 // Default contrast value
+                // Slightly uneven green, as from a real screen
+                image::Rgb([(x % 3) as u8 * 10, 250, 5])
+            }
+        });
+
+        let keyed = apply_filter_str(DynamicImage::ImageRgb8(img), "chromakey:00ff00:40")
+            .unwrap()
+            .to_rgba8();
+        for (x, y, pixel) in keyed.enumerate_pixels() {
+            let subject = (4..12).contains(&x) && (4..12).contains(&y);
+            assert_eq!(pixel.0[3], if subject { 255 } else { 0 }, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn premultiplying_half_transparent_pixels_halves_their_color() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            1,
+            1,
+            image::Rgba([200, 100, 50, 128]),
+        ));
+
+        let premultiplied = premultiply_alpha(img);
+        assert_eq!(
+            premultiplied.to_rgba8().get_pixel(0, 0).0,
+            [100, 50, 25, 128]
+        );
+
+        let restored = unpremultiply_alpha(premultiplied);
+        assert_eq!(restored.to_rgba8().get_pixel(0, 0).0, [199, 100, 50, 128]);
+    }
+
+    #[test]
+    fn autoenhance_changes_a_dull_photo_and_is_a_no_op_at_zero() {
+        let dull = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([100 + x as u8, 110 + y as u8, 120])
+        }));
+
+        let enhanced = "autoenhance:1"
+            .parse::<Filter>()
+            .unwrap()
+            .apply(dull.clone());
+        assert_ne!(enhanced.to_rgb8(), dull.to_rgb8());
+        let [min, max] = enhanced
+            .to_luma8()
+            .pixels()
+            .fold([255, 0], |[min, max], p| [min.min(p[0]), max.max(p[0])]);
+        assert!(max - min > 100, "levels not stretched: {}..{}", min, max);
+
+        let untouched = "autoenhance:0"
+            .parse::<Filter>()
+            .unwrap()
+            .apply(dull.clone());
+        assert_eq!(untouched.to_rgb8(), dull.to_rgb8());
+    }
+
+    #[test]
+    fn grayscale_strength_blends_with_the_original() {
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([200, 0, 0])));
+        let full = red.grayscale().to_rgb8().get_pixel(0, 0).0;
+
+        let none = apply_filter_str(red.clone(), "grayscale:0").unwrap();
+        assert_eq!(none.to_rgb8(), red.to_rgb8());
+
+        let half = apply_filter_str(red.clone(), "grayscale:0.5").unwrap();
+        let half = half.to_rgb8().get_pixel(0, 0).0;
+        for ((&mixed, &original), &gray) in half.iter().zip(&[200u8, 0, 0]).zip(&full) {
+            let midpoint = (f32::from(original) + f32::from(gray)) / 2.0;
+            assert!(
+                (f32::from(mixed) - midpoint).abs() <= 1.0,
+                "{:?} vs {:?}",
+                half,
+                full
+            );
+        }
+
+        assert!(apply_filter_str(red, "grayscale:1.5").is_err());
+    }
+
+    #[test]
+    fn colors_parse_from_hex_and_css_names() {
+        let parse = |s: &str| parse_color(s).map(|color| color.0);
+        assert_eq!(parse("#f80").unwrap(), [255, 136, 0, 255]);
+        assert_eq!(parse("f808").unwrap(), [255, 136, 0, 136]);
+        assert_eq!(parse("#ff8800").unwrap(), [255, 136, 0, 255]);
+        assert_eq!(parse("ff880080").unwrap(), [255, 136, 0, 128]);
+        assert_eq!(parse("White").unwrap(), [255, 255, 255, 255]);
+        assert_eq!(parse("transparent").unwrap(), [0, 0, 0, 0]);
+
+        for invalid in ["", "#ff888", "#gggggg", "chartreuse-ish", "#ff8800ff00"] {
+            assert!(
+                matches!(parse_color(invalid), Err(AppError::InvalidColor(_))),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn higher_avif_effort_is_no_larger() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }));
+        let encode = |effort: &str| {
+            let options = EncodeOptions {
+                effort: parse_effort(effort).unwrap(),
+                ..EncodeOptions::new(OutputFormat::Avif)
+            };
+            let encoded = encode_image_to_bytes(img.clone(), &options).unwrap();
+            assert_eq!(
+                image::guess_format(&encoded.bytes).unwrap(),
+                ImageFormat::Avif
+            );
+            encoded.bytes.len()
+        };
+
+        assert!(encode("slow") <= encode("fast"));
+        assert!(encode("10") <= encode("2"));
+        assert!(parse_effort("11").is_err());
+        assert!(parse_effort("medium").is_err());
+    }
+
+    #[cfg(feature = "oxipng")]
+    #[test]
+    fn optimized_png_is_no_larger_and_decodes_identically() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x / 8 * 32) as u8, (y / 8 * 32) as u8, 128])
+        }));
+        let encode = |optimize: bool| {
+            let options = EncodeOptions {
+                optimize,
+                ..EncodeOptions::new(OutputFormat::Png)
+            };
+            encode_image_to_bytes(img.clone(), &options).unwrap().bytes
+        };
+
+        let (plain, optimized) = (encode(false), encode(true));
+        assert!(optimized.len() <= plain.len());
+        let decode = |bytes: &[u8]| image::load_from_memory(bytes).unwrap().to_rgb8();
+        assert_eq!(decode(&optimized), decode(&plain));
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn mozjpeg_is_smaller_than_the_default_encoder() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(96, 64, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }));
+        let encode = |jpeg_backend: JpegBackend| {
+            let options = EncodeOptions {
+                quality: Some(80),
+                jpeg_backend,
+                ..EncodeOptions::new(OutputFormat::Jpeg)
             };
-            Ok(img.adjust_contrast(value))
+            encode_image_to_bytes(img.clone(), &options).unwrap().bytes
+        };
+
+        let (baseline, mozjpeg) = (encode(JpegBackend::Default), encode(JpegBackend::Mozjpeg));
+        let decoded = image::load_from_memory_with_format(&mozjpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (96, 64));
+        assert!(mozjpeg.len() < baseline.len());
+    }
+
+    #[test]
+    fn smart_cover_keeps_an_off_center_subject() {
+        // Flat gray with a busy checkered subject near the right edge
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(300, 100, |x, y| {
+            if (220..290).contains(&x) && (15..85).contains(&y) {
+                let on = (x / 5 + y / 5) % 2 == 0;
+                image::Rgb(if on { [255; 3] } else { [0; 3] })
+            } else {
+                image::Rgb([128; 3])
+            }
+        }));
+        let has_subject = |img: &DynamicImage| {
+            let luma = img.to_luma8();
+            luma.pixels().any(|p| p[0] < 20) && luma.pixels().any(|p| p[0] > 235)
+        };
+
+        let centered = cover_image(img.clone(), 100, 100, imageops::FilterType::Triangle);
+        assert!(!has_subject(&centered));
+
+        let smart = smart_cover_image(img, 100, 100, imageops::FilterType::Triangle);
+        assert_eq!(smart.dimensions(), (100, 100));
+        assert!(has_subject(&smart));
+    }
+
+    /// A size 2 `.cube` LUT mapping each corner of the color cube through `f`.
+    fn cube_lut(f: impl Fn([f32; 3]) -> [f32; 3]) -> Lut {
+        let mut text = "TITLE \"test\"\nLUT_3D_SIZE 2\n".to_string();
+        for b in [0.0, 1.0] {
+            for g in [0.0, 1.0] {
+                for r in [0.0, 1.0] {
+                    let [r, g, b] = f([r, g, b]);
+                    text.push_str(&format!("{} {} {}\n", r, g, b));
+                }
+            }
         }
-        // Add more filters here
-        _ => Err(AppError::UnsupportedFilter(filter_name)),
+        Lut::parse_cube(&text).unwrap()
     }
-}
 
-pub fn encode_image_to_bytes(
-    img: DynamicImage,
-    format_str: &str,
-    quality: Option<u8>,
-) -> Result<ProcessedImage, AppError> {
-    let mut buffer = Cursor::new(Vec::new());
-
-    match format_str.to_lowercase().as_str() {
-        "png" => {
-            img.write_to(&mut buffer, ImageFormat::Png)?;
-            Ok(ProcessedImage {
-                bytes: buffer.into_inner(),
-                mime_type: "image/png".to_string(),
-            })
+    #[test]
+    fn identity_lut_keeps_colors_and_inverting_lut_inverts_them() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 200])
+        }));
+
+        let identity = apply_lut(&img, &cube_lut(|rgb| rgb));
+        assert_eq!(identity.to_rgb8(), img.to_rgb8());
+
+        let inverted = apply_lut(&img, &cube_lut(|rgb| rgb.map(|c| 1.0 - c)));
+        for (graded, original) in inverted.to_rgb8().pixels().zip(img.to_rgb8().pixels()) {
+            assert_eq!(graded.0, original.0.map(|c| 255 - c));
         }
-        "jpeg" | "jpg" => {
-            let quality = quality.unwrap_or(80).max(1).min(100);
-            img.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))?;
-            Ok(ProcessedImage {
-                bytes: buffer.into_inner(),
-                mime_type: "image/jpeg".to_string(),
-            })
+    }
+
+    #[test]
+    fn malformed_cube_files_are_rejected() {
+        for text in [
+            "LUT_3D_SIZE 2\n0 0 0\n",
+            "0 0 0\n1 1 1\n",
+            "LUT_1D_SIZE 2\n0 0 0\n1 1 1\n",
+            "LUT_3D_SIZE 1000\n",
+        ] {
+            assert!(matches!(
+                Lut::parse_cube(text),
+                Err(AppError::InvalidLut(_))
+            ));
         }
-        "webp" => {
-            img.write_to(&mut buffer, ImageFormat::WebP)?;
-            Ok(ProcessedImage {
-                bytes: buffer.into_inner(),
-                mime_type: "image/webp".to_string(),
-            })
+    }
+
+    #[test]
+    fn auto_quality_is_lower_for_photos_than_for_logos() {
+        // Smooth gradients with noise stand in for a photograph
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 128, |x, y| {
+            let noise = ((x * 7919 + y * 104729) % 23) as u8;
+            image::Rgb([
+                (x * 2) as u8 ^ noise,
+                (y * 2) as u8,
+                ((x + y) as u8).wrapping_add(noise),
+            ])
+        }));
+        // Two flat colors stand in for a logo
+        let logo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(128, 128, |x, y| {
+            if (32..96).contains(&x) && (32..96).contains(&y) {
+                image::Rgb([200, 30, 30])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        }));
+
+        let (photo_quality, logo_quality) = (auto_quality(&photo), auto_quality(&logo));
+        assert!(
+            photo_quality < logo_quality,
+            "{} vs {}",
+            photo_quality,
+            logo_quality
+        );
+        assert_eq!(logo_quality, AUTO_QUALITY_RANGE.1);
+
+        let options = EncodeOptions {
+            auto_quality: true,
+            ..EncodeOptions::new(OutputFormat::Jpeg)
+        };
+        let encoded = encode_image_to_bytes(photo, &options).unwrap();
+        assert_eq!(encoded.quality, Some(photo_quality));
+    }
+
+    #[test]
+    fn trim_tolerances_decide_whether_soft_edges_are_kept() {
+        // A 10x10 opaque sprite ringed by a faint anti-aliased edge on an
+        // opaque white canvas with a transparent margin
+        let sprite = DynamicImage::ImageRgba8(RgbaImage::from_fn(40, 40, |x, y| {
+            let inside = |lo: u32, hi: u32| (lo..hi).contains(&x) && (lo..hi).contains(&y);
+            if inside(15, 25) {
+                Rgba([200, 0, 0, 255])
+            } else if inside(14, 26) {
+                Rgba([200, 0, 0, 24])
+            } else if inside(5, 35) {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }));
+        let trim = |filter: &str| {
+            let trimmed = apply_filter_str(sprite.clone(), filter).unwrap();
+            (trimmed.width(), trimmed.height())
+        };
+
+        // Only the transparent margin goes when trimming on alpha
+        assert_eq!(trim("trim:alpha"), (30, 30));
+        // Matching the top-left pixel's color and alpha leaves the white canvas
+        assert_eq!(trim("trim"), (30, 30));
+        assert_eq!(trim("trim:0:32"), (30, 30));
+        // A white top-left pixel trims the canvas, keeping the soft edge
+        // unless the alpha tolerance swallows it
+        let framed = DynamicImage::ImageRgba8(
+            imageops::crop_imm(&sprite.to_rgba8(), 5, 5, 30, 30).to_image(),
+        );
+        let trim_framed = |filter: &str| {
+            let trimmed = apply_filter_str(framed.clone(), filter).unwrap();
+            (trimmed.width(), trimmed.height())
+        };
+        assert_eq!(trim_framed("trim"), (12, 12));
+        assert_eq!(trim_framed("trim:0:32"), (10, 10));
+        assert_eq!(trim_framed("trim:alpha:32"), (30, 30));
+    }
+
+    #[test]
+    fn removebg_clears_the_background_around_the_subject() {
+        // A dark ring on a slightly noisy white background, with white inside
+        // the ring that is enclosed by the subject
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 40, |x, y| {
+            let distance = (x as f32 - 19.5).hypot(y as f32 - 19.5);
+            if (8.0..14.0).contains(&distance) {
+                image::Rgb([30, 60, 90])
+            } else {
+                let noise = ((x * 7 + y * 13) % 5) as u8;
+                image::Rgb([250 + noise, 252, 251 - noise])
+            }
+        }));
+
+        let removed = apply_filter_str(img, "removebg").unwrap().to_rgba8();
+        for (x, y) in [(0, 0), (39, 0), (0, 39), (39, 39), (20, 2)] {
+            assert_eq!(removed.get_pixel(x, y).0[3], 0, "({}, {})", x, y);
         }
-        "bmp" => {
-            img.write_to(&mut buffer, ImageFormat::Bmp)?;
-            Ok(ProcessedImage {
-                bytes: buffer.into_inner(),
-                mime_type: "image/bmp".to_string(),
-            })
+        // The ring and the white it encloses stay opaque
+        assert_eq!(removed.get_pixel(20, 9).0, [30, 60, 90, 255]);
+        assert_eq!(removed.get_pixel(20, 20).0[3], 255);
+        assert!(Filter::from_str("removebg:24").unwrap().needs_alpha());
+    }
+
+    #[test]
+    fn warm_temperature_raises_red_and_lowers_blue() {
+        let gray = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            2,
+            2,
+            image::Rgb([128, 128, 128]),
+        ));
+
+        for warm in ["temperature:40", "temperature:3200"] {
+            let [red, _, blue] = apply_filter_str(gray.clone(), warm).unwrap().to_rgb8()[(0, 0)].0;
+            assert!(red > 128 && blue < 128, "{}: {} {}", warm, red, blue);
+        }
+        for cool in ["temperature:-40", "temperature:12000"] {
+            let [red, _, blue] = apply_filter_str(gray.clone(), cool).unwrap().to_rgb8()[(0, 0)].0;
+            assert!(red < 128 && blue > 128, "{}: {} {}", cool, red, blue);
         }
-        "gif" => {
-            img.write_to(&mut buffer, ImageFormat::Gif)?;
-            Ok(ProcessedImage {
-                bytes: buffer.into_inner(),
-                mime_type: "image/gif".to_string(),
+        let neutral = apply_filter_str(gray.clone(), "temperature:0").unwrap();
+        assert_eq!(neutral.to_rgb8(), gray.to_rgb8());
+        assert!(apply_filter_str(gray, "temperature:500").is_err());
+    }
+
+    #[test]
+    fn rotate_fills_the_exposed_corners() {
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(20, 20, image::Rgb([255, 0, 0])));
+
+        let rotated = apply_filter_str(red.clone(), "rotate:45:blue").unwrap();
+        assert_eq!(rotated.dimensions(), (20, 20));
+        let rotated = rotated.to_rgb8();
+        assert_eq!(rotated[(0, 0)].0, [0, 0, 255]);
+        assert_eq!(rotated[(19, 19)].0, [0, 0, 255]);
+        assert_eq!(rotated[(10, 10)].0, [255, 0, 0]);
+
+        let expanded = apply_filter_str(red.clone(), "rotate:45::true").unwrap();
+        assert_eq!(expanded.dimensions(), (29, 29));
+        assert_eq!(expanded.to_rgba8()[(0, 0)].0[3], 0);
+
+        let wide = red.crop_imm(0, 0, 20, 10);
+        let quarter = apply_filter_str(wide, "rotate:90::true").unwrap();
+        assert_eq!(quarter.dimensions(), (10, 20));
+    }
+
+    #[test]
+    fn jpeg_orientation_replaces_existing_exif() {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(16, 8)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .unwrap();
+        let tagged = set_jpeg_orientation(&set_jpeg_orientation(&bytes, 6), 8);
+
+        let exif_segments = jpeg_segment_ranges(&tagged)
+            .filter(|(marker, range)| {
+                *marker == 0xE1 && tagged[range.start + 4..range.end].starts_with(b"Exif\0\0")
             })
+            .count();
+        assert_eq!(exif_segments, 1);
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&tagged))
+            .unwrap();
+        let orientation = exif
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(8));
+        assert_eq!(
+            image::load_from_memory(&tagged).unwrap().to_rgb8(),
+            image::load_from_memory(&bytes).unwrap().to_rgb8()
+        );
+    }
+
+    #[test]
+    fn perspective_maps_the_corners_to_a_trapezoid() {
+        let red = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            20,
+            20,
+            image::Rgba([255, 0, 0, 255]),
+        ));
+
+        let warped = apply_filter_str(red.clone(), "perspective:5:0:14:0:19:19:0:19")
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(warped.dimensions(), (20, 20));
+        // The narrowed top leaves the upper corners uncovered
+        assert_eq!(warped[(0, 0)].0[3], 0);
+        assert_eq!(warped[(19, 0)].0[3], 0);
+        assert_eq!(warped[(10, 1)].0, [255, 0, 0, 255]);
+        assert_eq!(warped[(1, 18)].0, [255, 0, 0, 255]);
+        assert_eq!(warped[(18, 18)].0, [255, 0, 0, 255]);
+
+        assert!(apply_filter_str(red.clone(), "perspective:0:0:1:1").is_err());
+        // Collinear corners enclose nothing
+        assert!(apply_filter_str(red, "perspective:0:0:1:1:2:2:3:3").is_err());
+    }
+
+    #[test]
+    fn cmyk_jpegs_are_converted_to_rgb() {
+        for fixture in [
+            &include_bytes!("../tests/fixtures/cmyk-adobe.jpg")[..],
+            &include_bytes!("../tests/fixtures/ycck-adobe.jpg")[..],
+            &include_bytes!("../tests/fixtures/cmyk-plain.jpg")[..],
+        ] {
+            assert!(is_cmyk_jpeg(fixture));
+            let img = decode_image(fixture, 1024, 1024 * 1024, None).unwrap();
+            assert_eq!(img.color(), ColorType::Rgb8);
+            // Left half red, right half blue
+            for ((x, y), expected) in [((8, 16), [255, 0, 0]), ((56, 16), [0, 0, 255])] {
+                let pixel = img.get_pixel(x, y);
+                let close = pixel.0[..3]
+                    .iter()
+                    .zip(expected)
+                    .all(|(&a, e)| a.abs_diff(e) <= 8);
+                assert!(close, "expected {:?}, got {:?}", expected, pixel);
+            }
         }
-        _ => Err(AppError::UnsupportedOutputFormat(format_str.to_string())),
+    }
+
+    #[test]
+    fn og_cards_write_text_over_the_background() {
+        let background = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            300,
+            200,
+            image::Rgb([40, 120, 200]),
+        ));
+        let card = og_card(background.clone(), "Hello world", Some("example.com"));
+        let blank = og_card(background, "", None);
+        assert_eq!(card.dimensions(), OG_CARD_SIZE);
+        assert_eq!(blank.dimensions(), OG_CARD_SIZE);
+
+        let differs = |(left, top, right, bottom): (u32, u32, u32, u32)| {
+            (top..bottom)
+                .any(|y| (left..right).any(|x| card.get_pixel(x, y) != blank.get_pixel(x, y)))
+        };
+        // The text sits in the bottom-left corner, above the margin
+        let (width, height) = OG_CARD_SIZE;
+        assert!(differs((
+            OG_CARD_MARGIN,
+            height / 2,
+            width / 2,
+            height - OG_CARD_MARGIN
+        )));
+        assert!(!differs((0, 0, width, height / 3)));
+        assert!(!differs((0, height - OG_CARD_MARGIN, width, height)));
+    }
+
+    #[test]
+    fn wrap_text_breaks_at_spaces() {
+        let lines = |text, per_line, max_lines| -> Vec<String> {
+            wrap_text(text, per_line, max_lines)
+                .into_iter()
+                .map(|line| line.into_iter().collect())
+                .collect()
+        };
+        assert_eq!(lines("Hello big World", 10, 3), ["hello big", "world"]);
+        assert_eq!(lines("abcdefghijkl", 5, 3), ["abcde", "fghij", "kl"]);
+        assert_eq!(lines("one two three four", 8, 2), ["one two", "three..."]);
+        assert!(lines("   ", 8, 2).is_empty());
+    }
+
+    #[test]
+    fn rgb_jpegs_are_not_cmyk() {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(16, 16)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+            .unwrap();
+        assert!(!is_cmyk_jpeg(&bytes));
+        assert!(!is_cmyk_jpeg(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    /// Mean absolute difference per channel between two same-sized images.
+    fn mean_error(a: &image::RgbImage, b: &image::RgbImage) -> f64 {
+        a.as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(&a, &b)| f64::from(a.abs_diff(b)))
+            .sum::<f64>()
+            / a.as_raw().len() as f64
+    }
+
+    #[test]
+    fn large_sigma_blurs_stay_close_to_the_exact_blur() {
+        let img = shapes();
+        let sigma = FAST_BLUR_MIN_SIGMA * 2.0;
+        let error = mean_error(&img.blur(sigma).to_rgb8(), &blur(&img, sigma).to_rgb8());
+        assert!(error < 3.0, "mean error {}", error);
+    }
+
+    // Timing depends on the machine and build profile; run with --ignored
+    #[test]
+    #[ignore]
+    fn large_sigma_blurs_are_much_faster() {
+        let img = shapes().resize_exact(1024, 1024, imageops::FilterType::Triangle);
+        let sigma = FAST_BLUR_MIN_SIGMA * 4.0;
+
+        let started = std::time::Instant::now();
+        std::hint::black_box(img.blur(sigma));
+        let exact_time = started.elapsed();
+        let started = std::time::Instant::now();
+        std::hint::black_box(blur(&img, sigma));
+        let fast_time = started.elapsed();
+
+        assert!(
+            fast_time * 4 < exact_time,
+            "{:?} vs {:?}",
+            fast_time,
+            exact_time
+        );
+    }
+
+    #[test]
+    fn grayscale_methods_weigh_red_differently() {
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0])));
+        let gray = |filter| {
+            let img = apply_filter_str(red.clone(), filter).unwrap();
+            img.to_luma8()[(0, 0)].0[0]
+        };
+
+        assert_eq!(gray("grayscale"), gray("grayscale:bt709"));
+        assert_eq!(gray("grayscale:bt709"), 54);
+        assert_eq!(gray("grayscale:luminosity"), 76);
+        assert_eq!(gray("grayscale:average"), 85);
+        assert_eq!(gray("grayscale:lightness"), 128);
+
+        let chain = parse_filter_chain("grayscale:bt709:1,grayscale:average:0.5").unwrap();
+        assert_eq!(chain[0].to_string(), "grayscale");
+        assert_eq!(chain[1].to_string(), "grayscale:average:0.5");
+        assert!(apply_filter_str(red, "grayscale:sideways").is_err());
     }
 }
@@ -0,0 +1,246 @@
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::{
+    error::AppError,
+    ops::{self, OutputFormat, ResizeFilter},
+};
+
+/// An ordered list of operations given in a single `pipeline` parameter, e.g.
+/// `crop(10,10,100,100);resize(200);filter(blur:2);format(webp:80)`.
+///
+/// Steps are separated by `;` and written as `name(args)`:
+///
+/// - `crop(x,y,w,h)` crops to the given window
+/// - `resize(w)` scales to width `w`, keeping the aspect ratio, and
+///   `resize(w,h)` scales to exactly `w`x`h`
+/// - `filter(chain)` applies a filter chain, as in the `filter` parameter
+/// - `format(name)` or `format(name:quality)` sets the output format; it may
+///   only appear once, as the last step
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+    output: Option<(OutputFormat, Option<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Resize {
+        width: u32,
+        height: Option<u32>,
+    },
+    Filter(String),
+}
+
+impl Pipeline {
+    /// Output format set by a `format` step.
+    pub fn output_format(&self) -> Option<OutputFormat> {
+        self.output.map(|(format, _)| format)
+    }
+
+    /// Quality set by a `format` step.
+    pub fn quality(&self) -> Option<u8> {
+        self.output.and_then(|(_, quality)| quality)
+    }
+
+    /// Runs the steps in order, recording each in `operations`.
+    pub fn apply(
+        &self,
+        mut img: DynamicImage,
+        operations: &mut Vec<String>,
+    ) -> Result<DynamicImage, AppError> {
+        for step in &self.steps {
+            img = match step {
+                Step::Crop {
+                    x,
+                    y,
+                    width,
+                    height,
+                } => {
+                    operations.push(format!("crop:{},{},{}x{}", x, y, width, height));
+                    ops::crop_image(img, *x, *y, *width, *height)?
+                }
+                Step::Resize { width, height } => {
+                    let height = height.unwrap_or_else(|| {
+                        let scaled =
+                            f64::from(img.height()) * f64::from(*width) / f64::from(img.width());
+                        (scaled.round() as u32).max(1)
+                    });
+                    let filter =
+                        ResizeFilter::for_resize(img.dimensions(), (*width, height), None, None);
+                    operations.push(format!("resize:{}x{}@{}", width, height, filter));
+                    ops::resize_image(img, *width, height, filter.filter_type())
+                }
+                Step::Filter(chain) => {
+                    operations.push(format!("filter:{}", chain));
+                    ops::apply_filter_str(img, chain)?
+                }
+            };
+        }
+        Ok(img)
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pipeline = Pipeline {
+            steps: Vec::new(),
+            output: None,
+        };
+        for (index, step) in s.split(';').map(str::trim).enumerate() {
+            let invalid =
+                |msg: String| AppError::InvalidPipeline(format!("step {}: {}", index + 1, msg));
+            if step.is_empty() {
+                return Err(invalid("empty step".to_string()));
+            }
+            if pipeline.output.is_some() {
+                return Err(invalid("format must be the last step".to_string()));
+            }
+            let (name, args) = step
+                .strip_suffix(')')
+                .and_then(|step| step.split_once('('))
+                .ok_or_else(|| invalid(format!("expected name(args), found {}", step)))?;
+            let args = args.trim();
+
+            match name.trim().to_lowercase().as_str() {
+                "crop" => {
+                    let [x, y, width, height] = parse_numbers::<4>(args).map_err(invalid)?;
+                    if width == 0 || height == 0 {
+                        return Err(invalid(
+                            "crop width and height must be greater than 0".to_string(),
+                        ));
+                    }
+                    pipeline.steps.push(Step::Crop {
+                        x,
+                        y,
+                        width,
+                        height,
+                    });
+                }
+                "resize" => {
+                    let (width, height) = match parse_numbers::<1>(args) {
+                        Ok([width]) => (width, None),
+                        Err(_) => {
+                            let [width, height] = parse_numbers::<2>(args).map_err(|_| {
+                                invalid(format!("resize takes w or w,h, found {}", args))
+                            })?;
+                            (width, Some(height))
+                        }
+                    };
+                    if width == 0 || height == Some(0) {
+                        return Err(invalid(
+                            "resize dimensions must be greater than 0".to_string(),
+                        ));
+                    }
+                    pipeline.steps.push(Step::Resize { width, height });
+                }
+                "filter" => {
+                    // Parsed up front so malformed chains are reported as such
+                    ops::parse_filter_chain(args)?;
+                    pipeline.steps.push(Step::Filter(args.to_string()));
+                }
+                "format" => {
+                    let (format, quality) = match args.split_once(':') {
+                        Some((format, quality)) => {
+                            let quality = quality
+                                .trim()
+                                .parse::<u8>()
+                                .ok()
+                                .filter(|quality| (1..=100).contains(quality))
+                                .ok_or_else(|| {
+                                    invalid(format!("quality must be 1-100, found {}", quality))
+                                })?;
+                            (format, Some(quality))
+                        }
+                        None => (args, None),
+                    };
+                    pipeline.output = Some((format.parse()?, quality));
+                }
+                other => return Err(invalid(format!("unknown operation: {}", other))),
+            }
+        }
+        Ok(pipeline)
+    }
+}
+
+/// Parses exactly `N` comma-separated numbers.
+fn parse_numbers<const N: usize>(args: &str) -> Result<[u32; N], String> {
+    let values = args
+        .split(',')
+        .map(|value| value.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("expected {} numbers, found {}", N, args))?;
+    values
+        .try_into()
+        .map_err(|_| format!("expected {} numbers, found {}", N, args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_steps_in_order() {
+        let pipeline: Pipeline = "crop(10,10,100,80); resize(50); filter(blur:2); format(webp:80)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            pipeline.steps,
+            vec![
+                Step::Crop {
+                    x: 10,
+                    y: 10,
+                    width: 100,
+                    height: 80,
+                },
+                Step::Resize {
+                    width: 50,
+                    height: None,
+                },
+                Step::Filter("blur:2".to_string()),
+            ]
+        );
+        assert_eq!(pipeline.output_format(), Some(OutputFormat::WebP));
+        assert_eq!(pipeline.quality(), Some(80));
+
+        let mut operations = Vec::new();
+        let img = pipeline
+            .apply(DynamicImage::new_rgb8(200, 200), &mut operations)
+            .unwrap();
+        assert_eq!(img.dimensions(), (50, 40));
+        assert_eq!(operations.len(), 3);
+    }
+
+    #[test]
+    fn rejects_malformed_pipelines() {
+        for (pipeline, error) in [
+            ("resize(50", "step 1: expected name(args), found resize(50"),
+            ("crop(0,0,10)", "step 1: expected 4 numbers, found 0,0,10"),
+            (
+                "resize(0)",
+                "step 1: resize dimensions must be greater than 0",
+            ),
+            ("resize(50);;", "step 2: empty step"),
+            (
+                "format(png);resize(50)",
+                "step 2: format must be the last step",
+            ),
+            ("format(jpeg:0)", "step 1: quality must be 1-100, found 0"),
+            ("rotate(90)", "step 1: unknown operation: rotate"),
+        ] {
+            match pipeline.parse::<Pipeline>() {
+                Err(AppError::InvalidPipeline(msg)) => assert_eq!(msg, error, "{}", pipeline),
+                other => panic!("{} parsed as {:?}", pipeline, other),
+            }
+        }
+    }
+}
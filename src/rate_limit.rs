@@ -0,0 +1,88 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{Router, http::StatusCode, response::IntoResponse};
+use tower_governor::{GovernorError, GovernorLayer, governor::GovernorConfigBuilder};
+
+use crate::{config::Config, error::AppError};
+
+/// How often idle clients are dropped from the limiter's state.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// Bounds on the time it takes a client to earn another request. Rates too
+/// high or too low to represent are clamped to them.
+const MIN_PERIOD: Duration = Duration::from_nanos(1);
+const MAX_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Limits each client IP on the given routes to `RATE_LIMIT_PER_SECOND`, with
+/// bursts of up to `RATE_LIMIT_BURST`. Routes are returned unchanged when rate
+/// limiting is disabled.
+///
+/// Clients are keyed by the peer address of the connection, so the router must
+/// be served with connect info. Behind a proxy, every client shares the
+/// proxy's address.
+pub fn rate_limited<S>(router: Router<S>, config: &Config) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let per_second = config.rate_limit_per_second;
+    if !per_second.is_finite() || per_second <= 0.0 {
+        return router;
+    }
+    let burst = config
+        .rate_limit_burst
+        .unwrap_or(per_second.ceil() as u32)
+        .max(1);
+
+    let period = Duration::try_from_secs_f64(1.0 / per_second)
+        .unwrap_or(MAX_PERIOD)
+        .clamp(MIN_PERIOD, MAX_PERIOD);
+
+    let governor_config = GovernorConfigBuilder::default()
+        .period(period)
+        .burst_size(burst)
+        .error_handler(|error| match error {
+            GovernorError::TooManyRequests { wait_time, .. } => AppError::RateLimited {
+                // Rounded up so clients retrying on time are let through
+                retry_after_secs: wait_time.max(1),
+            }
+            .into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        })
+        .finish()
+        .expect("rate limit period and burst are non-zero");
+
+    let limiter = governor_config.limiter().clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiter.retain_recent();
+        }
+    });
+
+    router.route_layer(GovernorLayer {
+        config: Arc::new(governor_config),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn extreme_rates_do_not_panic() {
+        for (per_second, burst) in [
+            (1e12, None),
+            (1e-300, None),
+            (f64::MAX, Some(0)),
+            (5.0, Some(0)),
+        ] {
+            let config = Config {
+                rate_limit_per_second: per_second,
+                rate_limit_burst: burst,
+                ..Config::default()
+            };
+            let router = Router::<()>::new().route("/", axum::routing::get(|| async {}));
+            let _ = rate_limited(router, &config);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{FromRequest, Multipart, State, multipart::MultipartError},
+    http::{Request, Response, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use serde_json::{Value, json};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultMakeSpan, OnRequest, OnResponse, TraceLayer},
+};
+use tracing::{Level, Span, info, warn};
+
+use crate::config::Config;
+
+pub type RequestLogLayer =
+    TraceLayer<SharedClassifier<ServerErrorsAsFailures>, DefaultMakeSpan, LogRequest, LogResponse>;
+
+/// Builds the request logging layer, or `None` when `REQUEST_LOG` is unset.
+///
+/// Only the method, URI, status, response size and latency are logged; request
+/// and response bodies, and so image bytes, never are. Bodies only go to the
+/// sample file, see [`sample_request`].
+pub fn request_log_layer(config: &Config) -> Option<RequestLogLayer> {
+    if !config.request_log {
+        return None;
+    }
+    Some(
+        TraceLayer::new_for_http()
+            .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+            .on_request(LogRequest)
+            .on_response(LogResponse),
+    )
+}
+
+/// Appends every n-th request, with its body, to a JSON lines file so
+/// failures reported by clients can be replayed. Lines are written by a
+/// background task, so a slow disk never holds up requests.
+pub struct RequestSampler {
+    every: u64,
+    seen: AtomicU64,
+    /// Largest body buffered for a sample; bigger ones are only sized.
+    max_body_bytes: usize,
+    lines: mpsc::Sender<String>,
+}
+
+/// Sampled lines waiting for the writer; more are dropped rather than queued.
+const SAMPLE_QUEUE_SIZE: usize = 64;
+
+/// Builds the request sampler, or `None` unless `REQUEST_LOG` and
+/// `REQUEST_LOG_SAMPLE_FILE` are both set. Must be called within the runtime,
+/// which runs the file writer.
+pub fn request_sampler(config: &Config) -> Option<Arc<RequestSampler>> {
+    if !config.request_log {
+        return None;
+    }
+    let path = config.request_log_sample_file.clone()?;
+    let (lines, receiver) = mpsc::channel(SAMPLE_QUEUE_SIZE);
+    tokio::spawn(write_samples(path, receiver));
+    Some(Arc::new(RequestSampler {
+        every: config.request_log_sample_every.max(1),
+        seen: AtomicU64::new(0),
+        max_body_bytes: config.max_upload_bytes.max(config.max_json_body_bytes),
+        lines,
+    }))
+}
+
+async fn write_samples(path: PathBuf, mut lines: mpsc::Receiver<String>) {
+    while let Some(line) = lines.recv().await {
+        let result = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+        {
+            Ok(mut file) => file.write_all(line.as_bytes()).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!("failed to write request sample to {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Middleware recording sampled requests. Bodies are buffered and put back
+/// for the handler; text bodies are recorded as they are, while multipart
+/// bodies are recorded as their text fields, with only the name and size of
+/// uploaded files, so image bytes never end up in the file.
+pub async fn sample_request(
+    State(sampler): State<Arc<RequestSampler>>,
+    request: Request<Body>,
+    next: Next,
+) -> AxumResponse {
+    if !sampler
+        .seen
+        .fetch_add(1, Ordering::Relaxed)
+        .is_multiple_of(sampler.every)
+    {
+        return next.run(request).await;
+    }
+    let mut sample = json!({
+        "method": request.method().as_str(),
+        "uri": request.uri().to_string(),
+    });
+    let (parts, body) = request.into_parts();
+    let body_length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    let body = match body_length {
+        Some(length) if length > 0 && length <= sampler.max_body_bytes => {
+            let Ok(bytes) = axum::body::to_bytes(body, length).await else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            describe_body(&parts, &bytes, &mut sample).await;
+            Body::from(bytes)
+        }
+        Some(length) if length > 0 => {
+            sample["body_bytes"] = json!(length);
+            body
+        }
+        _ => body,
+    };
+
+    let mut line = sample.to_string();
+    line.push('\n');
+    if sampler.lines.try_send(line).is_err() {
+        warn!("request sample queue is full, dropping a sample");
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Adds a buffered request body to its sample.
+async fn describe_body(parts: &Parts, bytes: &Bytes, sample: &mut Value) {
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    if mime == "application/json"
+        || mime == "application/x-www-form-urlencoded"
+        || mime.starts_with("text/")
+    {
+        sample["body"] = json!(String::from_utf8_lossy(bytes));
+        return;
+    }
+    if mime == "multipart/form-data" {
+        let request = Request::from_parts(parts.clone(), Body::from(bytes.clone()));
+        if let Ok(multipart) = Multipart::from_request(request, &()).await
+            && let Ok((fields, files)) = multipart_fields(multipart).await
+        {
+            sample["fields"] = fields;
+            sample["files"] = files;
+            return;
+        }
+    }
+    sample["body_bytes"] = json!(bytes.len());
+}
+
+/// Text fields of a multipart body, and the name and size of its files.
+async fn multipart_fields(mut multipart: Multipart) -> Result<(Value, Value), MultipartError> {
+    let mut fields = serde_json::Map::new();
+    let mut files = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        match field.file_name().map(str::to_string) {
+            Some(filename) => {
+                let bytes = field.bytes().await?.len();
+                files.push(json!({ "name": name, "filename": filename, "bytes": bytes }));
+            }
+            None => {
+                fields.insert(name, json!(field.text().await?));
+            }
+        }
+    }
+    Ok((Value::Object(fields), Value::Array(files)))
+}
+
+#[derive(Clone)]
+pub struct LogRequest;
+
+impl<B> OnRequest<B> for LogRequest {
+    fn on_request(&mut self, request: &Request<B>, _span: &Span) {
+        info!("started {} {}", request.method(), request.uri());
+    }
+}
+
+#[derive(Clone)]
+pub struct LogResponse;
+
+impl<B: HttpBody> OnResponse<B> for LogResponse {
+    fn on_response(self, response: &Response<B>, latency: Duration, _span: &Span) {
+        // Streamed bodies have no known size up front
+        let bytes = response.body().size_hint().exact();
+        info!(
+            status = response.status().as_u16(),
+            bytes,
+            latency_ms = latency.as_millis() as u64,
+            "finished"
+        );
+    }
+}
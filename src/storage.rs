@@ -0,0 +1,201 @@
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use image::ImageFormat;
+use reqwest::{Client, Url, header};
+use serde::{
+    Deserialize, Serialize,
+    de::{self, IntoDeserializer},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, error::AppError, ops::ProcessedImage};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Where a processed image is stored instead of being returned.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageTarget {
+    S3,
+}
+
+impl FromStr for StorageTarget {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StorageTarget::deserialize(s.trim().to_lowercase().into_deserializer())
+            .map_err(|_: de::value::Error| AppError::InvalidStorageTarget(s.to_string()))
+    }
+}
+
+/// Uploads processed images to an S3-compatible bucket, addressed path-style
+/// (`{endpoint}/{bucket}/{key}`) so it also works with MinIO and similar.
+pub struct S3Storage {
+    client: Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    public_url: Option<String>,
+}
+
+/// Location of an uploaded object.
+pub struct StoredObject {
+    pub key: String,
+    pub url: String,
+}
+
+impl S3Storage {
+    /// Builds the storage from the `S3_*` settings, or returns `None` when
+    /// the endpoint, bucket or credentials are missing.
+    pub fn from_config(config: &Config, client: Client) -> Option<Self> {
+        let endpoint = Url::parse(config.s3_endpoint.as_deref()?).ok()?;
+        Some(S3Storage {
+            client,
+            endpoint,
+            bucket: config.s3_bucket.clone()?,
+            region: config.s3_region.clone(),
+            access_key_id: config.s3_access_key_id.clone()?,
+            secret_access_key: config.s3_secret_access_key.clone()?,
+            public_url: config.s3_public_url.clone(),
+        })
+    }
+
+    /// Uploads an image under a key derived from its contents, so storing the
+    /// same output twice writes the same object.
+    pub async fn put_image(&self, image: &ProcessedImage) -> Result<StoredObject, AppError> {
+        let extension = ImageFormat::from_mime_type(&image.mime_type)
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("bin");
+        self.put_object(image.bytes.clone(), &image.mime_type, extension)
+            .await
+    }
+
+    /// Uploads arbitrary content under a key derived from it, like
+    /// [`S3Storage::put_image`].
+    pub async fn put_object(
+        &self,
+        body: Vec<u8>,
+        content_type: &str,
+        extension: &str,
+    ) -> Result<StoredObject, AppError> {
+        let key = format!("{:x}.{}", Sha256::digest(&body), extension);
+        let path = format!("/{}/{}", self.bucket, key);
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let payload_hash = format!("{:x}", Sha256::digest(&body));
+        let amz_date = amz_date(SystemTime::now());
+        let authorization =
+            self.authorization(&path, &host, content_type, &payload_hash, &amz_date);
+
+        let response = self
+            .client
+            .put(url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header(header::AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| AppError::StorageError(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(AppError::StorageError(format!(
+                "bucket responded with {}",
+                response.status()
+            )));
+        }
+
+        let url = match &self.public_url {
+            Some(base) => format!("{}/{}", base.trim_end_matches('/'), key),
+            None => url.to_string(),
+        };
+        Ok(StoredObject { key, url })
+    }
+
+    /// AWS Signature Version 4 `Authorization` header for a PUT request.
+    fn authorization(
+        &self,
+        path: &str,
+        host: &str,
+        content_type: &str,
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        const SIGNED_HEADERS: &str = "content-type;host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "PUT\n{}\n\ncontent-type:{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, content_type, host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash
+        );
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let key = format!("AWS4{}", self.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hmac_sha256(&key, string_to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, SIGNED_HEADERS, signature
+        )
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Formats a time as `YYYYMMDDTHHMMSSZ`, as SigV4 expects.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
@@ -0,0 +1,116 @@
+use std::{
+    io::{self, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+use axum::body::Body;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use image::DynamicImage;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::ops::{self, EncodeOptions};
+
+/// Size of the chunks handed to the response body.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered between the encoder and the client before the encoder waits.
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Encodes an image on a blocking thread and streams the output into a
+/// response body as it is produced, rather than buffering the whole encoding.
+///
+/// Encoding errors, and panics in the encoder, can only surface after the
+/// response has started, so they end the body with an error. The connection
+/// is then aborted instead of the truncated image looking complete.
+pub fn encode_to_body(img: DynamicImage, options: EncodeOptions) -> Body {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            tx,
+            buffer: BytesMut::with_capacity(CHUNK_SIZE),
+        };
+        let encoded = panic::catch_unwind(AssertUnwindSafe(|| {
+            ops::encode_image(img, &options, &mut writer)
+        }));
+        let result = match encoded {
+            Ok(result) => result.map_err(|err| io::Error::other(err.to_string())),
+            Err(_) => {
+                error!("encoder panicked while streaming a response");
+                Err(io::Error::other("encoder panicked"))
+            }
+        }
+        .and_then(|()| writer.flush());
+        if let Err(err) = result {
+            warn!("streamed encode failed: {}", err);
+            let _ = writer.tx.blocking_send(Err(err));
+        }
+    });
+
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// Writer that forwards fixed-size chunks over a channel, blocking while the
+/// client catches up.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buffer: BytesMut,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.buffer.split().freeze();
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::OutputFormat;
+
+    /// An image whose encodings span several chunks.
+    fn noisy_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(512, 512, |x, y| {
+            let seed = x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(40_503);
+            image::Rgb([seed as u8, (seed >> 8) as u8, (seed >> 16) as u8])
+        }))
+    }
+
+    #[tokio::test]
+    async fn streamed_output_matches_buffered_output() {
+        for format in [OutputFormat::Png, OutputFormat::Jpeg, OutputFormat::WebP] {
+            let options = EncodeOptions::new(format);
+            let mut buffered = Vec::new();
+            ops::encode_image(noisy_image(), &options, &mut buffered).unwrap();
+            assert!(buffered.len() > CHUNK_SIZE);
+
+            let body = encode_to_body(noisy_image(), options);
+            let streamed = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+            assert_eq!(streamed, buffered, "{:?} output differs", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn encoder_errors_fail_the_body() {
+        // Wider than JPEG allows
+        let img = DynamicImage::new_rgb8(70_000, 1);
+        let body = encode_to_body(img, EncodeOptions::new(OutputFormat::Jpeg));
+        assert!(axum::body::to_bytes(body, usize::MAX).await.is_err());
+    }
+}